@@ -1,25 +1,41 @@
 //! Memory address values.
 
-use serde::{Deserialize, Deserializer, Serialize, Serializer};
-use std::num::ParseIntError;
-use std::str::FromStr;
+use crate::expr;
+use eyre::Error;
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
 
-/// Serializes `u32` as a memory address string.
-pub fn serialize<S: Serializer>(size: &u32, serializer: S) -> Result<S::Ok, S::Error> {
+/// Serializes `u64` as a memory address string.
+pub fn serialize<S: Serializer>(size: &u64, serializer: S) -> Result<S::Ok, S::Error> {
     to_string(*size).serialize(serializer)
 }
 
-/// Deserializes `u32` from a memory address string.
-pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u32, D::Error> {
-    u32::deserialize(deserializer)
+/// Deserializes `u64` from a memory address integer or string.
+pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u64, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Addr {
+        Integer(i64),
+        String(String),
+    }
+    match Addr::deserialize(deserializer)? {
+        Addr::Integer(addr) => u64::try_from(addr)
+            .map_err(|_| de::Error::custom(format!("address {addr} must not be negative"))),
+        Addr::String(addr) => from_str(&addr).map_err(de::Error::custom),
+    }
 }
 
-/// Returns a canonical string representation of the given address.
-pub fn to_string(addr: u32) -> String {
-    format!("0x{:08x}", addr)
+/// Returns a canonical string representation of the given address. Addresses
+/// beyond the 32-bit address space are padded to 16 digits.
+pub fn to_string(addr: u64) -> String {
+    if addr > u32::MAX.into() {
+        format!("0x{addr:016x}")
+    } else {
+        format!("0x{addr:08x}")
+    }
 }
 
-/// Parses an address value from the given string.
-pub fn from_str(s: &str) -> Result<u32, ParseIntError> {
-    u32::from_str(s)
+/// Parses an address value from the given string. See [`crate::expr`] for
+/// the supported syntax.
+pub fn from_str(s: &str) -> Result<u64, Error> {
+    expr::evaluate(s)
 }
@@ -0,0 +1,236 @@
+//! Rust constants generation.
+//!
+//! Firmware crates can use the memory layout as typed constants instead of
+//! declaring linker symbols by hand. The build script calls [`generate`], and
+//! the firmware includes the result:
+//!
+//! ```ignore
+//! include!(concat!(env!("OUT_DIR"), "/layout.rs"));
+//! ```
+//!
+//! The build script runs before linking, when the size of the data sections is
+//! not known yet. Values depending on it, e.g. flexible stacks and heaps, are
+//! resolved only by `drone-ld`, so they are left out of the generated file
+//! with a comment pointing to the corresponding linker symbols.
+//!
+//! Addresses and sizes are `usize`, so the build script checks that the
+//! layout `address-width` matches the pointer width of the target.
+
+use crate::layout::{Layout, LAYOUT_CONFIG_ENV, LAYOUT_IMAGE_ENV, LAYOUT_PROFILE_ENV};
+use eyre::{bail, eyre, Result, WrapErr};
+use std::fmt::Write;
+use std::path::Path;
+use std::{env, fs};
+
+/// Name of the generated file.
+pub const LAYOUT_RS: &str = "layout.rs";
+
+const PRELUDE: &str = "// Memory layout constants generated by drone-config. Do not edit.
+
+/// Memory block.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Region {
+    /// Beginning of the block.
+    pub origin: usize,
+    /// Length of the block.
+    pub size: usize,
+}
+
+impl Region {
+    /// Returns the end of the block.
+    pub const fn end(self) -> usize {
+        self.origin + self.size
+    }
+}
+
+/// Heap pool.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Pool {
+    /// Single block size inside this pool.
+    pub block: usize,
+    /// Count of the blocks inside this pool.
+    pub count: usize,
+}
+";
+
+/// Writes the constants for the layout of the current crate into
+/// `$OUT_DIR/layout.rs`. Intended to be called from a build script.
+pub fn generate() -> Result<()> {
+    for var in [LAYOUT_CONFIG_ENV, LAYOUT_PROFILE_ENV, LAYOUT_IMAGE_ENV] {
+        println!("cargo:rerun-if-env-changed={var}");
+    }
+    let root =
+        env::var_os("CARGO_MANIFEST_DIR").ok_or_else(|| eyre!("$CARGO_MANIFEST_DIR is not set"))?;
+    for entry in Path::new(&root).read_dir()? {
+        let path = entry?.path();
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+        if name.starts_with("layout") && name.ends_with(".toml") {
+            println!("cargo:rerun-if-changed={}", path.display());
+        }
+    }
+    let layout = Layout::read_from_cargo()?;
+    if let Ok(width) = env::var("CARGO_CFG_TARGET_POINTER_WIDTH") {
+        if width != layout.address_width().to_string() {
+            bail!(
+                "layout address-width is {}, but the target pointer width is {width}",
+                layout.address_width()
+            );
+        }
+    }
+    let out_dir = env::var_os("OUT_DIR").ok_or_else(|| eyre!("$OUT_DIR is not set"))?;
+    fs::write(Path::new(&out_dir).join(LAYOUT_RS), render(&layout)?)?;
+    Ok(())
+}
+
+/// Renders the constants for the `layout` calculated with unknown data
+/// sizes, as returned by [`Layout::read`].
+pub fn render(layout: &Layout) -> Result<String> {
+    let mut empty = layout.clone();
+    let data_sizes = layout.data.sections.keys().map(|name| (name.clone(), 0)).collect();
+    empty
+        .calculate_with_data_sizes(Some(0), &data_sizes)
+        .wrap_err("calculating layout with empty data sections")?;
+    let mut output = String::from(PRELUDE);
+    for (name, flash) in &layout.flash {
+        let region = (flash.origin, flash.size);
+        write_region(&mut output, &format!("flash.{name}"), region, region, "");
+    }
+    for (name, ram) in &layout.ram {
+        let region = (ram.origin, ram.size);
+        write_region(&mut output, &format!("ram.{name}"), region, region, "");
+    }
+    for (name, stack) in &layout.stack {
+        let other = &empty.stack[name];
+        write_region(
+            &mut output,
+            &format!("stack.{name}"),
+            (stack.origin + stack.prefix_size, stack.fixed_size),
+            (other.origin + other.prefix_size, other.fixed_size),
+            &format!("STACK_POINTER_{}", const_name(name)),
+        );
+    }
+    for (name, heap) in &layout.heap {
+        let (section, other) = (&heap.section, &empty.heap[name].section);
+        let symbol = format!("HEAP_{}_BUF", const_name(name));
+        write_region(
+            &mut output,
+            &format!("heap.{name}"),
+            (section.origin + section.prefix_size, section.fixed_size),
+            (other.origin + other.prefix_size, other.fixed_size),
+            &format!("{symbol}_BASE` and `{symbol}_END"),
+        );
+        let pools = heap.pools.iter().map(|p| (p.block, p.fixed_count)).collect::<Vec<_>>();
+        let other = empty.heap[name].pools.iter().map(|p| (p.block, p.fixed_count));
+        write_pools(&mut output, name, &pools, pools.iter().copied().eq(other));
+    }
+    let streams = layout.stream.iter().flat_map(|stream| &stream.sections);
+    writeln!(output).unwrap();
+    writeln!(output, "/// Drone Stream buffer names.").unwrap();
+    let names = streams.clone().map(|(name, _)| format!("{name:?}")).collect::<Vec<_>>();
+    writeln!(output, "pub const STREAM_NAMES: [&str; {}] = [{}];", names.len(), names.join(", "))
+        .unwrap();
+    for (name, stream) in streams {
+        let region = (stream.origin + stream.prefix_size, stream.size);
+        write_region(&mut output, &format!("stream.{name}"), region, region, "");
+    }
+    for (name, reserved) in &layout.reserved {
+        let region = (reserved.origin, reserved.size);
+        write_region(&mut output, &format!("reserved.{name}"), region, region, "");
+    }
+    if let Some(crashlog) = &layout.crashlog {
+        let region = (crashlog.origin, crashlog.size);
+        write_region(&mut output, "crashlog", region, region, "");
+    }
+    Ok(output)
+}
+
+fn write_region(
+    output: &mut String,
+    path: &str,
+    region: (u64, u64),
+    other: (u64, u64),
+    symbols: &str,
+) {
+    writeln!(output).unwrap();
+    if region != other {
+        writeln!(
+            output,
+            "// `{path}` depends on the data size; use the `{symbols}` linker symbols instead."
+        )
+        .unwrap();
+        return;
+    }
+    let (origin, size) = region;
+    writeln!(output, "/// `{path}` memory block.").unwrap();
+    writeln!(
+        output,
+        "pub const {}: Region = Region {{ origin: {}, size: {} }};",
+        const_name(path),
+        hex(origin),
+        hex(size)
+    )
+    .unwrap();
+}
+
+fn write_pools(output: &mut String, name: &str, pools: &[(u64, u64)], exact: bool) {
+    writeln!(output).unwrap();
+    if !exact {
+        writeln!(output, "// `heap.{name}` pool counts depend on the data size.").unwrap();
+        return;
+    }
+    let items = pools
+        .iter()
+        .map(|(block, count)| format!("Pool {{ block: {block}, count: {count} }}"))
+        .collect::<Vec<_>>();
+    writeln!(output, "/// `heap.{name}` pools.").unwrap();
+    writeln!(
+        output,
+        "pub const HEAP_{}_POOLS: [Pool; {}] = [{}];",
+        const_name(name),
+        pools.len(),
+        items.join(", ")
+    )
+    .unwrap();
+}
+
+fn const_name(path: &str) -> String {
+    path.chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c.to_ascii_uppercase() } else { '_' })
+        .collect()
+}
+
+fn hex(value: u64) -> String {
+    let groups = if value > u32::MAX.into() { 4 } else { 2 };
+    let digits = (0..groups).rev().map(|i| format!("{:04X}", value >> (i * 16) & 0xFFFF));
+    format!("0x{}", digits.collect::<Vec<_>>().join("_"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        let layout = r#"
+[ram]
+main = { origin = 0x20000000, size = "20K" }
+[data]
+ram = "main"
+[stack]
+core0 = { ram = "main", size = "4K" }
+[heap.main]
+ram = "main"
+size = "50%"
+pools = [{ block = "4", count = "100%" }]
+"#;
+        let output = render(&Layout::parse(layout).unwrap()).unwrap();
+        assert!(output.contains(
+            "pub const STACK_CORE0: Region = Region { origin: 0x2000_0000, size: 0x0000_1000 };"
+        ));
+        assert!(output.contains(
+            "// `heap.main` depends on the data size; use the `HEAP_MAIN_BUF_BASE` and \
+             `HEAP_MAIN_BUF_END` linker symbols instead."
+        ));
+        assert!(output.contains("pub const STREAM_NAMES: [&str; 0] = [];"));
+    }
+}
@@ -0,0 +1,169 @@
+//! Source-annotated diagnostics for `layout.toml`.
+
+use eyre::Report;
+use std::fmt::Write;
+
+/// Layout config source text.
+#[derive(Clone, Debug)]
+pub struct Source {
+    /// Source name shown in diagnostics, e.g. a file path.
+    pub name: String,
+    /// Source text.
+    pub text: String,
+}
+
+impl Source {
+    /// Creates a new source.
+    pub fn new(name: impl Into<String>, text: impl Into<String>) -> Self {
+        Self { name: name.into(), text: text.into() }
+    }
+
+    /// Returns the zero-based line and column of the definition of the dotted
+    /// key `path`, e.g. `stack.core0.size`. If the exact key is not found,
+    /// returns the location of the longest defined prefix of the path.
+    pub fn locate(&self, path: &str) -> Option<(usize, usize)> {
+        let path = path.split('.').collect::<Vec<_>>();
+        let mut best: Option<(usize, (usize, usize))> = None;
+        let mut consider = |depth: usize, location: (usize, usize)| {
+            if depth > best.map_or(0, |(best_depth, _)| best_depth) {
+                best = Some((depth, location));
+            }
+        };
+        let mut table = Vec::new();
+        for (line_number, line) in self.text.lines().enumerate() {
+            let indent = line.len() - line.trim_start().len();
+            let line = strip_comment(line.trim_start());
+            if let Some(header) = line.strip_prefix('[') {
+                let header = header.trim_start_matches('[');
+                let header = header.split(']').next().unwrap_or_default();
+                table = split_key(header);
+                if common_prefix(&table, &path) == table.len() {
+                    consider(table.len(), (line_number, indent));
+                }
+                continue;
+            }
+            let Some((key, value)) = line.split_once('=') else { continue };
+            let mut key_path = table.clone();
+            key_path.extend(split_key(key));
+            let depth = common_prefix(&key_path, &path);
+            if depth < key_path.len() {
+                continue;
+            }
+            consider(depth, (line_number, indent));
+            if let Some(inner) = path.get(depth) {
+                if let Some(column) = find_inline_key(value, inner) {
+                    let column = indent + key.len() + 1 + column;
+                    consider(depth + 1, (line_number, column));
+                }
+            }
+        }
+        best.map(|(_, location)| location)
+    }
+
+    /// Renders a diagnostic `message` pointing at the zero-based `line` and
+    /// `column`.
+    pub fn render(&self, line: usize, column: usize, message: &str) -> String {
+        let text = self.text.lines().nth(line).unwrap_or_default();
+        let number = (line + 1).to_string();
+        let gutter = " ".repeat(number.len());
+        let mut output = String::new();
+        writeln!(output, "{message}").unwrap();
+        writeln!(output, "{gutter}--> {}:{}:{}", self.name, line + 1, column + 1).unwrap();
+        writeln!(output, "{gutter} |").unwrap();
+        writeln!(output, "{number} | {text}").unwrap();
+        write!(output, "{gutter} | {}^", " ".repeat(column)).unwrap();
+        output
+    }
+}
+
+/// Annotates a layout config error with the source line of the offending key.
+/// TOML syntax and type errors are located by their reported position in the
+/// first of `sources`. Validation errors are located by the dotted key path
+/// their messages start with, searching all `sources` in order.
+pub fn annotate(err: Report, sources: &[Source]) -> Report {
+    if let Some(location) =
+        err.downcast_ref::<toml::de::Error>().and_then(toml::de::Error::line_col)
+    {
+        if let Some(source) = sources.first() {
+            let message = source.render(location.0, location.1, "layout config error");
+            return err.wrap_err(message);
+        }
+    }
+    let root_cause = err.root_cause().to_string();
+    let path = root_cause.split_whitespace().next().unwrap_or_default();
+    let path = path.trim_end_matches([':', ',', ';']);
+    if !path.contains('.') {
+        return err;
+    }
+    for source in sources {
+        if let Some((line, column)) = source.locate(path) {
+            let message = source.render(line, column, &format!("layout config error at `{path}`"));
+            return err.wrap_err(message);
+        }
+    }
+    err
+}
+
+fn strip_comment(line: &str) -> &str {
+    let mut quoted = false;
+    for (i, c) in line.char_indices() {
+        match c {
+            '"' => quoted = !quoted,
+            '#' if !quoted => return &line[..i],
+            _ => {}
+        }
+    }
+    line
+}
+
+fn split_key(key: &str) -> Vec<&str> {
+    key.split('.').map(|segment| segment.trim().trim_matches('"')).collect()
+}
+
+fn common_prefix(key: &[&str], path: &[&str]) -> usize {
+    key.iter().zip(path).take_while(|(a, b)| a == b).count()
+}
+
+fn find_inline_key(value: &str, key: &str) -> Option<usize> {
+    let start = value.find('{')? + 1;
+    let mut offset = start;
+    for entry in value[start..].split(',') {
+        let trimmed = entry.trim_start();
+        let leading = entry.len() - trimmed.len();
+        if let Some((name, _)) = trimmed.split_once('=') {
+            if name.trim().trim_matches('"') == key {
+                return Some(offset + leading);
+            }
+        }
+        offset += entry.len() + 1;
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_locate() {
+        let source = Source::new(
+            "layout.toml",
+            r#"
+[ram]
+main = { origin = 0x20000000, size = "20K" }
+
+[stack.core0]
+ram = "main" # comment
+size = "4K"
+"#,
+        );
+        assert_eq!(source.locate("ram.main.size"), Some((2, 30)));
+        assert_eq!(source.locate("stack.core0.size"), Some((6, 0)));
+        assert_eq!(source.locate("stack.core0.align"), Some((4, 0)));
+        assert_eq!(source.locate("heap.main"), None);
+        assert_eq!(
+            source.render(6, 7, "error"),
+            "error\n --> layout.toml:7:8\n  |\n7 | size = \"4K\"\n  |        ^"
+        );
+    }
+}
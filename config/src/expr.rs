@@ -0,0 +1,332 @@
+//! Memory size and address expressions.
+//!
+//! An expression is built from numbers, references to memory regions, and the
+//! `+`, `-`, `*`, `/` operators with parentheses. Numbers are decimal,
+//! hexadecimal (`0x`), octal (`0o`), or binary (`0b`) with optional `_`
+//! separators, and can be followed by a `K`, `M`, or `G` binary unit suffix
+//! (`Ki`, `KiB`, etc. are accepted as well). Decimal numbers with a unit
+//! suffix may have a fractional part, e.g. `1.5K`. References have the form
+//! `flash.<name>.<field>` or `ram.<name>.<field>`, where `<field>` is one of
+//! `origin`, `size`, or `end`. The `align(value, alignment)` function rounds
+//! `value` up to a multiple of `alignment`.
+//!
+//! All arithmetic is checked, and the result must fit into 64 bits. Whether
+//! it fits into the target address space is checked by the layout.
+
+use eyre::{bail, eyre, Result};
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+
+thread_local! {
+    static REFERENCES: RefCell<Option<BTreeMap<String, u64>>> = const { RefCell::new(None) };
+}
+
+const MAX_FRACTION_DIGITS: usize = 9;
+
+const UNITS: &[(&str, u64)] = &[
+    ("KiB", 1 << 10),
+    ("MiB", 1 << 20),
+    ("GiB", 1 << 30),
+    ("Ki", 1 << 10),
+    ("Mi", 1 << 20),
+    ("Gi", 1 << 30),
+    ("K", 1 << 10),
+    ("M", 1 << 20),
+    ("G", 1 << 30),
+];
+
+/// Evaluates the expression `input`. References are resolved against the
+/// values set by [`with_references`].
+pub fn evaluate(input: &str) -> Result<u64> {
+    REFERENCES.with(|references| {
+        let references = references.borrow();
+        evaluate_with(input, &mut |name| {
+            references
+                .as_ref()
+                .and_then(|references| references.get(name))
+                .copied()
+                .ok_or_else(|| eyre!("unknown reference `{name}`"))
+        })
+    })
+}
+
+/// Evaluates the expression `input` resolving references with `resolve`.
+pub fn evaluate_with(input: &str, resolve: &mut dyn FnMut(&str) -> Result<u64>) -> Result<u64> {
+    let mut parser = Parser { input, position: 0, resolve };
+    let value = parser.expression()?;
+    parser.skip_whitespace();
+    if parser.position < input.len() {
+        return Err(parser.error("unexpected trailing characters"));
+    }
+    Ok(value)
+}
+
+/// Runs `f` with `references` available to [`evaluate`].
+pub fn with_references<T>(references: BTreeMap<String, u64>, f: impl FnOnce() -> T) -> T {
+    let previous = REFERENCES.with(|cell| cell.replace(Some(references)));
+    let result = f();
+    REFERENCES.with(|cell| cell.replace(previous));
+    result
+}
+
+struct Parser<'a, 'r> {
+    input: &'a str,
+    position: usize,
+    resolve: &'r mut dyn FnMut(&str) -> Result<u64>,
+}
+
+impl Parser<'_, '_> {
+    fn expression(&mut self) -> Result<u64> {
+        let mut value = self.term()?;
+        loop {
+            self.skip_whitespace();
+            let start = self.position;
+            if self.eat('+') {
+                let rhs = self.term()?;
+                value = value.checked_add(rhs).ok_or_else(|| self.error_at(start, "overflow"))?;
+            } else if self.eat('-') {
+                let rhs = self.term()?;
+                value = value.checked_sub(rhs).ok_or_else(|| {
+                    self.error_at(start, &format!("{value:#x} - {rhs:#x} is negative"))
+                })?;
+            } else {
+                return Ok(value);
+            }
+        }
+    }
+
+    fn term(&mut self) -> Result<u64> {
+        let mut value = self.factor()?;
+        loop {
+            self.skip_whitespace();
+            let start = self.position;
+            if self.eat('*') {
+                let rhs = self.factor()?;
+                value = value.checked_mul(rhs).ok_or_else(|| self.error_at(start, "overflow"))?;
+            } else if self.eat('/') {
+                let rhs = self.factor()?;
+                value = value
+                    .checked_div(rhs)
+                    .ok_or_else(|| self.error_at(start, "division by zero"))?;
+            } else {
+                return Ok(value);
+            }
+        }
+    }
+
+    fn factor(&mut self) -> Result<u64> {
+        self.skip_whitespace();
+        let start = self.position;
+        match self.peek() {
+            Some('(') => {
+                self.position += 1;
+                let value = self.expression()?;
+                self.expect(')')?;
+                Ok(value)
+            }
+            Some(c) if c.is_ascii_digit() => self.number(),
+            Some(c) if c.is_ascii_alphabetic() || c == '_' => {
+                let name = self.identifier();
+                if name == "align" {
+                    self.expect('(')?;
+                    let value = self.expression()?;
+                    self.expect(',')?;
+                    let align = self.expression()?;
+                    self.expect(')')?;
+                    if align == 0 {
+                        return Err(self.error_at(start, "alignment must be greater than zero"));
+                    }
+                    value
+                        .checked_add(align - 1)
+                        .map(|value| value / align * align)
+                        .ok_or_else(|| self.error_at(start, "overflow"))
+                } else {
+                    (self.resolve)(&name).map_err(|err| self.error_at(start, &err.to_string()))
+                }
+            }
+            Some(_) => Err(self.error("expected a number, a reference, or `(`")),
+            None => Err(self.error("unexpected end of expression")),
+        }
+    }
+
+    fn number(&mut self) -> Result<u64> {
+        let start = self.position;
+        let rest = &self.input[start..];
+        let (radix, prefix) = match rest.get(0..2) {
+            Some("0x" | "0X") => (16, 2),
+            Some("0o" | "0O") => (8, 2),
+            Some("0b" | "0B") => (2, 2),
+            _ => (10, 0),
+        };
+        self.position += prefix;
+        let digits = self.take_while(|c| c.is_digit(radix) || c == '_');
+        let fraction = if radix == 10 && self.peek() == Some('.') {
+            self.position += 1;
+            Some(self.take_while(|c| c.is_ascii_digit() || c == '_'))
+        } else {
+            None
+        };
+        let digits = digits.replace('_', "");
+        if digits.is_empty() {
+            return Err(self.error("expected digits"));
+        }
+        let integer = u64::from_str_radix(&digits, radix)
+            .map_err(|_| self.error_at(start, "number is too large"))?;
+        let unit_start = self.position;
+        let unit = UNITS
+            .iter()
+            .find(|(suffix, _)| self.input[unit_start..].starts_with(suffix))
+            .map(|&(suffix, multiplier)| {
+                self.position += suffix.len();
+                multiplier
+            });
+        if self.peek().is_some_and(|c| c.is_ascii_alphanumeric()) {
+            return Err(self.error("unknown unit suffix"));
+        }
+        let Some(fraction) = fraction.map(|fraction| fraction.replace('_', "")) else {
+            let multiplier = unit.unwrap_or(1);
+            return integer.checked_mul(multiplier).ok_or_else(|| self.error_at(start, "overflow"));
+        };
+        let Some(multiplier) = unit else {
+            return Err(self.error_at(start, "fractional numbers require a unit suffix"));
+        };
+        if fraction.is_empty() || fraction.len() > MAX_FRACTION_DIGITS {
+            return Err(self.error_at(start, "invalid fractional part"));
+        }
+        let scale = 10_u128.pow(u32::try_from(fraction.len()).unwrap());
+        let fraction = fraction.parse::<u128>().unwrap();
+        let scaled = (u128::from(integer) * scale + fraction) * u128::from(multiplier);
+        if !scaled.is_multiple_of(scale) {
+            return Err(self.error_at(start, "number is not a whole number of bytes"));
+        }
+        u64::try_from(scaled / scale).map_err(|_| self.error_at(start, "overflow"))
+    }
+
+    fn identifier(&mut self) -> String {
+        self.take_while(|c| c.is_ascii_alphanumeric() || c == '_' || c == '.')
+    }
+
+    fn take_while(&mut self, f: impl Fn(char) -> bool) -> String {
+        let start = self.position;
+        while let Some(c) = self.peek().filter(|&c| f(c)) {
+            self.position += c.len_utf8();
+        }
+        self.input[start..self.position].to_string()
+    }
+
+    fn expect(&mut self, c: char) -> Result<()> {
+        if self.eat(c) {
+            Ok(())
+        } else {
+            Err(self.error(&format!("expected `{c}`")))
+        }
+    }
+
+    fn eat(&mut self, c: char) -> bool {
+        self.skip_whitespace();
+        if self.peek() == Some(c) {
+            self.position += c.len_utf8();
+            true
+        } else {
+            false
+        }
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.input[self.position..].chars().next()
+    }
+
+    fn skip_whitespace(&mut self) {
+        self.take_while(char::is_whitespace);
+    }
+
+    fn error(&self, message: &str) -> eyre::Report {
+        self.error_at(self.position, message)
+    }
+
+    fn error_at(&self, position: usize, message: &str) -> eyre::Report {
+        let column = self.input[..position].chars().count() + 1;
+        eyre!("invalid expression `{}` at column {column}: {message}", self.input)
+    }
+}
+
+/// Returns `Err` if `name` is not a valid reference to a memory region field.
+pub fn check_reference(name: &str) -> Result<(&str, &str)> {
+    match name.rsplit_once('.') {
+        Some((region, "origin" | "size" | "end"))
+            if region.starts_with("flash.") || region.starts_with("ram.") =>
+        {
+            Ok((region, &name[region.len() + 1..]))
+        }
+        _ => bail!(
+            "unknown reference `{name}`; expected `flash.<name>.<field>` or `ram.<name>.<field>`, \
+             where <field> is `origin`, `size`, or `end`"
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn eval(input: &str) -> Result<u64> {
+        evaluate_with(input, &mut |name| match name {
+            "ram.main.end" => Ok(0x2000_5000),
+            _ => bail!("unknown reference `{name}`"),
+        })
+    }
+
+    #[test]
+    fn test_evaluate() {
+        assert_eq!(eval("0x2000_0000").unwrap(), 0x2000_0000);
+        assert_eq!(eval("020").unwrap(), 20);
+        assert_eq!(eval("20KiB").unwrap(), 20 * 1024);
+        assert_eq!(eval("1.5K").unwrap(), 1536);
+        assert_eq!(eval("ram.main.end - 4K").unwrap(), 0x2000_4000);
+        assert_eq!(eval("align(1000, 256) * 2").unwrap(), 2048);
+        assert_eq!(
+            eval("1.3K").unwrap_err().to_string(),
+            "invalid expression `1.3K` at column 1: number is not a whole number of bytes"
+        );
+        assert_eq!(
+            eval("4K - 8K").unwrap_err().to_string(),
+            "invalid expression `4K - 8K` at column 4: 0x1000 - 0x2000 is negative"
+        );
+        assert_eq!(
+            eval("4X").unwrap_err().to_string(),
+            "invalid expression `4X` at column 2: unknown unit suffix"
+        );
+        assert_eq!(evaluate("4G").unwrap(), 4 << 30);
+        assert!(evaluate("4G * 4G").is_err());
+    }
+
+    #[test]
+    fn test_unknown_reference() {
+        assert_eq!(
+            evaluate("ram.main.end - 4K").unwrap_err().to_string(),
+            "invalid expression `ram.main.end - 4K` at column 1: unknown reference `ram.main.end`"
+        );
+        assert_eq!(
+            eval("4K + ram.ccm.end").unwrap_err().to_string(),
+            "invalid expression `4K + ram.ccm.end` at column 6: unknown reference `ram.ccm.end`"
+        );
+    }
+
+    #[test]
+    fn test_non_ascii() {
+        assert_eq!(eval("1\u{a0}+ 1").unwrap(), 2);
+        assert_eq!(
+            eval("1 + \u{e4}").unwrap_err().to_string(),
+            "invalid expression `1 + \u{e4}` at column 5: expected a number, a reference, or `(`"
+        );
+        assert_eq!(
+            eval("4K\u{e4} + 1").unwrap_err().to_string(),
+            "invalid expression `4K\u{e4} + 1` at column 3: unexpected trailing characters"
+        );
+        assert_eq!(
+            eval("\u{e4}\u{e4} + 1K\u{2014}").unwrap_err().to_string(),
+            "invalid expression `\u{e4}\u{e4} + 1K\u{2014}` at column 1: expected a number, a \
+             reference, or `(`"
+        );
+    }
+}
@@ -1,25 +1,57 @@
 //! `layout.toml` config file for project memory layout.
 
+use crate::diagnostic::{self, Source};
+use crate::mpu::Mpu;
 use crate::{
-    addr, size, HEAP_POOL_SIZE, HEAP_PREFIX_SIZE, STREAM_GLOBAL_RUNTIME_SIZE, STREAM_RUNTIME_SIZE,
+    addr, expr, size, CRASHLOG_HEADER_SIZE, HEAP_POOL_SIZE, HEAP_PREFIX_SIZE, IMAGE_HEADER_SIZE,
+    STREAM_GLOBAL_RUNTIME_SIZE, STREAM_RUNTIME_SIZE,
 };
 use drone_stream::MIN_BUFFER_SIZE;
 use eyre::{bail, eyre, Result, WrapErr};
 use indexmap::IndexMap;
-use serde::{Deserialize, Serialize};
-use std::path::Path;
-use std::{env, fs, mem};
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::{env, fmt, fs, mem, slice};
 
 /// The name of the Drone configuration file.
 pub const LAYOUT_CONFIG: &str = "layout.toml";
 
-const ALIGN: u32 = 4;
+/// The environment variable holding a memory layout configuration, which is
+/// read instead of [`LAYOUT_CONFIG`] by [`Layout::read_from_cargo`].
+pub const LAYOUT_CONFIG_ENV: &str = "DRONE_LAYOUT_CONFIG";
+
+/// The environment variable selecting a layout profile. When set to `name`,
+/// `layout.<name>.toml` is read instead of [`LAYOUT_CONFIG`].
+pub const LAYOUT_PROFILE_ENV: &str = "DRONE_LAYOUT_PROFILE";
+
+/// The environment variable selecting an image to link. When set to `name`,
+/// the program is placed into the flash region of `image.<name>`.
+pub const LAYOUT_IMAGE_ENV: &str = "DRONE_LAYOUT_IMAGE";
+
+/// JSON Schema of the memory layout configuration file.
+pub const LAYOUT_SCHEMA: &str = include_str!("../layout.schema.json");
+
+/// The key of a layout file to extend.
+const EXTEND_KEY: &str = "extend";
+
+const ALIGN: u64 = 4;
+
+const DEFAULT_ADDRESS_WIDTH: u32 = 32;
+
+const DEFAULT_HEADER_SIZE: u64 = 256;
+
+const DEFAULT_HEADER_OFFSET: u64 = 1024;
 
 /// Memory layout configuration.
 #[non_exhaustive]
 #[derive(Clone, Debug, Serialize, Deserialize)]
 #[serde(rename_all = "kebab-case")]
 pub struct Layout {
+    /// Width of the target address space in bits, either 32 or 64. Defaults
+    /// to 32.
+    pub address_width: Option<u32>,
     /// Flash memory regions.
     #[serde(default)]
     pub flash: IndexMap<String, Memory>,
@@ -28,6 +60,9 @@ pub struct Layout {
     pub ram: IndexMap<String, Memory>,
     /// Combined BSS and DATA section.
     pub data: Data,
+    /// Code sections placed apart from the main program code.
+    #[serde(default)]
+    pub code: IndexMap<String, Code>,
     /// Stack memory sections.
     #[serde(default)]
     pub stack: IndexMap<String, Section>,
@@ -36,9 +71,27 @@ pub struct Layout {
     /// Heap memory sections.
     #[serde(default)]
     pub heap: IndexMap<String, Heap>,
+    /// Sections pinned at fixed addresses.
+    #[serde(default)]
+    pub reserved: IndexMap<String, Reserved>,
+    /// Persistent crash log section.
+    pub crashlog: Option<Crashlog>,
+    /// Bootloader and application images sharing this layout.
+    #[serde(default)]
+    pub image: IndexMap<String, Image>,
+    /// Firmware image header.
+    pub header: Option<Header>,
+    /// Image the layout is selected for. See [`Layout::select_image`].
+    #[serde(skip)]
+    pub selected_image: Option<String>,
     /// Additional linker options.
     #[serde(default)]
     pub linker: Linker,
+    /// Post-link memory usage report options.
+    #[serde(default)]
+    pub report: Report,
+    /// MPU configuration.
+    pub mpu: Option<Mpu>,
 }
 
 /// Memory region of some type.
@@ -48,28 +101,94 @@ pub struct Layout {
 pub struct Memory {
     /// Beginning of the memory region.
     #[serde(with = "addr")]
-    pub origin: u32,
+    pub origin: u64,
     /// Length of the memory region.
     #[serde(with = "size")]
-    pub size: u32,
+    pub size: u64,
+    /// Linker access mode attributes, e.g. `rx` or `rwx`. Defaults to `rx` for
+    /// flash and `wx` for RAM.
+    pub mode: Option<String>,
+    /// Whether the region is inaccessible for DMA controllers. Data sections
+    /// holding DMA buffers can't be placed into such a region.
+    pub no_dma: Option<bool>,
+    /// Whether the region is cacheable. Defaults to `true`.
+    pub cacheable: Option<bool>,
 }
 
 /// Combined BSS and DATA section.
 #[non_exhaustive]
 #[derive(Clone, Debug, Serialize, Deserialize)]
-#[serde(rename_all = "kebab-case")]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
 pub struct Data {
     /// RAM memory region this section belongs to.
     pub ram: String,
+    /// Flash memory region the initial values are loaded from. Defaults to
+    /// `program`.
+    pub load_from: Option<String>,
+    /// Extra padding to compensate alignment.
+    #[serde(default, with = "size::opt")]
+    pub padding: Option<u64>,
+    /// Required alignment of this section origin.
+    #[serde(default, with = "size::opt")]
+    pub align: Option<u64>,
+    /// Auto-calculated origin of this section.
+    #[serde(default, with = "calculated::addr")]
+    pub origin: u64,
+    /// Size of this section.
+    #[serde(default, with = "calculated::size")]
+    pub size: u64,
+    /// Additional data sections, defined as `[data.sections.<name>]`.
+    #[serde(default)]
+    pub sections: IndexMap<String, DataSection>,
+}
+
+/// Additional BSS and DATA section.
+#[non_exhaustive]
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct DataSection {
+    /// RAM memory region this section belongs to.
+    pub ram: String,
+    /// Flash memory region the initial values are loaded from. Defaults to
+    /// `program`.
+    pub load_from: Option<String>,
     /// Extra padding to compensate alignment.
     #[serde(default, with = "size::opt")]
-    pub padding: Option<u32>,
+    pub padding: Option<u64>,
+    /// Required alignment of this section origin.
+    #[serde(default, with = "size::opt")]
+    pub align: Option<u64>,
+    /// Whether this section holds DMA buffers. Defaults to `false`.
+    pub dma: Option<bool>,
     /// Auto-calculated origin of this section.
-    #[serde(skip_deserializing, with = "addr")]
-    pub origin: u32,
+    #[serde(default, with = "calculated::addr")]
+    pub origin: u64,
     /// Size of this section.
-    #[serde(skip_deserializing, with = "size")]
-    pub size: u32,
+    #[serde(default, with = "calculated::size")]
+    pub size: u64,
+}
+
+/// Code section placed apart from the main program code.
+#[non_exhaustive]
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct Code {
+    /// RAM memory region this section is executed from.
+    pub ram: Option<String>,
+    /// Flash memory region this section is loaded from when executed from
+    /// RAM. Defaults to `program`.
+    pub load_from: Option<String>,
+    /// Flash memory region this section is executed in place from.
+    pub flash: Option<String>,
+    /// Size reserved for this section in RAM.
+    #[serde(default, with = "size::opt")]
+    pub size: Option<u64>,
+    /// Required alignment of this section origin.
+    #[serde(default, with = "size::opt")]
+    pub align: Option<u64>,
+    /// Auto-calculated origin of this section in RAM.
+    #[serde(default, with = "calculated::addr")]
+    pub origin: u64,
 }
 
 /// Drone Stream configuration.
@@ -80,11 +199,11 @@ pub struct Stream {
     /// RAM memory region for Drone Stream global runtime data.
     pub ram: String,
     /// Auto-calculated origin of this section.
-    #[serde(skip_deserializing, with = "addr")]
-    pub origin: u32,
+    #[serde(default, with = "calculated::addr")]
+    pub origin: u64,
     /// Auto-calculated fixed size of this section.
-    #[serde(skip_deserializing, with = "size")]
-    pub fixed_size: u32,
+    #[serde(default, with = "calculated::size")]
+    pub fixed_size: u64,
     /// Stream memory sections.
     #[serde(flatten)]
     pub sections: IndexMap<String, FixedSection>,
@@ -99,15 +218,18 @@ pub struct Section {
     pub ram: String,
     /// Length of the memory section.
     pub size: size::Flexible,
+    /// Required alignment of this section origin.
+    #[serde(default, with = "size::opt")]
+    pub align: Option<u64>,
     /// Auto-calculated origin of this section.
-    #[serde(skip_deserializing, with = "addr")]
-    pub origin: u32,
+    #[serde(default, with = "calculated::addr")]
+    pub origin: u64,
     /// Auto-calculated fixed size of this section.
-    #[serde(skip_deserializing, with = "size")]
-    pub fixed_size: u32,
+    #[serde(default, with = "calculated::size")]
+    pub fixed_size: u64,
     /// Auto-calculated specific prefix size of this section.
-    #[serde(skip_deserializing, with = "size")]
-    pub prefix_size: u32,
+    #[serde(default, with = "calculated::size")]
+    pub prefix_size: u64,
 }
 
 /// Memory section inside some RAM memory region with fixed size.
@@ -119,15 +241,18 @@ pub struct FixedSection {
     pub ram: String,
     /// Length of the memory section.
     #[serde(with = "size")]
-    pub size: u32,
+    pub size: u64,
+    /// Required alignment of this section origin.
+    #[serde(default, with = "size::opt")]
+    pub align: Option<u64>,
     /// Whether this section is the primary initializer.
     pub init_primary: Option<bool>,
     /// Auto-calculated origin of this section.
-    #[serde(skip_deserializing, with = "addr")]
-    pub origin: u32,
+    #[serde(default, with = "calculated::addr")]
+    pub origin: u64,
     /// Auto-calculated specific prefix size of this section.
-    #[serde(skip_deserializing, with = "size")]
-    pub prefix_size: u32,
+    #[serde(default, with = "calculated::size")]
+    pub prefix_size: u64,
 }
 
 /// Heap.
@@ -149,12 +274,181 @@ pub struct Heap {
 pub struct HeapPool {
     /// Single block size inside this pool.
     #[serde(with = "size")]
-    pub block: u32,
+    pub block: u64,
     /// Count of the blocks inside this pool.
-    pub count: size::Flexible,
+    pub count: PoolCount,
     /// Auto-calculated fixed count of the blocks inside this pool.
-    #[serde(skip_deserializing, with = "size")]
-    pub fixed_count: u32,
+    #[serde(default, with = "calculated::size")]
+    pub fixed_count: u64,
+}
+
+/// Count of the blocks inside a heap pool.
+///
+/// Written as `<count>` or `<count> blocks` for an absolute number of blocks,
+/// `<size> bytes` for a byte budget, or `<percent>%` for a share of the heap
+/// space left after the other pools.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum PoolCount {
+    /// Absolute number of blocks.
+    Blocks(u64),
+    /// Byte budget, a multiple of the block size.
+    Bytes(u64),
+    /// Share of the free heap space in millionths, see
+    /// [`size::FLEXIBLE_SCALE`].
+    Flexible(u32),
+}
+
+impl PoolCount {
+    /// Returns `true` if this count is relative to the free heap space.
+    pub fn is_flexible(&self) -> bool {
+        matches!(self, Self::Flexible(_))
+    }
+
+    /// Returns the number of blocks of size `block` if the count is fixed,
+    /// and `None` otherwise.
+    pub fn blocks(&self, block: u64) -> Option<u64> {
+        match *self {
+            Self::Blocks(count) => Some(count),
+            Self::Bytes(bytes) => Some(bytes / block),
+            Self::Flexible(_) => None,
+        }
+    }
+
+    /// Returns `Some(weight)` if the count is flexible, and `None` otherwise.
+    pub fn flexible(&self) -> Option<u32> {
+        match *self {
+            Self::Flexible(weight) => Some(weight),
+            Self::Blocks(_) | Self::Bytes(_) => None,
+        }
+    }
+}
+
+impl FromStr for PoolCount {
+    type Err = eyre::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let s = s.trim();
+        if s.ends_with('%') {
+            let weight = s.parse::<size::Flexible>()?.flexible();
+            Ok(Self::Flexible(weight.expect("percent is always flexible")))
+        } else if let Some(bytes) = s.strip_suffix("bytes") {
+            Ok(Self::Bytes(size::from_str(bytes.trim_end())?))
+        } else {
+            Ok(Self::Blocks(size::from_str(s.strip_suffix("blocks").unwrap_or(s).trim_end())?))
+        }
+    }
+}
+
+impl fmt::Display for PoolCount {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            Self::Blocks(count) => write!(f, "{count} blocks"),
+            Self::Bytes(bytes) => write!(f, "{} bytes", size::to_string(bytes)),
+            Self::Flexible(weight) => f.write_str(&size::Flexible::Flexible(weight).to_string()),
+        }
+    }
+}
+
+impl Serialize for PoolCount {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.to_string().serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for PoolCount {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?.parse().map_err(de::Error::custom)
+    }
+}
+
+/// Section pinned at a fixed address inside some RAM memory region. The other
+/// sections are laid out in a single contiguous span, so reserved sections
+/// must be adjacent to the beginning or the end of the region, or to each
+/// other.
+#[non_exhaustive]
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct Reserved {
+    /// RAM memory region this section belongs to.
+    pub ram: String,
+    /// Beginning of the section.
+    #[serde(with = "addr")]
+    pub origin: u64,
+    /// Length of the section.
+    #[serde(with = "size")]
+    pub size: u64,
+    /// Whether the section keeps its contents across resets. Otherwise it is
+    /// zeroed at startup. Defaults to `false`.
+    pub noinit: Option<bool>,
+}
+
+/// Persistent crash log section. The firmware fault handler writes a crash
+/// record into it, which survives the following reset.
+#[non_exhaustive]
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct Crashlog {
+    /// RAM memory region this section belongs to.
+    pub ram: String,
+    /// Length of the section.
+    #[serde(with = "size")]
+    pub size: u64,
+    /// Auto-calculated origin of this section. The section is placed at the
+    /// end of its RAM region, so that its address stays the same between
+    /// firmware versions.
+    #[serde(default, with = "calculated::addr")]
+    pub origin: u64,
+}
+
+/// Bootloader or application image stored in its own flash region.
+#[non_exhaustive]
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct Image {
+    /// Flash memory region this image is stored in and executed from.
+    pub flash: String,
+    /// Cargo binary name of this image. Defaults to the image name.
+    pub binary: Option<String>,
+    /// Entry point symbol of this image. Defaults to `main`.
+    pub entry: Option<String>,
+}
+
+/// Firmware image header placed into the program flash region and filled in
+/// after linking.
+#[non_exhaustive]
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct Header {
+    /// Checksum algorithm. Defaults to `crc32`.
+    pub checksum: Option<Checksum>,
+    /// Firmware version in `MAJOR.MINOR.PATCH` form. Overridden by the
+    /// `DRONE_IMAGE_VERSION` environment variable. Linking fails if neither
+    /// is set.
+    pub version: Option<String>,
+    /// Size reserved for the header section. Defaults to 256, which keeps the
+    /// vector table aligned when the header precedes it.
+    #[serde(default, with = "size::opt")]
+    pub size: Option<u64>,
+    /// Offset of the header from the beginning of the program flash region.
+    /// The beginning of the program code, which holds the vector table, stays
+    /// at the region origin and must fit before the offset, otherwise linking
+    /// fails. Defaults to 1K.
+    ///
+    /// Zero places the header before the vector table. Such an image doesn't
+    /// boot by itself and must be started by a bootloader which skips the
+    /// header.
+    #[serde(default, with = "size::opt")]
+    pub offset: Option<u64>,
+}
+
+/// Firmware image checksum algorithm.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Checksum {
+    /// CRC-32 (ISO-HDLC).
+    Crc32,
+    /// SHA-256.
+    Sha256,
 }
 
 /// Additional linker options.
@@ -171,33 +465,173 @@ pub struct Linker {
     pub include_after: Vec<String>,
 }
 
+/// Post-link memory usage report options.
+#[non_exhaustive]
+#[derive(Default, Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct Report {
+    /// Maximum allowed usage of each flash memory region in percent.
+    pub flash_threshold: Option<f32>,
+    /// Maximum allowed usage of each RAM memory region in percent.
+    pub ram_threshold: Option<f32>,
+    /// Minimum allowed calculated size of each stack and heap. Catches
+    /// flexible stacks and heaps shrinking as the data sections grow.
+    #[serde(default, with = "size::opt")]
+    pub min_size: Option<u64>,
+}
+
 impl Layout {
     /// Reads a memory layout configuration file from inside cargo environment,
-    /// e.g. when inside a proc macro.
+    /// e.g. when inside a proc macro. If [`LAYOUT_CONFIG_ENV`] is set, its
+    /// value is read with [`Layout::read_str`] instead of the file.
     pub fn read_from_cargo() -> Result<Self> {
-        if let Ok(string) = env::var("DRONE_LAYOUT_CONFIG") {
-            Self::parse(&string)
+        let project_root = env::var_os("CARGO_MANIFEST_DIR")
+            .ok_or_else(|| eyre!("$CARGO_MANIFEST_DIR is not set"))?;
+        if let Ok(string) = env::var(LAYOUT_CONFIG_ENV) {
+            Self::read_str(&string, project_root.as_ref())
         } else {
-            Self::read_from_project_root(
-                env::var_os("CARGO_MANIFEST_DIR")
-                    .ok_or_else(|| eyre!("$CARGO_MANIFEST_DIR is not set"))?
-                    .as_ref(),
-            )
+            Self::read_from_project_root(project_root.as_ref())
         }
     }
 
+    /// Reads a memory layout configuration from the `string`, resolving its
+    /// `extend` key relative to `project_root` directory. Without `extend`,
+    /// the configuration extends the file of [`LAYOUT_PROFILE_ENV`] profile if
+    /// it is set. The image is selected with [`LAYOUT_IMAGE_ENV`].
+    pub fn read_str(string: &str, project_root: &Path) -> Result<Self> {
+        let profile = profile_file_name().map(|file_name| project_root.join(file_name));
+        let source = Source::new(format!("${LAYOUT_CONFIG_ENV}"), string);
+        let mut sources = Vec::new();
+        let value = extend_source(source, project_root, profile, &mut Vec::new(), &mut sources)?;
+        let mut layout = Self::from_value(&value, &sources)?;
+        layout.select_image(image_from_env())?;
+        Ok(layout)
+    }
+
     /// Reads a memory layout configuration file from `project_root` directory.
+    /// If [`LAYOUT_PROFILE_ENV`] is set, the profile's file is read instead.
+    /// The image is selected with [`LAYOUT_IMAGE_ENV`].
     pub fn read_from_project_root(project_root: &Path) -> Result<Self> {
-        let path = project_root.join(LAYOUT_CONFIG);
+        let file_name = profile_file_name().unwrap_or_else(|| LAYOUT_CONFIG.into());
+        let path = project_root.join(&file_name);
         if !path.exists() {
-            bail!("{} configuration file not exists in {}", LAYOUT_CONFIG, project_root.display());
+            bail!("{} configuration file not exists in {}", file_name, project_root.display());
         }
-        Self::parse(&fs::read_to_string(&path)?)
+        let mut layout = Self::read(&path)?;
+        layout.select_image(image_from_env())?;
+        Ok(layout)
     }
 
-    /// Reads a memory layout configuration file from the given `path`.
+    /// Reads a memory layout configuration file from the given `path`. If the
+    /// file has an `extend` key, it is merged on top of the referenced file.
+    /// Errors are annotated with the offending lines of the source files.
     pub fn read(path: &Path) -> Result<Self> {
-        Self::parse(&fs::read_to_string(path)?)
+        let mut sources = Vec::new();
+        let value = read_extended(path, &mut Vec::new(), &mut sources)?;
+        Self::from_value(&value, &sources)
+    }
+
+    fn from_value(value: &toml::Value, sources: &[Source]) -> Result<Self> {
+        let layout = region_references(value).and_then(|references| {
+            let layout = expr::with_references(references, || {
+                if let [source] = sources {
+                    toml::from_str::<Self>(&source.text)
+                } else {
+                    value.clone().try_into::<Self>()
+                }
+            })?;
+            Self::build(layout)
+        });
+        layout.map_err(|err| diagnostic::annotate(err, sources))
+    }
+
+    /// Reads a memory layout previously calculated and written by
+    /// [`Layout::write`] from the given `path`. Unlike [`Layout::read`], the
+    /// auto-calculated values are taken from the file as is.
+    pub fn read_calculated(path: &Path) -> Result<Self> {
+        let string = fs::read_to_string(path)?;
+        let layout = calculated::with(|| toml::from_str::<Self>(&string))?;
+        layout.validate().wrap_err("layout config validation error")?;
+        layout.validate_placement().wrap_err("layout config validation error")?;
+        Ok(layout)
+    }
+
+    /// Reads either a memory layout configuration file or a memory layout
+    /// calculated during a build from the given `path`. Configuration files
+    /// are calculated with the given data sizes, see
+    /// [`Layout::calculate_with_data_sizes`].
+    pub fn read_any(
+        path: &Path,
+        data_size: u64,
+        data_sizes: &IndexMap<String, u64>,
+    ) -> Result<Self> {
+        let value = fs::read_to_string(path)?.parse::<toml::Value>()?;
+        if value.get("data").and_then(|data| data.get("origin")).is_some() {
+            return Self::read_calculated(path);
+        }
+        let mut layout = Self::read(path)?;
+        layout
+            .calculate_with_data_sizes(Some(data_size), data_sizes)
+            .wrap_err("layout config calculation error")?;
+        Ok(layout)
+    }
+
+    /// Reads the memory layout calculated during the last build from the
+    /// target directory, falling back to calculating the layout from
+    /// `project_root` directory.
+    pub fn read_last_built(project_root: &Path) -> Result<Self> {
+        if let Ok(target) = crate::locate_target_root(project_root) {
+            let path = target.join(built_file_name("toml"));
+            if path.exists() {
+                let mut layout = Self::read_calculated(&path)?;
+                layout.select_image(image_from_env())?;
+                return Ok(layout);
+            }
+        }
+        Self::read_from_project_root(project_root)
+    }
+
+    /// Selects the `image` to link. The program code of the image is placed
+    /// into its flash region instead of `flash.program`.
+    pub fn select_image(&mut self, image: Option<String>) -> Result<()> {
+        if let Some(image) = &image {
+            if !self.image.contains_key(image) {
+                bail!("{LAYOUT_IMAGE_ENV} points to an unknown image {image}");
+            }
+        }
+        self.selected_image = image;
+        Ok(())
+    }
+
+    /// Returns the width of the target address space in bits.
+    pub fn address_width(&self) -> u32 {
+        self.address_width.unwrap_or(DEFAULT_ADDRESS_WIDTH)
+    }
+
+    /// Returns the first address past the end of the target address space.
+    pub fn address_space_end(&self) -> u128 {
+        1 << self.address_width()
+    }
+
+    /// Returns the size of the image header section, if the header is
+    /// enabled.
+    pub fn header_size(&self) -> Option<u64> {
+        self.header.as_ref().map(|header| header.size.unwrap_or(DEFAULT_HEADER_SIZE))
+    }
+
+    /// Returns the offset of the image header from the beginning of the
+    /// program flash region, if the header is enabled.
+    pub fn header_offset(&self) -> Option<u64> {
+        self.header.as_ref().map(|header| header.offset.unwrap_or(DEFAULT_HEADER_OFFSET))
+    }
+
+    /// Returns the flash region the program code is placed into, which is
+    /// the selected image's region or `program`.
+    pub fn program_flash(&self) -> &str {
+        self.selected_image
+            .as_ref()
+            .and_then(|image| self.image.get(image))
+            .map_or("program", |image| &image.flash)
     }
 
     /// Writes the memory layout to the file system.
@@ -208,7 +642,15 @@ impl Layout {
 
     /// Parses a memory layout configuration from the `string`.
     pub fn parse(string: &str) -> Result<Self> {
-        let mut layout = toml::from_str::<Self>(string)?;
+        let value = toml::from_str::<toml::Value>(string)?;
+        if value.get(EXTEND_KEY).is_some() {
+            bail!("`{EXTEND_KEY}` is supported only in layout config files");
+        }
+        let references = region_references(&value)?;
+        Self::build(expr::with_references(references, || toml::from_str(string))?)
+    }
+
+    fn build(mut layout: Self) -> Result<Self> {
         layout.validate().wrap_err("layout config validation error")?;
         layout.calculate(None).wrap_err("layout config calculation error")?;
         Ok(layout)
@@ -217,18 +659,33 @@ impl Layout {
     /// Returns `Err` if the layout is not valid.
     pub fn validate(&self) -> Result<()> {
         self.validate_coherence()?;
+        self.validate_regions()?;
         self.validate_stream_sizes()?;
         self.validate_stream_init_primary()?;
         self.validate_addresses()?;
+        self.validate_alignments()?;
+        self.validate_report()?;
         Ok(())
     }
 
     /// Calculates a fixed layout. `data_size` is the size of BSS and DATA
     /// sections combined.
-    #[allow(clippy::cast_precision_loss)]
-    pub fn calculate(&mut self, data_size: Option<u32>) -> Result<()> {
+    pub fn calculate(&mut self, data_size: Option<u64>) -> Result<()> {
+        self.calculate_with_data_sizes(data_size, &IndexMap::new())
+    }
+
+    /// Calculates a fixed layout. `data_size` is the size of the main BSS and
+    /// DATA sections combined, and `data_sizes` contains the same for the
+    /// named data sections. Data sections of unknown size share the remaining
+    /// space of their RAM region equally.
+    pub fn calculate_with_data_sizes(
+        &mut self,
+        data_size: Option<u64>,
+        data_sizes: &IndexMap<String, u64>,
+    ) -> Result<()> {
         self.calculate_fixed_blocks();
         for (key, ram) in &self.ram {
+            let (origin, size) = free_span(key, ram, &self.reserved, self.crashlog.as_ref())?;
             let mut stacks = self.stack.values_mut().filter(|s| &s.ram == key).collect::<Vec<_>>();
             let (global_stream, mut streams) = unzip_option(self.stream.as_mut().map(|stream| {
                 ((&stream.ram, stream.fixed_size, &mut stream.origin), &mut stream.sections)
@@ -238,82 +695,177 @@ impl Layout {
                 .iter_mut()
                 .flat_map(|sections| sections.values_mut().filter(|s| &s.ram == key))
                 .collect::<Vec<_>>();
+            let mut codes =
+                self.code.values_mut().filter(|c| c.ram.as_ref() == Some(key)).collect::<Vec<_>>();
             let mut heaps = self
                 .heap
                 .values_mut()
                 .map(|h| &mut h.section)
                 .filter(|s| &s.ram == key)
                 .collect::<Vec<_>>();
+            let mut datas = DataSlot::collect(&mut self.data, key, data_size, data_sizes);
             let fixed_first = stacks.first().map_or(false, |s| s.size.is_fixed());
-            let fixed_size = stacks.iter().filter_map(|s| s.size.fixed()).sum::<u32>()
+            let align_slack = stacks
+                .iter()
+                .map(|s| s.align)
+                .chain(streams.iter().map(|s| s.align))
+                .chain(codes.iter().map(|c| c.align))
+                .chain(heaps.iter().map(|s| s.align))
+                .chain(datas.iter().map(|d| d.align))
+                .map(|align| align.map_or(0, |align| align - 1))
+                .sum::<u64>();
+            let fixed_size = stacks.iter().filter_map(|s| s.size.fixed()).sum::<u64>()
+                + stacks.iter().map(|s| s.prefix_size).sum::<u64>()
                 + global_stream.as_ref().map_or(0, |&(_, fixed_size, _)| fixed_size)
-                + streams.iter().map(|s| s.size + s.prefix_size).sum::<u32>()
-                + heaps.iter().filter_map(|s| s.size.fixed()).sum::<u32>()
-                + heaps.iter().map(|s| s.prefix_size).sum::<u32>();
-            let mut flexible_size = ram.size.checked_sub(fixed_size).ok_or_else(|| {
-                eyre!(
-                    "ram.{key} size is not enough to store all sections ({} < {})",
-                    ram.size,
-                    fixed_size
-                )
+                + streams.iter().map(|s| s.size + s.prefix_size).sum::<u64>()
+                + codes.iter().filter_map(|c| c.size).sum::<u64>()
+                + heaps.iter().filter_map(|s| s.size.fixed()).sum::<u64>()
+                + heaps.iter().map(|s| s.prefix_size).sum::<u64>()
+                + align_slack;
+            let mut flexible_size = size.checked_sub(fixed_size).ok_or_else(|| {
+                eyre!("ram.{key} size is not enough to store all sections ({size} < {fixed_size})")
             })?;
-            let data_size = (&self.data.ram == key).then(|| data_size.unwrap_or(flexible_size));
-            flexible_size -= data_size.unwrap_or(0);
-            let flexible_sum = stacks.iter().filter_map(|s| s.size.flexible()).sum::<f32>()
-                + heaps.iter().filter_map(|s| s.size.flexible()).sum::<f32>();
-            let flexible_term = flexible_size as f32 / flexible_sum;
-            let mut flexible_count = stacks.iter().filter(|s| s.size.is_flexible()).count()
-                + heaps.iter().filter(|s| s.size.is_flexible()).count();
-            let mut fixed_pointer = ram.origin + ram.size;
-            let mut flexible_pointer = ram.origin;
+            flexible_size = calculate_data_sizes(key, &mut datas, flexible_size)?;
+            let weights = stacks.iter().chain(&heaps).filter_map(|s| s.size.flexible());
+            let mut distribution = Distribution::new(flexible_size, weights);
+            let mut fixed_pointer = origin + size;
+            let mut flexible_pointer = origin;
             if fixed_first {
                 mem::swap(&mut fixed_pointer, &mut flexible_pointer);
             }
-            let mut correction = 0.0;
             calculate_flexible_sections(
                 &mut stacks,
                 fixed_first,
-                flexible_term,
-                &mut flexible_count,
+                &mut distribution,
                 &mut fixed_pointer,
                 &mut flexible_pointer,
-                &mut correction,
             );
-            let data_origin = calculate_fixed_sections(
+            calculate_fixed_sections(
                 &mut global_stream,
                 &mut streams,
-                data_size,
+                &mut codes,
+                &mut datas,
                 fixed_first,
                 &mut fixed_pointer,
             );
-            if let Some((data_origin, data_size)) = data_origin.zip(data_size) {
-                self.data.origin = data_origin;
-                self.data.size = data_size;
-            }
             calculate_flexible_sections(
                 &mut heaps,
                 fixed_first,
-                flexible_term,
-                &mut flexible_count,
+                &mut distribution,
                 &mut fixed_pointer,
                 &mut flexible_pointer,
-                &mut correction,
             );
         }
         calculate_pools(&mut self.heap)?;
+        self.validate_placement()?;
+        if let Some(mpu) = &self.mpu {
+            mpu.regions(self).wrap_err("MPU configuration error")?;
+        }
+        Ok(())
+    }
+
+    /// Returns `Err` if any of the calculated sections overflows the address
+    /// space, doesn't fit into its region, or overlaps another section, or if
+    /// heap pools don't fit into their heap.
+    pub fn validate_placement(&self) -> Result<()> {
+        for (key, heap) in &self.heap {
+            let used = heap.pools.iter().map(|p| p.block * p.fixed_count).sum::<u64>();
+            if used > heap.section.fixed_size {
+                bail!(
+                    "heap.{key} pools take {used} bytes, which exceeds the heap size ({})",
+                    heap.section.fixed_size
+                );
+            }
+        }
+        let mut placed = Vec::new();
+        let mut place = |path: String, key: &str, origin: u64, size: u64| -> Result<()> {
+            let end = u128::from(origin) + u128::from(size);
+            if end > self.address_space_end() {
+                bail!(
+                    "{path} overflows the {}-bit address space ({} + {size})",
+                    self.address_width(),
+                    addr::to_string(origin)
+                );
+            }
+            if let Some(ram) = self.ram.get(key) {
+                if origin < ram.origin || end > u128::from(ram.origin) + u128::from(ram.size) {
+                    bail!(
+                        "{path} ({}..{end:#010x}) is outside of ram.{key} ({}..{})",
+                        addr::to_string(origin),
+                        addr::to_string(ram.origin),
+                        addr::to_string(ram.origin + ram.size)
+                    );
+                }
+            }
+            if size > 0 {
+                placed.push((path, origin, end));
+            }
+            Ok(())
+        };
+        place("data".into(), &self.data.ram, self.data.origin, self.data.size)?;
+        for (key, data) in &self.data.sections {
+            place(format!("data.{key}"), &data.ram, data.origin, data.size)?;
+        }
+        for (key, code) in &self.code {
+            if let Some((ram, size)) = code.ram.as_ref().zip(code.size) {
+                place(format!("code.{key}"), ram, code.origin, size)?;
+            }
+        }
+        for (key, stack) in &self.stack {
+            let size = stack.prefix_size + stack.fixed_size;
+            place(format!("stack.{key}"), &stack.ram, stack.origin, size)?;
+        }
+        if let Some(stream) = &self.stream {
+            place("stream".into(), &stream.ram, stream.origin, stream.fixed_size)?;
+            for (key, section) in &stream.sections {
+                let size = section.prefix_size + section.size;
+                place(format!("stream.{key}"), &section.ram, section.origin, size)?;
+            }
+        }
+        for (key, heap) in &self.heap {
+            let size = heap.section.prefix_size + heap.section.fixed_size;
+            place(format!("heap.{key}"), &heap.section.ram, heap.section.origin, size)?;
+        }
+        for (key, reserved) in &self.reserved {
+            place(format!("reserved.{key}"), &reserved.ram, reserved.origin, reserved.size)?;
+        }
+        if let Some(crashlog) = &self.crashlog {
+            place("crashlog".into(), &crashlog.ram, crashlog.origin, crashlog.size)?;
+        }
+        placed.sort_by_key(|&(_, origin, _)| origin);
+        for pair in placed.windows(2) {
+            let ((a_path, a_origin, a_end), (b_path, b_origin, _)) = (&pair[0], &pair[1]);
+            if *a_end > u128::from(*b_origin) {
+                bail!(
+                    "{a_path} ({}..{a_end:#010x}) overlaps {b_path} starting at {}",
+                    addr::to_string(*a_origin),
+                    addr::to_string(*b_origin)
+                );
+            }
+        }
         Ok(())
     }
 
-    #[allow(clippy::cast_possible_truncation)]
     fn calculate_fixed_blocks(&mut self) {
+        if let Some(crashlog) = &mut self.crashlog {
+            if let Some(ram) = self.ram.get(&crashlog.ram) {
+                crashlog.origin = (ram.origin + ram.size).saturating_sub(crashlog.size);
+            }
+        }
         if let Some(stream) = &mut self.stream {
             stream.fixed_size = STREAM_GLOBAL_RUNTIME_SIZE;
             for stream in stream.sections.values_mut() {
                 stream.prefix_size = STREAM_RUNTIME_SIZE;
             }
         }
+        let guard = self.mpu.as_ref().and_then(|mpu| mpu.stack_guard).unwrap_or(0);
+        for stack in self.stack.values_mut() {
+            stack.prefix_size = guard;
+        }
+        let words = u64::from(self.address_width() / 32);
         for heap in self.heap.values_mut() {
-            heap.section.prefix_size = HEAP_PREFIX_SIZE + HEAP_POOL_SIZE * heap.pools.len() as u32;
+            let size = HEAP_PREFIX_SIZE + HEAP_POOL_SIZE * heap.pools.len() as u64;
+            heap.section.prefix_size = size * words;
         }
     }
 
@@ -324,7 +876,51 @@ impl Layout {
             }
             Ok(())
         }
+        fn validate_flash(layout: &Layout, path: &str, key: &str) -> Result<()> {
+            if !layout.flash.contains_key(key) {
+                bail!("{path} points to an unknown flash region {key}");
+            }
+            Ok(())
+        }
         validate_ram(self, "data.ram", &self.data.ram)?;
+        if let Some(load_from) = &self.data.load_from {
+            validate_flash(self, "data.load-from", load_from)?;
+        }
+        for (name, data) in &self.data.sections {
+            validate_ram(self, &format!("data.sections.{name}.ram"), &data.ram)?;
+            if let Some(load_from) = &data.load_from {
+                validate_flash(self, &format!("data.sections.{name}.load-from"), load_from)?;
+            }
+            if data.dma.unwrap_or(false) && self.ram[&data.ram].no_dma.unwrap_or(false) {
+                bail!(
+                    "data.sections.{name} holds DMA buffers, but ram.{} is inaccessible for DMA",
+                    data.ram
+                );
+            }
+        }
+        for (name, code) in &self.code {
+            match (&code.ram, &code.flash) {
+                (Some(ram), None) => {
+                    validate_ram(self, &format!("code.{name}.ram"), ram)?;
+                    if let Some(load_from) = &code.load_from {
+                        validate_flash(self, &format!("code.{name}.load-from"), load_from)?;
+                    }
+                    if code.size.is_none() {
+                        bail!("code.{name}.size must be set for a section executed from RAM");
+                    }
+                }
+                (None, Some(flash)) => {
+                    validate_flash(self, &format!("code.{name}.flash"), flash)?;
+                    if code.load_from.is_some() {
+                        bail!("code.{name}.load-from is only applicable with code.{name}.ram");
+                    }
+                    if code.size.is_some() {
+                        bail!("code.{name}.size is only applicable with code.{name}.ram");
+                    }
+                }
+                _ => bail!("code.{name} must have exactly one of `ram` or `flash` set"),
+            }
+        }
         for (name, stack) in &self.stack {
             validate_ram(self, &format!("stack.{name}.ram"), &stack.ram)?;
         }
@@ -337,18 +933,77 @@ impl Layout {
         for (name, heap) in &self.heap {
             validate_ram(self, &format!("heap.{name}.ram"), &heap.section.ram)?;
         }
+        for (name, reserved) in &self.reserved {
+            validate_ram(self, &format!("reserved.{name}.ram"), &reserved.ram)?;
+        }
+        if let Some(crashlog) = &self.crashlog {
+            validate_ram(self, "crashlog.ram", &crashlog.ram)?;
+        }
+        let mut image_flashes = BTreeMap::new();
+        for (name, image) in &self.image {
+            validate_flash(self, &format!("image.{name}.flash"), &image.flash)?;
+            if let Some(other) = image_flashes.insert(&image.flash, name) {
+                bail!(
+                    "image.{name}.flash points to flash.{} already used by image.{other}",
+                    image.flash
+                );
+            }
+        }
+        Ok(())
+    }
+
+    fn validate_regions(&self) -> Result<()> {
+        let width = self.address_width();
+        if width != 32 && width != 64 {
+            bail!("address-width must be either 32 or 64 ({width})");
+        }
+        let flash = self.flash.iter().map(|(key, memory)| (format!("flash.{key}"), memory));
+        let ram = self.ram.iter().map(|(key, memory)| (format!("ram.{key}"), memory));
+        let mut regions = flash.chain(ram).collect::<Vec<_>>();
+        for (path, memory) in &regions {
+            if u128::from(memory.origin) + u128::from(memory.size) > self.address_space_end() {
+                bail!(
+                    "{path} overflows the {width}-bit address space ({} + {})",
+                    addr::to_string(memory.origin),
+                    size::to_string(memory.size)
+                );
+            }
+        }
+        for (path, memory) in &regions {
+            if let Some(mode) = &memory.mode {
+                if mode.is_empty() || !mode.chars().all(|c| "rwxailRWXAIL!".contains(c)) {
+                    bail!(
+                        "{path}.mode must consist of `r`, `w`, `x`, `a`, `i`, `l` or `!` ({mode})"
+                    );
+                }
+            }
+        }
+        regions.sort_by_key(|(_, memory)| memory.origin);
+        for pair in regions.windows(2) {
+            let (a_path, a) = &pair[0];
+            let (b_path, b) = &pair[1];
+            if a.origin + a.size > b.origin {
+                bail!(
+                    "{a_path} ({}..{}) overlaps {b_path} ({}..{})",
+                    addr::to_string(a.origin),
+                    addr::to_string(a.origin + a.size),
+                    addr::to_string(b.origin),
+                    addr::to_string(b.origin + b.size)
+                );
+            }
+        }
         Ok(())
     }
 
     fn validate_stream_sizes(&self) -> Result<()> {
         if let Some(stream) = &self.stream {
             for (name, stream) in &stream.sections {
-                if stream.size < MIN_BUFFER_SIZE {
+                if stream.size < MIN_BUFFER_SIZE.into() {
                     bail!(
                         "stream.{name}.size is set to {}, which is less than the minimum possible \
                          size {}",
                         size::to_string(stream.size),
-                        size::to_string(MIN_BUFFER_SIZE)
+                        size::to_string(MIN_BUFFER_SIZE.into())
                     );
                 }
             }
@@ -378,6 +1033,11 @@ impl Layout {
             validate_address(ram.origin, false, || format!("ram.{key}.origin"))?;
             validate_address(ram.size, true, || format!("ram.{key}.size"))?;
         }
+        for (key, code) in &self.code {
+            if let Some(size) = code.size {
+                validate_address(size, true, || format!("code.{key}.size"))?;
+            }
+        }
         for (key, stack) in &self.stack {
             if let Some(size) = stack.size.fixed() {
                 validate_address(size, true, || format!("stack.{key}.size"))?;
@@ -394,154 +1054,480 @@ impl Layout {
             }
             for (i, pool) in heap.pools.iter().enumerate() {
                 validate_address(pool.block, true, || format!("heap.{key}.pools[{i}].block"))?;
+                if let PoolCount::Bytes(bytes) = pool.count {
+                    if bytes % pool.block != 0 {
+                        bail!(
+                            "heap.{key}.pools[{i}].count is not a multiple of the block size \
+                             ({bytes} % {})",
+                            pool.block
+                        );
+                    }
+                }
             }
         }
-        Ok(())
-    }
-}
-
-fn validate_address(value: u32, non_zero: bool, name: impl FnOnce() -> String) -> Result<()> {
-    let reminder = value % ALIGN;
-    if reminder != 0 {
-        bail!("{} is not word-aligned ({value} % {ALIGN} == {reminder})", name());
-    }
-    if non_zero && value == 0 {
-        bail!("{} must be greater than zero", name());
-    }
-    Ok(())
-}
-
-fn calculate_fixed_sections(
-    global_stream: &mut Option<(&String, u32, &mut u32)>,
-    streams: &mut [&mut FixedSection],
-    data_size: Option<u32>,
-    fixed_first: bool,
-    fixed_pointer: &mut u32,
-) -> Option<u32> {
-    if let Some((_, ref fixed_size, &mut ref mut origin)) = global_stream {
-        if fixed_first {
-            *origin = *fixed_pointer;
-            *fixed_pointer += fixed_size;
-        } else {
-            *fixed_pointer -= fixed_size;
-            *origin = *fixed_pointer;
+        for (key, reserved) in &self.reserved {
+            validate_address(reserved.origin, false, || format!("reserved.{key}.origin"))?;
+            validate_address(reserved.size, true, || format!("reserved.{key}.size"))?;
         }
-    }
-    for stream in streams {
-        if fixed_first {
-            stream.origin = *fixed_pointer;
-            *fixed_pointer += stream.size + stream.prefix_size;
-        } else {
-            *fixed_pointer -= stream.size + stream.prefix_size;
-            stream.origin = *fixed_pointer;
+        if let Some(size) = self.header.as_ref().and_then(|header| header.size) {
+            validate_address(size, true, || "header.size".into())?;
+            if size < IMAGE_HEADER_SIZE {
+                bail!("header.size must be at least {IMAGE_HEADER_SIZE} bytes ({size})");
+            }
         }
-    }
-    data_size.map(|data_size| {
-        let data_origin;
-        if fixed_first {
-            data_origin = *fixed_pointer;
-            *fixed_pointer += data_size;
-        } else {
-            *fixed_pointer -= data_size;
-            data_origin = *fixed_pointer;
+        if let Some(offset) = self.header.as_ref().and_then(|header| header.offset) {
+            validate_address(offset, false, || "header.offset".into())?;
         }
-        data_origin
-    })
-}
+        if let Some(crashlog) = &self.crashlog {
+            validate_address(crashlog.size, true, || "crashlog.size".into())?;
+            if crashlog.size < CRASHLOG_HEADER_SIZE {
+                bail!(
+                    "crashlog.size must be at least {CRASHLOG_HEADER_SIZE} bytes to hold the \
+                     crash record header ({})",
+                    crashlog.size
+                );
+            }
+        }
+        Ok(())
+    }
 
-#[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss, clippy::cast_precision_loss)]
-fn calculate_flexible_sections(
-    sections: &mut [&mut Section],
-    fixed_first: bool,
-    flexible_term: f32,
-    flexible_count: &mut usize,
-    fixed_pointer: &mut u32,
-    flexible_pointer: &mut u32,
-    correction: &mut f32,
-) {
-    for section in sections {
-        match section.size {
-            size::Flexible::Fixed(size) => {
-                section.fixed_size = size;
-                if fixed_first {
-                    section.origin = *fixed_pointer;
-                    *fixed_pointer += section.fixed_size + section.prefix_size;
-                } else {
-                    *fixed_pointer -= section.fixed_size + section.prefix_size;
-                    section.origin = *fixed_pointer;
+    fn validate_alignments(&self) -> Result<()> {
+        fn validate_align(align: Option<u64>, size: Option<u64>, path: &str) -> Result<()> {
+            if let Some(align) = align {
+                if !align.is_power_of_two() || align < ALIGN {
+                    bail!("{path}.align must be a power of two not less than {ALIGN} ({align})");
                 }
-            }
-            size::Flexible::Flexible(size) => {
-                let mut decimal_size = (size + *correction) * flexible_term;
-                *flexible_count -= 1;
-                if *flexible_count > 0 {
-                    *correction = decimal_size % ALIGN as f32;
-                    if *correction > ALIGN as f32 / 2.0 {
-                        *correction -= ALIGN as f32;
+                if let Some(size) = size {
+                    if size % align != 0 {
+                        bail!("{path}.size is not a multiple of {path}.align ({size} % {align})");
                     }
-                    decimal_size -= *correction;
-                    *correction /= flexible_term;
-                }
-                section.fixed_size = decimal_size.floor() as _;
-                if fixed_first {
-                    *flexible_pointer -= section.fixed_size + section.prefix_size;
-                    section.origin = *flexible_pointer;
-                } else {
-                    section.origin = *flexible_pointer;
-                    *flexible_pointer += section.fixed_size + section.prefix_size;
                 }
             }
+            Ok(())
         }
-    }
-}
-
-#[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss, clippy::cast_precision_loss)]
-fn calculate_pools(heaps: &mut IndexMap<String, Heap>) -> Result<()> {
-    for (key, heap) in heaps {
-        heap.pools.sort_unstable_by_key(|p| p.block);
-        let fixed_size = heap.pools.iter().filter_map(|p| p.count.fixed()).sum::<u32>();
-        let mut flexible_size =
-            heap.section.fixed_size.checked_sub(fixed_size).ok_or_else(|| {
-                eyre!(
-                    "heap.{key} size is not enough to store all pools ({} < {})",
-                    heap.section.fixed_size,
-                    fixed_size
-                )
-            })?;
-        let flexible_sum = heap.pools.iter().filter_map(|p| p.count.flexible()).sum::<f32>();
-        let flexible_term = flexible_size as f32 / flexible_sum;
-        let mut flexible_count = heap.pools.iter().filter(|p| p.count.is_flexible()).count();
-        let mut correction = 0.0;
-        for pool in &mut heap.pools {
-            match pool.count {
-                size::Flexible::Fixed(size) => {
-                    pool.fixed_count = size;
+        validate_align(self.data.align, None, "data")?;
+        for (key, data) in &self.data.sections {
+            validate_align(data.align, None, &format!("data.sections.{key}"))?;
+        }
+        for (key, code) in &self.code {
+            validate_align(code.align, code.size, &format!("code.{key}"))?;
+        }
+        for (key, stack) in &self.stack {
+            validate_align(stack.align, stack.size.fixed(), &format!("stack.{key}"))?;
+        }
+        if let Some(stream) = &self.stream {
+            for (key, stream) in &stream.sections {
+                validate_align(stream.align, None, &format!("stream.{key}"))?;
+            }
+        }
+        for (key, heap) in &self.heap {
+            validate_align(heap.section.align, None, &format!("heap.{key}"))?;
+        }
+        Ok(())
+    }
+
+    fn validate_report(&self) -> Result<()> {
+        let Report { flash_threshold, ram_threshold, min_size: _ } = self.report;
+        for (name, threshold) in
+            [("flash-threshold", flash_threshold), ("ram-threshold", ram_threshold)]
+        {
+            if let Some(threshold) = threshold {
+                if !(0.0..=100.0).contains(&threshold) {
+                    bail!("report.{name} must be a percentage between 0 and 100 ({threshold})");
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Returns the file name of the layout calculated during the last build
+/// inside the target directory, which is specific to the selected image.
+pub fn built_file_name(extension: &str) -> String {
+    match image_from_env() {
+        Some(image) => format!("layout.{image}.{extension}"),
+        None => format!("layout.{extension}"),
+    }
+}
+
+fn profile_file_name() -> Option<String> {
+    env::var(LAYOUT_PROFILE_ENV)
+        .ok()
+        .filter(|profile| !profile.is_empty())
+        .map(|profile| format!("layout.{profile}.toml"))
+}
+
+fn image_from_env() -> Option<String> {
+    env::var(LAYOUT_IMAGE_ENV).ok().filter(|image| !image.is_empty())
+}
+
+fn read_extended(
+    path: &Path,
+    chain: &mut Vec<PathBuf>,
+    sources: &mut Vec<Source>,
+) -> Result<toml::Value> {
+    let canonical = path
+        .canonicalize()
+        .wrap_err_with(|| format!("couldn't open layout config {}", path.display()))?;
+    if chain.contains(&canonical) {
+        bail!("layout config {} extends itself", path.display());
+    }
+    chain.push(canonical);
+    let source = Source::new(path.display().to_string(), fs::read_to_string(path)?);
+    extend_source(source, path.parent().unwrap_or_else(|| Path::new(".")), None, chain, sources)
+}
+
+/// Parses the layout config `source` and merges it on top of the file its
+/// `extend` key references relative to `dir`, or `default_base` if the key
+/// is absent.
+fn extend_source(
+    source: Source,
+    dir: &Path,
+    default_base: Option<PathBuf>,
+    chain: &mut Vec<PathBuf>,
+    sources: &mut Vec<Source>,
+) -> Result<toml::Value> {
+    let name = source.name.clone();
+    let mut value = toml::from_str::<toml::Value>(&source.text)
+        .map_err(|err| diagnostic::annotate(err.into(), slice::from_ref(&source)))?;
+    sources.push(source);
+    let table =
+        value.as_table_mut().ok_or_else(|| eyre!("layout config {name} is not a table"))?;
+    let base = match table.remove(EXTEND_KEY) {
+        None => default_base,
+        Some(toml::Value::String(base)) => Some(dir.join(base)),
+        Some(_) => bail!("`{EXTEND_KEY}` in {name} must be a path string"),
+    };
+    match base {
+        None => Ok(value),
+        Some(base) => {
+            let mut base = read_extended(&base, chain, sources)?;
+            merge(&mut base, value);
+            Ok(base)
+        }
+    }
+}
+
+/// Evaluates origins and sizes of all memory regions, so that they can be
+/// referenced from expressions.
+fn region_references(value: &toml::Value) -> Result<BTreeMap<String, u64>> {
+    let mut raw = BTreeMap::new();
+    for kind in ["flash", "ram"] {
+        for (name, region) in value.get(kind).and_then(toml::Value::as_table).into_iter().flatten()
+        {
+            for field in ["origin", "size"] {
+                if let Some(field_value) = region.get(field) {
+                    raw.insert(format!("{kind}.{name}.{field}"), field_value);
                 }
-                size::Flexible::Flexible(size) => {
-                    let mut decimal_count = (size + correction) * flexible_term;
-                    flexible_count -= 1;
-                    if flexible_count > 0 {
-                        correction = decimal_count % pool.block as f32;
-                        if correction > pool.block as f32 / 2.0 {
-                            correction -= pool.block as f32;
-                        }
-                        decimal_count -= correction;
-                        correction /= flexible_term;
+            }
+        }
+    }
+    let mut references = BTreeMap::new();
+    for key in raw.keys() {
+        resolve_reference(key, &raw, &mut references, &mut Vec::new())?;
+        if let Some(region) = key.strip_suffix(".origin") {
+            if raw.contains_key(&format!("{region}.size")) {
+                resolve_reference(
+                    &format!("{region}.end"),
+                    &raw,
+                    &mut references,
+                    &mut Vec::new(),
+                )?;
+            }
+        }
+    }
+    Ok(references)
+}
+
+fn resolve_reference(
+    name: &str,
+    raw: &BTreeMap<String, &toml::Value>,
+    resolved: &mut BTreeMap<String, u64>,
+    chain: &mut Vec<String>,
+) -> Result<u64> {
+    if let Some(&value) = resolved.get(name) {
+        return Ok(value);
+    }
+    let (region, field) = expr::check_reference(name)?;
+    let value = if field == "end" {
+        let origin = resolve_reference(&format!("{region}.origin"), raw, resolved, chain)?;
+        let size = resolve_reference(&format!("{region}.size"), raw, resolved, chain)?;
+        origin.checked_add(size).ok_or_else(|| eyre!("{region} overflows the address space"))?
+    } else {
+        let raw_value = raw.get(name).ok_or_else(|| eyre!("unknown reference `{name}`"))?;
+        if chain.iter().any(|key| key == name) {
+            bail!("{name} references itself ({} -> {name})", chain.join(" -> "));
+        }
+        chain.push(name.to_string());
+        let value = match raw_value {
+            toml::Value::Integer(value) => {
+                u64::try_from(*value).map_err(|_| eyre!("{name} must not be negative"))?
+            }
+            toml::Value::String(string) => expr::evaluate_with(string, &mut |reference| {
+                resolve_reference(reference, raw, resolved, chain)
+            })
+            .map_err(|err| eyre!("{name}: {err}"))?,
+            _ => bail!("{name} must be a string or an integer"),
+        };
+        chain.pop();
+        value
+    };
+    resolved.insert(name.to_string(), value);
+    Ok(value)
+}
+
+/// Merges `overlay` into `base`. Tables are merged recursively, while other
+/// values are replaced.
+fn merge(base: &mut toml::Value, overlay: toml::Value) {
+    match (base, overlay) {
+        (toml::Value::Table(base), toml::Value::Table(overlay)) => {
+            for (key, value) in overlay {
+                match base.get_mut(&key) {
+                    Some(base) => merge(base, value),
+                    None => {
+                        base.insert(key, value);
                     }
-                    pool.fixed_count = (decimal_count / pool.block as f32).floor() as _;
-                    flexible_size -= pool.fixed_count * pool.block;
                 }
             }
         }
-        for pool in heap.pools.iter_mut().rev() {
-            let add = flexible_size / pool.block;
+        (base, overlay) => *base = overlay,
+    }
+}
+
+fn validate_address(value: u64, non_zero: bool, name: impl FnOnce() -> String) -> Result<()> {
+    let reminder = value % ALIGN;
+    if reminder != 0 {
+        bail!("{} is not word-aligned ({value} % {ALIGN} == {reminder})", name());
+    }
+    if non_zero && value == 0 {
+        bail!("{} must be greater than zero", name());
+    }
+    Ok(())
+}
+
+/// Returns the largest span of `ram` not occupied by `reserved` sections or
+/// the crash log. The calculated sections are laid out inside this span only.
+/// Returns the span of `ram` left for the calculated sections. Returns `Err`
+/// if the reserved and crash log sections leave more than one free span.
+fn free_span(
+    key: &str,
+    ram: &Memory,
+    reserved: &IndexMap<String, Reserved>,
+    crashlog: Option<&Crashlog>,
+) -> Result<(u64, u64)> {
+    let mut reserved = reserved
+        .iter()
+        .map(|(name, r)| (format!("reserved.{name}"), &r.ram, r.origin, r.size))
+        .chain(crashlog.map(|c| ("crashlog".into(), &c.ram, c.origin, c.size)))
+        .filter(|(_, ram, _, _)| *ram == key)
+        .map(|(path, _, origin, size)| (origin, origin.saturating_add(size), path))
+        .collect::<Vec<_>>();
+    reserved.sort_unstable();
+    let end = ram.origin + ram.size;
+    let mut span = None;
+    let mut pointer = ram.origin;
+    let mut previous = None;
+    for (origin, reserved_end, path) in reserved.into_iter().chain([(end, end, String::new())]) {
+        let origin = origin.clamp(pointer, end);
+        if origin > pointer {
+            if let (Some(_), Some((path, origin, end))) = (span, previous) {
+                bail!(
+                    "{path} ({}..{}) leaves free space on both sides in ram.{key}; reserved \
+                     sections must be adjacent to the beginning or the end of the region",
+                    addr::to_string(origin),
+                    addr::to_string(end)
+                );
+            }
+            span = Some((pointer, origin - pointer));
+        }
+        pointer = pointer.max(reserved_end.min(end));
+        previous = Some((path, origin, reserved_end));
+    }
+    Ok(span.unwrap_or((ram.origin, 0)))
+}
+
+fn calculate_fixed_sections(
+    global_stream: &mut Option<(&String, u64, &mut u64)>,
+    streams: &mut [&mut FixedSection],
+    codes: &mut [&mut Code],
+    datas: &mut [DataSlot<'_>],
+    fixed_first: bool,
+    fixed_pointer: &mut u64,
+) {
+    if let Some((_, ref fixed_size, &mut ref mut origin)) = global_stream {
+        *origin = allocate(fixed_pointer, *fixed_size, None, fixed_first);
+    }
+    for stream in streams {
+        let size = stream.size + stream.prefix_size;
+        stream.origin = allocate(fixed_pointer, size, stream.align, fixed_first);
+    }
+    for code in codes {
+        code.origin = allocate(fixed_pointer, code.size.unwrap_or(0), code.align, fixed_first);
+    }
+    for data in datas {
+        *data.origin = allocate(fixed_pointer, *data.size, data.align, fixed_first);
+    }
+}
+
+/// Allocates `size` bytes at `pointer` aligned to `align`, moving the pointer
+/// upwards or downwards. Returns the origin of the allocated block.
+fn allocate(pointer: &mut u64, size: u64, align: Option<u64>, upwards: bool) -> u64 {
+    let align = align.unwrap_or(1);
+    let origin =
+        if upwards { pointer.div_ceil(align) * align } else { (*pointer - size) / align * align };
+    *pointer = if upwards { origin + size } else { origin };
+    origin
+}
+
+fn calculate_data_sizes(
+    key: &str,
+    datas: &mut [DataSlot<'_>],
+    mut flexible_size: u64,
+) -> Result<u64> {
+    let known_size = datas.iter().filter_map(|d| d.known_size).sum::<u64>();
+    flexible_size = flexible_size.checked_sub(known_size).ok_or_else(|| {
+        eyre!(
+            "ram.{key} size is not enough to store data sections ({flexible_size} < {known_size})"
+        )
+    })?;
+    let mut unknown_count = datas.iter().filter(|d| d.known_size.is_none()).count();
+    for data in datas {
+        *data.size = data.known_size.unwrap_or_else(|| {
+            let share = if unknown_count > 1 {
+                flexible_size / unknown_count as u64 / ALIGN * ALIGN
+            } else {
+                flexible_size
+            };
+            unknown_count -= 1;
+            flexible_size -= share;
+            share
+        });
+    }
+    Ok(flexible_size)
+}
+
+fn calculate_flexible_sections(
+    sections: &mut [&mut Section],
+    fixed_first: bool,
+    distribution: &mut Distribution,
+    fixed_pointer: &mut u64,
+    flexible_pointer: &mut u64,
+) {
+    for section in sections {
+        section.fixed_size = match section.size {
+            size::Flexible::Fixed(size) => size,
+            size::Flexible::Flexible(weight) => {
+                distribution.next(weight, section.align.unwrap_or(ALIGN))
+            }
+        };
+        let size = section.fixed_size + section.prefix_size;
+        section.origin = if section.size.is_fixed() {
+            allocate(fixed_pointer, size, section.align, fixed_first)
+        } else {
+            allocate(flexible_pointer, size, section.align, !fixed_first)
+        };
+    }
+}
+
+fn calculate_pools(heaps: &mut IndexMap<String, Heap>) -> Result<()> {
+    for (key, heap) in heaps {
+        heap.pools.sort_unstable_by_key(|p| p.block);
+        let fixed_size =
+            heap.pools.iter().filter_map(|p| p.count.blocks(p.block).map(|count| count * p.block));
+        let fixed_size = fixed_size.sum::<u64>();
+        let flexible_size = heap.section.fixed_size.checked_sub(fixed_size).ok_or_else(|| {
+            eyre!(
+                "heap.{key} size is not enough to store all pools ({} < {fixed_size})",
+                heap.section.fixed_size
+            )
+        })?;
+        let weights = heap.pools.iter().filter_map(|p| p.count.flexible());
+        let mut distribution = Distribution::new(flexible_size, weights);
+        for pool in &mut heap.pools {
+            pool.fixed_count = match pool.count.flexible() {
+                Some(weight) => distribution.next(weight, pool.block) / pool.block,
+                None => pool.count.blocks(pool.block).unwrap_or_default(),
+            };
+        }
+        let mut rest = flexible_size - distribution.allocated();
+        for pool in heap.pools.iter_mut().rev().filter(|p| p.count.is_flexible()) {
+            let add = rest / pool.block;
             pool.fixed_count += add;
-            flexible_size -= add * pool.block;
+            rest -= add * pool.block;
         }
     }
     Ok(())
 }
 
+/// Exact proportional distribution of free space between flexible sizes.
+///
+/// Each share ends at the cumulative weight boundary rounded down to the
+/// share's unit, so rounding errors never accumulate, and the shares add up
+/// to the whole space minus less than one unit.
+struct Distribution {
+    total: u64,
+    weight_sum: u64,
+    cumulative: u64,
+    allocated: u64,
+}
+
+impl Distribution {
+    fn new(total: u64, weights: impl Iterator<Item = u32>) -> Self {
+        let weight_sum = weights.map(u64::from).sum();
+        Self { total, weight_sum, cumulative: 0, allocated: 0 }
+    }
+
+    /// Returns the next share for `weight` as a multiple of `unit`.
+    fn next(&mut self, weight: u32, unit: u64) -> u64 {
+        self.cumulative += u64::from(weight);
+        let boundary =
+            u128::from(self.total) * u128::from(self.cumulative) / u128::from(self.weight_sum);
+        let boundary = u64::try_from(boundary).expect("boundary is within the total");
+        let share = (boundary - self.allocated) / unit * unit;
+        self.allocated += share;
+        share
+    }
+
+    fn allocated(&self) -> u64 {
+        self.allocated
+    }
+}
+
+struct DataSlot<'a> {
+    known_size: Option<u64>,
+    align: Option<u64>,
+    origin: &'a mut u64,
+    size: &'a mut u64,
+}
+
+impl<'a> DataSlot<'a> {
+    fn collect(
+        data: &'a mut Data,
+        key: &str,
+        data_size: Option<u64>,
+        data_sizes: &IndexMap<String, u64>,
+    ) -> Vec<Self> {
+        let mut slots = Vec::new();
+        if data.ram == key {
+            slots.push(DataSlot {
+                known_size: data_size,
+                align: data.align,
+                origin: &mut data.origin,
+                size: &mut data.size,
+            });
+        }
+        for (name, section) in data.sections.iter_mut().filter(|(_, s)| s.ram == key) {
+            slots.push(DataSlot {
+                known_size: data_sizes.get(name).copied(),
+                align: section.align,
+                origin: &mut section.origin,
+                size: &mut section.size,
+            });
+        }
+        slots
+    }
+}
+
 // TODO use `Option::unzip()` when `#![feature(unzip_option)]` is stabilized.
 fn unzip_option<T, U>(option: Option<(T, U)>) -> (Option<T>, Option<U>) {
     match option {
@@ -550,9 +1536,59 @@ fn unzip_option<T, U>(option: Option<(T, U)>) -> (Option<T>, Option<U>) {
     }
 }
 
+/// Serialization of the auto-calculated values. They are read only by
+/// [`Layout::read_calculated`], and rejected in layout config files.
+mod calculated {
+    use serde::{de, Deserializer};
+    use std::cell::Cell;
+
+    thread_local! {
+        static ENABLED: Cell<bool> = const { Cell::new(false) };
+    }
+
+    pub mod addr {
+        pub use crate::addr::serialize;
+        use serde::Deserializer;
+
+        pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u64, D::Error> {
+            super::check(deserializer, crate::addr::deserialize)
+        }
+    }
+
+    pub mod size {
+        pub use crate::size::serialize;
+        use serde::Deserializer;
+
+        pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u64, D::Error> {
+            super::check(deserializer, crate::size::deserialize)
+        }
+    }
+
+    /// Runs `f` with the auto-calculated values enabled.
+    pub fn with<T>(f: impl FnOnce() -> T) -> T {
+        let previous = ENABLED.with(|cell| cell.replace(true));
+        let result = f();
+        ENABLED.with(|cell| cell.set(previous));
+        result
+    }
+
+    fn check<'de, D: Deserializer<'de>>(
+        deserializer: D,
+        f: fn(D) -> Result<u64, D::Error>,
+    ) -> Result<u64, D::Error> {
+        if ENABLED.with(Cell::get) {
+            f(deserializer)
+        } else {
+            Err(de::Error::custom("the value is calculated automatically and can't be set"))
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use proptest::prelude::*;
+    use std::fmt::Write;
 
     #[test]
     fn test_single_flexible() {
@@ -633,7 +1669,7 @@ core0 = { ram = "main", size = "100%", pools = [{ block = "4", count = "100%" }]
             0x20000000 + 4 * 1024 + STREAM_RUNTIME_SIZE + STREAM_GLOBAL_RUNTIME_SIZE + 260 + 400
         );
         assert_eq!(heap[0].section.prefix_size, HEAP_POOL_SIZE + HEAP_PREFIX_SIZE);
-        assert_eq!(heap[0].section.fixed_size, 15688);
+        assert_eq!(heap[0].section.fixed_size, 15672);
         assert_eq!(
             stack[0].fixed_size
                 + stream[0].prefix_size
@@ -668,33 +1704,33 @@ core0 = { ram = "main", size = "75%", pools = [{ block = "4", count = "100%" }]
             layout.stream.iter().flat_map(|stream| stream.sections.values()).collect::<Vec<_>>();
         let heap = layout.heap.values().collect::<Vec<_>>();
         assert_eq!(stack[0].origin, 0x20000000);
-        assert_eq!(stack[0].fixed_size, 4944);
-        assert_eq!(heap[0].section.origin, 0x20000000 + 4944);
+        assert_eq!(stack[0].fixed_size, 4940);
+        assert_eq!(heap[0].section.origin, 0x20000000 + 4940);
         assert_eq!(heap[0].section.prefix_size, HEAP_POOL_SIZE + HEAP_PREFIX_SIZE);
-        assert_eq!(heap[0].section.fixed_size, 14840);
+        assert_eq!(heap[0].section.fixed_size, 14828);
         assert_eq!(
             layout.data.origin,
-            0x20000000 + 4944 + HEAP_POOL_SIZE + HEAP_PREFIX_SIZE + 14840
+            0x20000000 + 4940 + HEAP_POOL_SIZE + HEAP_PREFIX_SIZE + 14828
         );
         assert_eq!(layout.data.size, 400);
         assert_eq!(
             stream[0].origin,
-            0x20000000 + 4944 + HEAP_POOL_SIZE + HEAP_PREFIX_SIZE + 14840 + 400
+            0x20000000 + 4940 + HEAP_POOL_SIZE + HEAP_PREFIX_SIZE + 14828 + 400
         );
         assert_eq!(stream[0].prefix_size, STREAM_RUNTIME_SIZE);
         assert_eq!(stream[0].size, 260);
         assert_eq!(
             layout.stream.as_ref().unwrap().origin,
             0x20000000
-                + 4944
+                + 4940
                 + HEAP_POOL_SIZE
                 + HEAP_PREFIX_SIZE
-                + 14840
+                + 14828
                 + 400
                 + STREAM_RUNTIME_SIZE
                 + 260
         );
-        assert_eq!(heap[0].pools[0].fixed_count, 3710);
+        assert_eq!(heap[0].pools[0].fixed_count, 3707);
         assert_eq!(
             stack[0].fixed_size
                 + heap[0].section.prefix_size
@@ -757,6 +1793,291 @@ pools = [
         );
     }
 
+    #[test]
+    fn test_pool_counts() {
+        let layout = r#"
+[ram]
+main = { origin = 0x20000000, size = "20K" }
+[data]
+ram = "main"
+[heap.main]
+ram = "main"
+size = "1K"
+pools = [
+    { block = "4", count = "64 blocks" },
+    { block = "16", count = "256 bytes" },
+    { block = "32", count = "100%" },
+]
+"#;
+        let layout = Layout::parse(layout).unwrap();
+        let counts = layout.heap["main"].pools.iter().map(|p| p.fixed_count).collect::<Vec<_>>();
+        assert_eq!(counts, [64, 16, 16]);
+        assert_eq!(layout.heap["main"].pools[1].count.to_string(), "256 bytes");
+        let layout = r#"
+[ram]
+main = { origin = 0x20000000, size = "20K" }
+[data]
+ram = "main"
+[heap.main]
+ram = "main"
+size = "1K"
+pools = [{ block = "16", count = "100 bytes" }]
+"#;
+        let err = Layout::parse(layout).unwrap_err();
+        assert_eq!(
+            format!("{err:#}"),
+            "layout config validation error: heap.main.pools[0].count is not a multiple of the \
+             block size (100 % 16)"
+        );
+        let mut layout = Layout::parse(&layout.replace("100 bytes", "64")).unwrap();
+        layout.heap["main"].pools[0].fixed_count = 65;
+        let err = layout.validate_placement().unwrap_err();
+        assert_eq!(
+            format!("{err:#}"),
+            "heap.main pools take 1040 bytes, which exceeds the heap size (1024)"
+        );
+    }
+
+    #[test]
+    fn test_read_calculated() {
+        let layout = r#"
+[ram]
+main = { origin = 0x20000000, size = "20K" }
+[data]
+ram = "main"
+[stack]
+core0 = { ram = "main", size = "4K" }
+[stream]
+ram = "main"
+core0 = { ram = "main", size = "260", init-primary = true }
+[heap]
+core0 = { ram = "main", size = "100%", pools = [{ block = "4", count = "100%" }] }
+"#;
+        let mut layout = Layout::parse(layout).unwrap();
+        layout.calculate(Some(400)).unwrap();
+        let layout = toml::to_string(&layout).unwrap();
+        let path = env::temp_dir().join("drone-config-test-read-calculated.toml");
+        fs::write(&path, &layout).unwrap();
+        let calculated = Layout::read_calculated(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(toml::to_string(&calculated).unwrap(), layout);
+        assert_eq!(calculated.data.size, 400);
+        assert_eq!(calculated.stream.unwrap().sections["core0"].prefix_size, STREAM_RUNTIME_SIZE);
+    }
+
+    #[test]
+    fn test_calculated_in_config() {
+        let layout = r#"
+[ram]
+main = { origin = 0x20000000, size = "20K" }
+[data]
+ram = "main"
+origin = 0x20001000
+"#;
+        let err = Layout::parse(layout).unwrap_err();
+        assert!(format!("{err:#}").contains("calculated automatically"));
+    }
+
+    #[test]
+    fn test_extend() {
+        let base = r#"
+[ram]
+main = { origin = 0x20000000, size = "20K" }
+[data]
+ram = "main"
+[stack]
+core0 = { ram = "main", size = "4K" }
+"#;
+        let overlay = r#"
+extend = "drone-config-test-extend-base.toml"
+[ram]
+main = { size = "64K" }
+"#;
+        let dir = env::temp_dir();
+        let base_path = dir.join("drone-config-test-extend-base.toml");
+        let overlay_path = dir.join("drone-config-test-extend-overlay.toml");
+        fs::write(&base_path, base).unwrap();
+        fs::write(&overlay_path, overlay).unwrap();
+        let layout = Layout::read(&overlay_path);
+        fs::remove_file(&base_path).unwrap();
+        fs::remove_file(&overlay_path).unwrap();
+        let layout = layout.unwrap();
+        assert_eq!(layout.ram["main"].origin, 0x2000_0000);
+        assert_eq!(layout.ram["main"].size, 64 * 1024);
+        assert_eq!(layout.stack["core0"].fixed_size, 4 * 1024);
+    }
+
+    /// Returns the serialized field names of the struct `T`.
+    fn struct_fields<'de, T: Deserialize<'de>>() -> &'static [&'static str] {
+        #[derive(Debug)]
+        struct Fields(&'static [&'static str]);
+
+        impl fmt::Display for Fields {
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                write!(f, "{:?}", self.0)
+            }
+        }
+
+        impl std::error::Error for Fields {}
+
+        impl de::Error for Fields {
+            fn custom<T: fmt::Display>(_msg: T) -> Self {
+                Self(&[])
+            }
+        }
+
+        struct Capture;
+
+        impl<'de> Deserializer<'de> for Capture {
+            type Error = Fields;
+
+            fn deserialize_any<V: de::Visitor<'de>>(self, _visitor: V) -> Result<V::Value, Fields> {
+                Err(Fields(&[]))
+            }
+
+            fn deserialize_struct<V: de::Visitor<'de>>(
+                self,
+                _name: &'static str,
+                fields: &'static [&'static str],
+                _visitor: V,
+            ) -> Result<V::Value, Fields> {
+                Err(Fields(fields))
+            }
+
+            serde::forward_to_deserialize_any! {
+                bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string bytes
+                byte_buf option unit unit_struct newtype_struct seq tuple tuple_struct map enum
+                identifier ignored_any
+            }
+        }
+
+        T::deserialize(Capture).map(|_| ()).unwrap_err().0
+    }
+
+    #[test]
+    fn test_schema_fields() {
+        let schema = serde_json::from_str::<serde_json::Value>(LAYOUT_SCHEMA).unwrap();
+        let mut heap_fields = struct_fields::<Section>().to_vec();
+        heap_fields.push("pools");
+        // Calculated fields are written only into the calculated layout.
+        let types: &[(&str, &[&str], &[&str])] = &[
+            ("", struct_fields::<Layout>(), &[]),
+            ("/definitions/memory", struct_fields::<Memory>(), &[]),
+            ("/properties/data", struct_fields::<Data>(), &["origin", "size"]),
+            ("/definitions/data-section", struct_fields::<DataSection>(), &["origin", "size"]),
+            ("/definitions/code", struct_fields::<Code>(), &["origin"]),
+            ("/definitions/section", struct_fields::<Section>(), &[
+                "origin",
+                "fixed-size",
+                "prefix-size",
+            ]),
+            ("/definitions/fixed-section", struct_fields::<FixedSection>(), &[
+                "origin",
+                "prefix-size",
+            ]),
+            ("/definitions/heap", &heap_fields, &["origin", "fixed-size", "prefix-size"]),
+            ("/definitions/heap/properties/pools/items", struct_fields::<HeapPool>(), &[
+                "fixed-count",
+            ]),
+            ("/definitions/reserved", struct_fields::<Reserved>(), &[]),
+            ("/properties/crashlog", struct_fields::<Crashlog>(), &["origin"]),
+            ("/properties/image/additionalProperties", struct_fields::<Image>(), &[]),
+            ("/properties/header", struct_fields::<Header>(), &[]),
+            ("/properties/linker", struct_fields::<Linker>(), &[]),
+            ("/properties/report", struct_fields::<Report>(), &[]),
+            ("/properties/mpu", struct_fields::<Mpu>(), &[]),
+        ];
+        for (pointer, fields, calculated) in types {
+            assert!(!fields.is_empty(), "no fields captured for `{pointer}`");
+            let properties = schema
+                .pointer(&format!("{pointer}/properties"))
+                .and_then(serde_json::Value::as_object)
+                .unwrap_or_else(|| panic!("`{pointer}` has no properties in the schema"));
+            for field in fields.iter().filter(|field| !calculated.contains(field)) {
+                assert!(
+                    properties.contains_key(*field),
+                    "`{field}` of `{pointer}` is missing in the schema"
+                );
+            }
+            for property in properties.keys() {
+                assert!(
+                    fields.contains(&property.as_str()) || pointer.is_empty() && property == EXTEND_KEY,
+                    "`{property}` of `{pointer}` is not a layout config field"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_schema_project_templates() {
+        let schema = serde_json::from_str::<serde_json::Value>(LAYOUT_SCHEMA).unwrap();
+        let schema = jsonschema::JSONSchema::compile(&schema).unwrap();
+        let templates = Path::new(env!("CARGO_MANIFEST_DIR")).join("../project-templates");
+        let mut count = 0;
+        for entry in templates.read_dir().unwrap() {
+            let path = entry.unwrap().path().join(LAYOUT_CONFIG);
+            if !path.exists() {
+                continue;
+            }
+            let value = fs::read_to_string(&path).unwrap().parse::<toml::Value>().unwrap();
+            let value = serde_json::to_value(value).unwrap();
+            if let Err(errors) = schema.validate(&value) {
+                let errors = errors.map(|err| format!("{err} at {}", err.instance_path));
+                panic!("{} is invalid: {:#?}", path.display(), errors.collect::<Vec<_>>());
+            }
+            Layout::read(&path).unwrap();
+            count += 1;
+        }
+        assert!(count > 0, "no project templates found in {}", templates.display());
+    }
+
+    #[test]
+    fn test_read_str_extend() {
+        let base = r#"
+[ram]
+main = { origin = 0x20000000, size = "20K" }
+[data]
+ram = "main"
+[stack]
+core0 = { ram = "main", size = "4K" }
+"#;
+        let overlay = r#"
+extend = "drone-config-test-read-str-base.toml"
+[ram]
+main = { size = "64K" }
+"#;
+        let dir = env::temp_dir();
+        let base_path = dir.join("drone-config-test-read-str-base.toml");
+        fs::write(&base_path, base).unwrap();
+        let layout = Layout::read_str(overlay, &dir);
+        fs::remove_file(&base_path).unwrap();
+        let layout = layout.unwrap();
+        assert_eq!(layout.ram["main"].origin, 0x2000_0000);
+        assert_eq!(layout.ram["main"].size, 64 * 1024);
+        assert_eq!(layout.stack["core0"].fixed_size, 4 * 1024);
+    }
+
+    #[test]
+    fn test_region_references() {
+        let layout = r#"
+[ram]
+main = { origin = "0x2000_0000", size = "16KiB" }
+ccm = { origin = "align(ram.main.end, 64K)", size = "ram.main.size / 4" }
+[data]
+ram = "main"
+"#;
+        let layout = Layout::parse(layout).unwrap();
+        assert_eq!(layout.ram["ccm"].origin, 0x2001_0000);
+        assert_eq!(layout.ram["ccm"].size, 4 * 1024);
+        let layout = r#"
+[ram]
+main = { origin = "ram.main.end", size = "4K" }
+[data]
+ram = "main"
+"#;
+        assert!(Layout::parse(layout).is_err());
+    }
+
     #[test]
     fn test_stage_one() {
         let layout = r#"
@@ -790,4 +2111,510 @@ core0 = { ram = "main", size = "100%", pools = [{ block = "4", count = "100%" }]
             20 * 1024
         );
     }
+
+    #[test]
+    fn test_overlapping_regions() {
+        let layout = r#"
+[flash]
+program = { origin = 0x08000000, size = "64K" }
+[ram]
+main = { origin = 0x20000000, size = "20K" }
+ccm = { origin = 0x20004000, size = "4K" }
+[data]
+ram = "main"
+"#;
+        let err = Layout::parse(layout).unwrap_err();
+        assert_eq!(
+            format!("{err:#}"),
+            "layout config validation error: ram.main (0x20000000..0x20005000) overlaps ram.ccm \
+             (0x20004000..0x20005000)"
+        );
+    }
+
+    #[test]
+    fn test_region_overflow() {
+        let layout = r#"
+[ram]
+main = { origin = 0xFFFFF000, size = "8K" }
+[data]
+ram = "main"
+"#;
+        let err = Layout::parse(layout).unwrap_err();
+        assert_eq!(
+            format!("{err:#}"),
+            "layout config validation error: ram.main overflows the 32-bit address space \
+             (0xfffff000 + 8K)"
+        );
+    }
+
+    #[test]
+    fn test_address_width() {
+        let config = r#"
+address-width = 64
+[ram]
+main = { origin = "0x8_0000_0000", size = "8G" }
+[data]
+ram = "main"
+[stack]
+core0 = { ram = "main", size = "4K" }
+"#;
+        let mut layout = Layout::parse(config).unwrap();
+        layout.calculate(Some(1024)).unwrap();
+        assert_eq!(layout.stack[0].origin, 0x8_0000_0000);
+        assert_eq!(layout.data.origin, 0x8_0000_1000);
+        assert_eq!(layout.data.size, 1024);
+        assert_eq!(addr::to_string(layout.data.origin), "0x0000000800001000");
+        let err = Layout::parse(&config.replace("address-width = 64", "")).unwrap_err();
+        assert_eq!(
+            format!("{err:#}"),
+            "layout config validation error: ram.main overflows the 32-bit address space \
+             (0x0000000800000000 + 8G)"
+        );
+    }
+
+    #[test]
+    fn test_section_outside_of_region() {
+        let layout = r#"
+[ram]
+main = { origin = 0x20000000, size = "20K" }
+[data]
+ram = "main"
+[stack]
+core0 = { ram = "main", size = "4K" }
+"#;
+        let mut layout = Layout::parse(layout).unwrap();
+        layout.calculate(Some(1024)).unwrap();
+        layout.stack[0].origin = 0x2000_1200;
+        assert_eq!(
+            layout.validate_placement().unwrap_err().to_string(),
+            "data (0x20001000..0x20001400) overlaps stack.core0 starting at 0x20001200"
+        );
+        layout.stack[0].origin = 0x2000_4800;
+        assert_eq!(
+            layout.validate_placement().unwrap_err().to_string(),
+            "stack.core0 (0x20004800..0x20005800) is outside of ram.main (0x20000000..0x20005000)"
+        );
+    }
+
+    #[test]
+    fn test_code_sections() {
+        let layout = r#"
+[flash]
+program = { origin = 0x08000000, size = "64K" }
+bank2 = { origin = 0x08010000, size = "64K" }
+[ram]
+main = { origin = 0x20000000, size = "20K" }
+[data]
+ram = "main"
+[code]
+ramfunc = { ram = "main", size = "1K" }
+bank2 = { flash = "bank2" }
+[stack]
+core0 = { ram = "main", size = "4K" }
+"#;
+        let mut layout = Layout::parse(layout).unwrap();
+        layout.calculate(Some(512)).unwrap();
+        assert_eq!(layout.code["ramfunc"].origin, 0x2000_0000 + 4 * 1024);
+        assert_eq!(layout.data.origin, 0x2000_0000 + 5 * 1024);
+        let layout = r#"
+[ram]
+main = { origin = 0x20000000, size = "20K" }
+[data]
+ram = "main"
+[code]
+ramfunc = { ram = "main" }
+"#;
+        let err = Layout::parse(layout).unwrap_err();
+        assert_eq!(
+            format!("{err:#}"),
+            "layout config validation error: code.ramfunc.size must be set for a section executed \
+             from RAM"
+        );
+    }
+
+    #[test]
+    fn test_reserved_sections() {
+        let layout = r#"
+[ram]
+main = { origin = 0x20000000, size = "20K" }
+[data]
+ram = "main"
+[stack]
+core0 = { ram = "main", size = "50%" }
+[heap.main]
+ram = "main"
+size = "50%"
+pools = []
+[reserved]
+crashlog = { ram = "main", origin = "ram.main.end - 256", size = "256", noinit = true }
+"#;
+        let mut layout = Layout::parse(layout).unwrap();
+        layout.calculate(Some(0)).unwrap();
+        assert_eq!(layout.reserved["crashlog"].origin, 0x2000_4F00);
+        assert_eq!(layout.stack["core0"].origin, 0x2000_0000);
+        assert_eq!(layout.data.origin, 0x2000_4F00);
+        let layout = r#"
+[ram]
+main = { origin = 0x20000000, size = "20K" }
+[data]
+ram = "main"
+[reserved]
+mailbox = { ram = "main", origin = 0x20006000, size = "64" }
+"#;
+        let err = Layout::parse(layout).unwrap_err();
+        assert_eq!(
+            format!("{err:#}"),
+            "layout config calculation error: reserved.mailbox (0x20006000..0x20006040) is \
+             outside of ram.main (0x20000000..0x20005000)"
+        );
+        let layout = r#"
+[ram]
+main = { origin = 0x20000000, size = "20K" }
+[data]
+ram = "main"
+[reserved]
+boot = { ram = "main", origin = 0x20000000, size = "256" }
+mailbox = { ram = "main", origin = 0x20000100, size = "64" }
+"#;
+        let layout = Layout::parse(layout).unwrap();
+        assert_eq!(layout.data.origin, 0x2000_0140);
+        let layout = r#"
+[ram]
+main = { origin = 0x20000000, size = "20K" }
+[data]
+ram = "main"
+[reserved]
+mailbox = { ram = "main", origin = 0x20002000, size = "64" }
+"#;
+        let err = Layout::parse(layout).unwrap_err();
+        assert_eq!(
+            format!("{err:#}"),
+            "layout config calculation error: reserved.mailbox (0x20002000..0x20002040) leaves \
+             free space on both sides in ram.main; reserved sections must be adjacent to the \
+             beginning or the end of the region"
+        );
+    }
+
+    #[test]
+    fn test_crashlog() {
+        let layout = r#"
+[ram]
+main = { origin = 0x20000000, size = "20K" }
+[data]
+ram = "main"
+[stack]
+core0 = { ram = "main", size = "100%" }
+[crashlog]
+ram = "main"
+size = "1K"
+"#;
+        let mut layout = Layout::parse(layout).unwrap();
+        layout.calculate(Some(1024)).unwrap();
+        assert_eq!(layout.crashlog.as_ref().unwrap().origin, 0x2000_4C00);
+        assert_eq!(layout.data.origin, 0x2000_4800);
+        let layout = r#"
+[ram]
+main = { origin = 0x20000000, size = "20K" }
+[data]
+ram = "main"
+[crashlog]
+ram = "main"
+size = "64"
+"#;
+        let err = Layout::parse(layout).unwrap_err();
+        assert_eq!(
+            format!("{err:#}"),
+            "layout config validation error: crashlog.size must be at least 128 bytes to hold the \
+             crash record header (64)"
+        );
+    }
+
+    #[test]
+    fn test_images() {
+        let layout = r#"
+[flash]
+boot = { origin = 0x08000000, size = "16K" }
+slot-a = { origin = 0x08004000, size = "56K" }
+slot-b = { origin = 0x08012000, size = "56K" }
+[ram]
+main = { origin = 0x20000000, size = "20K" }
+[data]
+ram = "main"
+[image]
+bootloader = { flash = "boot" }
+app-a = { flash = "slot-a", binary = "app" }
+app-b = { flash = "slot-b", binary = "app" }
+"#;
+        let mut layout = Layout::parse(layout).unwrap();
+        assert_eq!(layout.program_flash(), "program");
+        layout.select_image(Some("app-b".into())).unwrap();
+        assert_eq!(layout.program_flash(), "slot-b");
+        assert!(layout.select_image(Some("app-c".into())).is_err());
+        let layout = r#"
+[flash]
+boot = { origin = 0x08000000, size = "16K" }
+[ram]
+main = { origin = 0x20000000, size = "20K" }
+[data]
+ram = "main"
+[image]
+bootloader = { flash = "boot" }
+app = { flash = "boot" }
+"#;
+        let err = Layout::parse(layout).unwrap_err();
+        assert_eq!(
+            format!("{err:#}"),
+            "layout config validation error: image.app.flash points to flash.boot already used by \
+             image.bootloader"
+        );
+    }
+
+    #[test]
+    fn test_header() {
+        let layout = r#"
+[ram]
+main = { origin = 0x20000000, size = "20K" }
+[data]
+ram = "main"
+[header]
+checksum = "sha256"
+"#;
+        let layout = Layout::parse(layout).unwrap();
+        assert_eq!(layout.header.as_ref().unwrap().checksum, Some(Checksum::Sha256));
+        assert_eq!(layout.header_size(), Some(256));
+        assert_eq!(layout.header_offset(), Some(1024));
+        let layout = r#"
+[ram]
+main = { origin = 0x20000000, size = "20K" }
+[data]
+ram = "main"
+[header]
+size = "32"
+"#;
+        let err = Layout::parse(layout).unwrap_err();
+        assert_eq!(
+            format!("{err:#}"),
+            "layout config validation error: header.size must be at least 64 bytes (32)"
+        );
+    }
+
+    #[test]
+    fn test_data_sections() {
+        let layout = r#"
+[ram]
+main = { origin = 0x20000000, size = "20K" }
+ccm = { origin = 0x10000000, size = "8K" }
+[data]
+ram = "main"
+[data.sections.ccm]
+ram = "ccm"
+[stack]
+core0 = { ram = "main", size = "4K" }
+"#;
+        let mut layout = Layout::parse(layout).unwrap();
+        assert_eq!(layout.data.sections["ccm"].origin, 0x1000_0000);
+        assert_eq!(layout.data.sections["ccm"].size, 8 * 1024);
+        let data_sizes = [("ccm".to_string(), 1024)].into_iter().collect();
+        layout.calculate_with_data_sizes(Some(512), &data_sizes).unwrap();
+        assert_eq!(layout.data.origin, 0x2000_0000 + 4 * 1024);
+        assert_eq!(layout.data.size, 512);
+        assert_eq!(layout.data.sections["ccm"].origin, 0x1000_0000 + 7 * 1024);
+        assert_eq!(layout.data.sections["ccm"].size, 1024);
+        let layout = r#"
+[ram]
+main = { origin = 0x20000000, size = "20K" }
+[data]
+ram = "main"
+rma = "main"
+"#;
+        let err = Layout::parse(layout).unwrap_err();
+        assert!(format!("{err:#}").contains("unknown field `rma`"), "{err:#}");
+        let layout = r#"
+[ram]
+main = { origin = 0x20000000, size = "20K" }
+ccm = { origin = 0x10000000, size = "8K", no-dma = true }
+[data]
+ram = "main"
+[data.sections.ccm]
+ram = "ccm"
+dma = true
+"#;
+        let err = Layout::parse(layout).unwrap_err();
+        assert_eq!(
+            format!("{err:#}"),
+            "layout config validation error: data.sections.ccm holds DMA buffers, but ram.ccm is \
+             inaccessible for DMA"
+        );
+    }
+
+    #[test]
+    fn test_section_alignment() {
+        let layout = r#"
+[ram]
+main = { origin = 0x20000004, size = "20K" }
+[data]
+ram = "main"
+[stack]
+core0 = { ram = "main", size = "4K", align = "8" }
+[stream]
+ram = "main"
+core0 = { ram = "main", size = "260", init-primary = true, align = "32" }
+[heap]
+core0 = { ram = "main", size = "100%", align = "1K", pools = [{ block = "4", count = "100%" }] }
+"#;
+        let mut layout = Layout::parse(layout).unwrap();
+        layout.calculate(Some(400)).unwrap();
+        assert_eq!(layout.stack["core0"].origin % 8, 0);
+        assert_eq!(layout.stream.as_ref().unwrap().sections["core0"].origin % 32, 0);
+        assert_eq!(layout.heap["core0"].section.origin % 1024, 0);
+        assert_eq!(layout.heap["core0"].section.fixed_size % 1024, 0);
+        layout.validate_placement().unwrap();
+        let layout = r#"
+[ram]
+main = { origin = 0x20000000, size = "20K" }
+[data]
+ram = "main"
+[stack]
+core0 = { ram = "main", size = "4K", align = "12" }
+"#;
+        let err = Layout::parse(layout).unwrap_err();
+        assert_eq!(
+            format!("{err:#}"),
+            "layout config validation error: stack.core0.align must be a power of two not less \
+             than 4 (12)"
+        );
+    }
+
+    #[test]
+    fn test_distribution() {
+        let mut distribution = Distribution::new(100, [1, 1, 1].into_iter());
+        assert_eq!(distribution.next(1, 4), 32);
+        assert_eq!(distribution.next(1, 4), 32);
+        assert_eq!(distribution.next(1, 4), 36);
+        assert_eq!(distribution.allocated(), 100);
+        let mut distribution = Distribution::new(u64::MAX - 3, [333_333, 666_667].into_iter());
+        assert_eq!(distribution.next(333_333, 4) + distribution.next(666_667, 4), u64::MAX - 3);
+        assert_eq!("33.3333%".parse::<size::Flexible>().unwrap().flexible(), Some(333_333));
+        assert_eq!(size::Flexible::Flexible(125_000).to_string(), "12.5%");
+        assert!("33.33333%".parse::<size::Flexible>().is_err());
+    }
+
+    fn align() -> impl Strategy<Value = Option<u64>> {
+        prop_oneof![3 => Just(None), 1 => (2_u32..8).prop_map(|shift| Some(1 << shift))]
+    }
+
+    fn section() -> impl Strategy<Value = (String, Option<u64>)> {
+        align().prop_flat_map(|align| {
+            let unit = align.unwrap_or(ALIGN);
+            let size = prop_oneof![
+                (1_u64..64).prop_map(move |units| (units * unit).to_string()),
+                (1_u32..10_000).prop_map(|w| format!("{}.{:02}%", w / 100, w % 100)),
+            ];
+            (size, Just(align))
+        })
+    }
+
+    prop_compose! {
+        fn arbitrary_layout()(
+            ram_words in 16_384_u64..65_536,
+            stacks in prop::collection::vec(section(), 0..4),
+            heaps in prop::collection::vec(
+                (section(), prop::collection::btree_map(2_u32..8, 1_u32..100, 1..4)),
+                0..3,
+            ),
+            streams in prop::collection::vec((MIN_BUFFER_SIZE / 4..128, align()), 0..3),
+        ) -> String {
+            let mut layout = format!(
+                "[ram]\nmain = {{ origin = 0x20000000, size = \"{}\" }}\n[data]\nram = \"main\"\n",
+                ram_words * 4
+            );
+            let align = |align: Option<u64>| {
+                align.map_or(String::new(), |align| format!(", align = \"{align}\""))
+            };
+            layout += "[stack]\n";
+            for (i, (size, a)) in stacks.into_iter().enumerate() {
+                let align = align(a);
+                writeln!(layout, "s{i} = {{ ram = \"main\", size = \"{size}\"{align} }}").unwrap();
+            }
+            layout += "[heap]\n";
+            for (i, ((size, a), pools)) in heaps.into_iter().enumerate() {
+                let pools = pools
+                    .into_iter()
+                    .map(|(shift, weight)| {
+                        format!("{{ block = \"{}\", count = \"{weight}%\" }}", 1 << shift)
+                    })
+                    .collect::<Vec<_>>();
+                writeln!(
+                    layout,
+                    "h{i} = {{ ram = \"main\", size = \"{size}\"{}, pools = [{}] }}",
+                    align(a),
+                    pools.join(", ")
+                )
+                .unwrap();
+            }
+            if !streams.is_empty() {
+                layout += "[stream]\nram = \"main\"\n";
+                for (i, (words, a)) in streams.into_iter().enumerate() {
+                    writeln!(
+                        layout,
+                        "t{i} = {{ ram = \"main\", size = \"{}\", init-primary = {}{} }}",
+                        words * 4,
+                        i == 0,
+                        align(a)
+                    )
+                    .unwrap();
+                }
+            }
+            layout
+        }
+    }
+
+    proptest! {
+        #[test]
+        fn test_calculate_properties(layout in arbitrary_layout(), data_words in 0_u64..1024) {
+            let mut layout = Layout::parse(&layout).unwrap();
+            layout.calculate(Some(data_words * 4)).unwrap();
+            let ram = &layout.ram["main"];
+            let mut blocks = vec![(layout.data.origin, layout.data.size, layout.data.align)];
+            let mut align_slack = 0;
+            let mut has_flexible = false;
+            for section in layout.stack.values().chain(layout.heap.values().map(|h| &h.section)) {
+                let size = section.prefix_size + section.fixed_size;
+                blocks.push((section.origin, size, section.align));
+                align_slack += section.align.map_or(0, |align| align - 1);
+                has_flexible |= section.size.is_flexible();
+                prop_assert_eq!(section.fixed_size % section.align.unwrap_or(ALIGN), 0);
+            }
+            if let Some(stream) = &layout.stream {
+                blocks.push((stream.origin, stream.fixed_size, None));
+                for section in stream.sections.values() {
+                    let size = section.prefix_size + section.size;
+                    blocks.push((section.origin, size, section.align));
+                    align_slack += section.align.map_or(0, |align| align - 1);
+                }
+            }
+            blocks.sort_unstable();
+            let mut pointer = ram.origin;
+            for &(origin, size, align) in &blocks {
+                prop_assert!(origin >= pointer, "overlapping section at {origin:#x}");
+                prop_assert_eq!(origin % align.unwrap_or(ALIGN), 0);
+                pointer = origin + size;
+            }
+            prop_assert!(pointer <= ram.origin + ram.size);
+            let uncovered = ram.size - blocks.iter().map(|&(_, size, _)| size).sum::<u64>();
+            if has_flexible {
+                let max_align = blocks.iter().filter_map(|&(_, _, align)| align).max().unwrap_or(0);
+                prop_assert!(uncovered <= align_slack + max_align, "{uncovered} bytes are lost");
+                if align_slack == 0 {
+                    prop_assert_eq!(uncovered, 0);
+                }
+            }
+            for heap in layout.heap.values() {
+                let used = heap.pools.iter().map(|p| p.block * p.fixed_count).sum::<u64>();
+                let min_block = heap.pools.iter().map(|p| p.block).min().unwrap();
+                prop_assert!(used <= heap.section.fixed_size);
+                prop_assert!(heap.section.fixed_size - used < min_block);
+            }
+        }
+    }
 }
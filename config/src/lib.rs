@@ -5,10 +5,17 @@
 #![allow(clippy::missing_errors_doc, clippy::module_name_repetitions, clippy::must_use_candidate)]
 
 pub mod addr;
+pub mod consts;
+pub mod diagnostic;
+pub mod expr;
 pub mod layout;
+pub mod map;
+pub mod mpu;
 pub mod size;
 
-pub use crate::layout::{Layout, LAYOUT_CONFIG};
+pub use crate::layout::{
+    Layout, LAYOUT_CONFIG, LAYOUT_CONFIG_ENV, LAYOUT_IMAGE_ENV, LAYOUT_PROFILE_ENV, LAYOUT_SCHEMA,
+};
 pub use eyre::{bail, eyre, Result, WrapErr};
 use std::env;
 use std::env::VarError;
@@ -20,19 +27,25 @@ use std::os::unix::prelude::*;
 use std::path::{Path, PathBuf};
 use std::process::Command;
 
-/// Memory size of one heap pool metadata.
-pub const HEAP_POOL_SIZE: u32 = 16;
+/// Memory size of the crash record header preceding the stack dump.
+pub const CRASHLOG_HEADER_SIZE: u64 = 128;
 
-/// Memory size of the heap metadata without pools.
-pub const HEAP_PREFIX_SIZE: u32 = 4;
+/// Memory size of the firmware image header fields.
+pub const IMAGE_HEADER_SIZE: u64 = 64;
+
+/// Memory size of one heap pool metadata on 32-bit targets. It consists of
+/// pointer-sized words, so it doubles on 64-bit targets.
+pub const HEAP_POOL_SIZE: u64 = 16;
+
+/// Memory size of the heap metadata without pools on 32-bit targets. It
+/// consists of pointer-sized words, so it doubles on 64-bit targets.
+pub const HEAP_PREFIX_SIZE: u64 = 4;
 
 /// Memory size of Drone Stream global runtime.
-#[allow(clippy::cast_possible_truncation)]
-pub const STREAM_GLOBAL_RUNTIME_SIZE: u32 = size_of::<drone_stream::GlobalRuntime>() as u32;
+pub const STREAM_GLOBAL_RUNTIME_SIZE: u64 = size_of::<drone_stream::GlobalRuntime>() as u64;
 
 /// Memory size of Drone Stream runtime.
-#[allow(clippy::cast_possible_truncation)]
-pub const STREAM_RUNTIME_SIZE: u32 = size_of::<drone_stream::Runtime>() as u32;
+pub const STREAM_RUNTIME_SIZE: u64 = size_of::<drone_stream::Runtime>() as u64;
 
 /// Locates cargo project root starting from the current directory.
 pub fn locate_project_root() -> Result<PathBuf> {
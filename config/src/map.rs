@@ -0,0 +1,432 @@
+//! Resolved memory map.
+
+use crate::Layout;
+use serde::{Deserialize, Serialize};
+
+/// Resolved memory map of a calculated layout.
+#[derive(Clone, Debug, Serialize)]
+pub struct MemoryMap {
+    /// Memory regions in the order of their declaration.
+    pub regions: Vec<Region>,
+}
+
+/// Memory region with placed sections.
+#[derive(Clone, Debug, Serialize)]
+pub struct Region {
+    /// Memory type of the region.
+    pub kind: RegionKind,
+    /// Region name.
+    pub name: String,
+    /// Beginning of the region.
+    pub origin: u64,
+    /// Length of the region.
+    pub size: u64,
+    /// Sections and free gaps sorted by their origins.
+    pub sections: Vec<Placement>,
+}
+
+/// Memory type of a region.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum RegionKind {
+    /// Flash memory.
+    Flash,
+    /// RAM memory.
+    Ram,
+}
+
+/// Section placed inside a memory region.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Placement {
+    /// Section name.
+    pub name: String,
+    /// Section type.
+    pub kind: SectionKind,
+    /// Beginning of the section.
+    pub origin: u64,
+    /// Length of the section.
+    pub size: u64,
+}
+
+/// Change of a memory region between two memory maps.
+#[derive(Clone, Debug, Serialize)]
+pub struct RegionDiff {
+    /// Memory type of the region.
+    pub kind: RegionKind,
+    /// Region name.
+    pub name: String,
+    /// Span of the region in the old map, if present.
+    pub old: Option<Span>,
+    /// Span of the region in the new map, if present.
+    pub new: Option<Span>,
+    /// Sections added, removed, resized, or moved, sorted by their origins.
+    pub sections: Vec<SectionDiff>,
+    /// Number of the sections placed the same in both maps.
+    pub unchanged: usize,
+}
+
+/// Change of a section between two memory maps.
+#[derive(Clone, Debug, Serialize)]
+pub struct SectionDiff {
+    /// Section name.
+    pub name: String,
+    /// Section type.
+    pub kind: SectionKind,
+    /// Span of the section in the old map, if present.
+    pub old: Option<Span>,
+    /// Span of the section in the new map, if present.
+    pub new: Option<Span>,
+    /// For a section which kept its size, the type and name of the nearest
+    /// added, removed, or resized neighbour it was moved by, e.g.
+    /// `stack core0`, or the region path if the region itself was changed.
+    pub moved_by: Option<String>,
+}
+
+/// Origin and size of a region or section.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+pub struct Span {
+    /// Beginning of the span.
+    pub origin: u64,
+    /// Length of the span.
+    pub size: u64,
+}
+
+/// Type of a placed section.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum SectionKind {
+    /// Combined BSS and DATA section.
+    Data,
+    /// Code section executed from RAM.
+    Code,
+    /// Stack section.
+    Stack,
+    /// Drone Stream global runtime.
+    StreamRuntime,
+    /// Drone Stream buffer along with its runtime.
+    Stream,
+    /// Heap section along with its metadata.
+    Heap,
+    /// Section pinned at a fixed address.
+    Reserved,
+    /// Program code and read-only data in flash memory.
+    Program,
+    /// Unused memory.
+    Free,
+}
+
+impl MemoryMap {
+    /// Builds a memory map from the calculated `layout`.
+    pub fn new(layout: &Layout) -> Self {
+        let mut regions = Vec::new();
+        for (name, flash) in &layout.flash {
+            regions.push(Region {
+                kind: RegionKind::Flash,
+                name: name.clone(),
+                origin: flash.origin,
+                size: flash.size,
+                sections: Vec::new(),
+            });
+        }
+        for (key, ram) in &layout.ram {
+            let mut sections = Vec::new();
+            let mut place = |name: &str, kind, origin, size| {
+                sections.push(Placement { name: name.to_string(), kind, origin, size });
+            };
+            if &layout.data.ram == key {
+                place("data", SectionKind::Data, layout.data.origin, layout.data.size);
+            }
+            for (name, data) in layout.data.sections.iter().filter(|(_, d)| &d.ram == key) {
+                place(name, SectionKind::Data, data.origin, data.size);
+            }
+            for (name, code) in layout.code.iter().filter(|(_, c)| c.ram.as_ref() == Some(key)) {
+                place(name, SectionKind::Code, code.origin, code.size.unwrap_or(0));
+            }
+            for (name, stack) in layout.stack.iter().filter(|(_, s)| &s.ram == key) {
+                place(name, SectionKind::Stack, stack.origin, stack.prefix_size + stack.fixed_size);
+            }
+            if let Some(stream) = &layout.stream {
+                if &stream.ram == key {
+                    place("stream", SectionKind::StreamRuntime, stream.origin, stream.fixed_size);
+                }
+                for (name, section) in stream.sections.iter().filter(|(_, s)| &s.ram == key) {
+                    let size = section.prefix_size + section.size;
+                    place(name, SectionKind::Stream, section.origin, size);
+                }
+            }
+            for (name, heap) in layout.heap.iter().filter(|(_, h)| &h.section.ram == key) {
+                let size = heap.section.prefix_size + heap.section.fixed_size;
+                place(name, SectionKind::Heap, heap.section.origin, size);
+            }
+            for (name, reserved) in layout.reserved.iter().filter(|(_, r)| &r.ram == key) {
+                place(name, SectionKind::Reserved, reserved.origin, reserved.size);
+            }
+            if let Some(crashlog) = layout.crashlog.as_ref().filter(|c| &c.ram == key) {
+                place("crashlog", SectionKind::Reserved, crashlog.origin, crashlog.size);
+            }
+            sections.sort_by_key(|section| section.origin);
+            regions.push(Region {
+                kind: RegionKind::Ram,
+                name: key.clone(),
+                origin: ram.origin,
+                size: ram.size,
+                sections: fill_gaps(ram.origin, ram.size, sections),
+            });
+        }
+        Self { regions }
+    }
+
+    /// Places the `sections` of a linked image into the flash regions
+    /// containing them. The contents of flash memory are defined by the
+    /// linker rather than the layout, so [`MemoryMap::new`] leaves flash
+    /// regions empty.
+    pub fn place_flash(&mut self, sections: &[Placement]) {
+        for region in self.regions.iter_mut().filter(|r| r.kind == RegionKind::Flash) {
+            let range = region.origin..region.origin + region.size;
+            let mut placed = sections
+                .iter()
+                .filter(|section| range.contains(&section.origin))
+                .cloned()
+                .collect::<Vec<_>>();
+            if placed.is_empty() {
+                continue;
+            }
+            placed.sort_by_key(|section| section.origin);
+            region.sections = fill_gaps(region.origin, region.size, placed);
+        }
+    }
+}
+
+impl RegionDiff {
+    /// Returns the configuration path of the region, e.g. `ram.main`.
+    pub fn path(&self) -> String {
+        format!("{}.{}", self.kind.as_str(), self.name)
+    }
+}
+
+/// Returns the changes between the `old` and `new` memory maps. Regions
+/// without changes are omitted.
+pub fn diff(old: &MemoryMap, new: &MemoryMap) -> Vec<RegionDiff> {
+    let mut regions = Vec::new();
+    let mut seen = Vec::new();
+    for (kind, name) in new.regions.iter().chain(&old.regions).map(|r| (r.kind, &r.name)) {
+        if seen.contains(&(kind, name)) {
+            continue;
+        }
+        seen.push((kind, name));
+        let find = |map: &MemoryMap| {
+            map.regions.iter().find(|r| r.kind == kind && &r.name == name).cloned()
+        };
+        let (old, new) = (find(old), find(new));
+        let span = |region: &Region| Span { origin: region.origin, size: region.size };
+        let mut region = RegionDiff {
+            kind,
+            name: name.clone(),
+            old: old.as_ref().map(span),
+            new: new.as_ref().map(span),
+            sections: Vec::new(),
+            unchanged: 0,
+        };
+        let sections = |region: Option<Region>| {
+            region.map_or_else(Vec::new, |region| {
+                region.sections.into_iter().filter(|s| s.kind != SectionKind::Free).collect()
+            })
+        };
+        diff_sections(&mut region, &sections(old), &sections(new));
+        if region.old != region.new || !region.sections.is_empty() {
+            regions.push(region);
+        }
+    }
+    regions
+}
+
+fn diff_sections(region: &mut RegionDiff, old: &[Placement], new: &[Placement]) {
+    let span = |section: &Placement| Span { origin: section.origin, size: section.size };
+    let mut seen = Vec::new();
+    for section in new.iter().chain(old) {
+        if seen.contains(&(section.kind, &section.name)) {
+            continue;
+        }
+        seen.push((section.kind, &section.name));
+        let same = |s: &&Placement| s.kind == section.kind && s.name == section.name;
+        let (old, new) = (old.iter().find(same).map(span), new.iter().find(same).map(span));
+        if old == new {
+            region.unchanged += 1;
+            continue;
+        }
+        region.sections.push(SectionDiff {
+            name: section.name.clone(),
+            kind: section.kind,
+            old,
+            new,
+            moved_by: None,
+        });
+    }
+    let changed = region
+        .sections
+        .iter()
+        .filter(|s| s.old.map(|span| span.size) != s.new.map(|span| span.size))
+        .filter_map(|s| {
+            let origin = s.new.or(s.old)?.origin;
+            Some((origin, format!("{} {}", s.kind.as_str(), s.name)))
+        })
+        .collect::<Vec<_>>();
+    let region_changed = region.old != region.new;
+    let path = region.path();
+    for section in &mut region.sections {
+        let (Some(old), Some(new)) = (section.old, section.new) else { continue };
+        if old.size != new.size {
+            continue;
+        }
+        let neighbour = if new.origin > old.origin {
+            changed.iter().filter(|(origin, _)| *origin < new.origin).max()
+        } else {
+            changed.iter().filter(|(origin, _)| *origin > new.origin).min()
+        };
+        section.moved_by = neighbour
+            .map(|(_, name)| name.clone())
+            .or_else(|| region_changed.then(|| path.clone()));
+    }
+    region.sections.sort_by_key(|s| s.new.or(s.old).map(|span| span.origin));
+}
+
+impl RegionKind {
+    /// Returns the configuration key of the memory type.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Flash => "flash",
+            Self::Ram => "ram",
+        }
+    }
+}
+
+impl SectionKind {
+    /// Returns a human-readable name of the section type.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Data => "data",
+            Self::Code => "code",
+            Self::Stack => "stack",
+            Self::StreamRuntime => "stream-runtime",
+            Self::Stream => "stream",
+            Self::Heap => "heap",
+            Self::Reserved => "reserved",
+            Self::Program => "program",
+            Self::Free => "free",
+        }
+    }
+}
+
+impl Region {
+    /// Returns the total size of the non-free sections.
+    pub fn used(&self) -> u64 {
+        self.sections.iter().filter(|s| s.kind != SectionKind::Free).map(|s| s.size).sum()
+    }
+}
+
+impl Placement {
+    /// Returns the end address of the section.
+    pub fn end(&self) -> u64 {
+        self.origin + self.size
+    }
+}
+
+fn fill_gaps(origin: u64, size: u64, sections: Vec<Placement>) -> Vec<Placement> {
+    let mut filled = Vec::with_capacity(sections.len());
+    let mut pointer = origin;
+    for section in sections {
+        if section.size == 0 {
+            filled.push(section);
+            continue;
+        }
+        if section.origin > pointer {
+            filled.push(free(pointer, section.origin - pointer));
+        }
+        pointer = pointer.max(section.end());
+        filled.push(section);
+    }
+    if origin + size > pointer {
+        filled.push(free(pointer, origin + size - pointer));
+    }
+    filled
+}
+
+fn free(origin: u64, size: u64) -> Placement {
+    Placement { name: String::new(), kind: SectionKind::Free, origin, size }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_diff() {
+        let layout = |stack: &str| {
+            let layout = format!(
+                r#"
+[ram]
+main = {{ origin = 0x20000000, size = "20K" }}
+[data]
+ram = "main"
+[stack]
+core0 = {{ ram = "main", size = "{stack}" }}
+[stream]
+ram = "main"
+core0 = {{ ram = "main", size = "1K", init-primary = true }}
+"#
+            );
+            let mut layout = Layout::parse(&layout).unwrap();
+            layout.calculate(Some(1024)).unwrap();
+            MemoryMap::new(&layout)
+        };
+        let regions = diff(&layout("4K"), &layout("5K"));
+        assert_eq!(regions.len(), 1);
+        assert_eq!(regions[0].path(), "ram.main");
+        assert_eq!(regions[0].unchanged, 0);
+        let sections = &regions[0].sections;
+        let names = sections.iter().map(|s| (s.kind, s.moved_by.as_deref())).collect::<Vec<_>>();
+        assert_eq!(names, [
+            (SectionKind::Stack, None),
+            (SectionKind::StreamRuntime, Some("stack core0")),
+            (SectionKind::Stream, Some("stack core0")),
+            (SectionKind::Data, Some("stack core0")),
+        ]);
+        assert_eq!(sections[0].new, Some(Span { origin: 0x2000_0000, size: 5 * 1024 }));
+        assert!(diff(&layout("4K"), &layout("4K")).is_empty());
+    }
+
+    #[test]
+    fn test_memory_map() {
+        let layout = r#"
+[flash]
+program = { origin = 0x08000000, size = "64K" }
+[ram]
+main = { origin = 0x20000000, size = "20K" }
+[data]
+ram = "main"
+[stack]
+core0 = { ram = "main", size = "4K" }
+"#;
+        let mut layout = Layout::parse(layout).unwrap();
+        layout.calculate(Some(1024)).unwrap();
+        let mut map = MemoryMap::new(&layout);
+        assert_eq!(map.regions.len(), 2);
+        assert_eq!(map.regions[0].kind, RegionKind::Flash);
+        let ram = &map.regions[1];
+        let kinds = ram.sections.iter().map(|s| s.kind).collect::<Vec<_>>();
+        assert_eq!(kinds, [SectionKind::Stack, SectionKind::Data, SectionKind::Free]);
+        assert_eq!(ram.sections[2].origin, 0x2000_0000 + 5 * 1024);
+        assert_eq!(ram.sections[2].size, 15 * 1024);
+        assert_eq!(ram.used(), 5 * 1024);
+        let text = Placement {
+            name: ".text".into(),
+            kind: SectionKind::Program,
+            origin: 0x0800_0000,
+            size: 1024,
+        };
+        map.place_flash(&[text]);
+        let kinds = map.regions[0].sections.iter().map(|s| s.kind).collect::<Vec<_>>();
+        assert_eq!(kinds, [SectionKind::Program, SectionKind::Free]);
+        assert_eq!(map.regions[0].used(), 1024);
+    }
+}
@@ -0,0 +1,307 @@
+//! MPU configuration.
+//!
+//! Generates Cortex-M MPU region register values from a calculated layout.
+//! Regions with higher numbers take priority, so stack guards are placed after
+//! the memory regions they override.
+
+use crate::layout::{Layout, Memory};
+use crate::{addr, size};
+use eyre::{bail, Result};
+use serde::{Deserialize, Serialize};
+
+/// Minimal size of an MPU region.
+pub const MIN_REGION_SIZE: u32 = 32;
+
+/// ARMv8-M MAIR0 value: attribute 0 is normal write-back cacheable memory,
+/// attribute 1 is normal non-cacheable memory.
+pub const ARMV8M_MAIR0: u32 = 0x44FF;
+
+/// MPU configuration.
+#[non_exhaustive]
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct Mpu {
+    /// MPU architecture.
+    pub arch: Arch,
+    /// Number of available MPU regions. Defaults to 8.
+    pub regions: Option<u32>,
+    /// Size of the no-access guard region reserved below each stack. The guard
+    /// is allocated in addition to the stack size, so the stack keeps its
+    /// configured size. Not supported by ARMv8-M.
+    #[serde(default, with = "size::opt")]
+    pub stack_guard: Option<u64>,
+    /// Whether to generate regions for all flash and RAM memory regions.
+    /// Otherwise the default memory map is used as the background region.
+    pub background: Option<bool>,
+}
+
+/// MPU architecture.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Arch {
+    /// ARMv7-M `PMSAv7` MPU.
+    Armv7m,
+    /// ARMv8-M `PMSAv8` MPU.
+    Armv8m,
+}
+
+/// MPU region.
+#[derive(Clone, Debug)]
+pub struct Region {
+    /// Layout path of the region origin, e.g. `ram.main` or `stack.core0`.
+    pub path: String,
+    /// Base address.
+    pub base: u32,
+    /// Region size.
+    pub size: u32,
+    /// Access permissions.
+    pub access: Access,
+    /// Whether instruction fetches are allowed.
+    pub executable: bool,
+    /// Whether the region is cacheable.
+    pub cacheable: bool,
+}
+
+/// Access permissions of an MPU region.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Access {
+    /// No access. Not supported by ARMv8-M, which has no such permission.
+    None,
+    /// Read-only access.
+    ReadOnly,
+    /// Read-write access.
+    ReadWrite,
+}
+
+impl Mpu {
+    /// Returns MPU regions for the calculated `layout`.
+    pub fn regions(&self, layout: &Layout) -> Result<Vec<Region>> {
+        if layout.address_width() != 32 {
+            bail!("MPU is supported only with a 32-bit address width");
+        }
+        let mut regions = Vec::new();
+        if self.background.unwrap_or(false) {
+            for (key, flash) in &layout.flash {
+                regions.push(memory_region(format!("flash.{key}"), flash, Access::ReadOnly, true)?);
+            }
+            for (key, ram) in &layout.ram {
+                regions.push(memory_region(format!("ram.{key}"), ram, Access::ReadWrite, false)?);
+            }
+        }
+        for (key, code) in &layout.code {
+            if let Some(size) = code.size.filter(|_| code.ram.is_some()) {
+                let path = format!("code.{key}");
+                let (base, size) = narrow(&path, code.origin, size)?;
+                regions.push(Region {
+                    path,
+                    base,
+                    size,
+                    access: Access::ReadOnly,
+                    executable: true,
+                    cacheable: true,
+                });
+            }
+        }
+        if let Some(guard) = self.stack_guard {
+            for (key, stack) in &layout.stack {
+                let path = format!("stack.{key}");
+                let (base, size) = narrow(&path, stack.origin, guard)?;
+                regions.push(Region {
+                    path,
+                    base,
+                    size,
+                    access: Access::None,
+                    executable: false,
+                    cacheable: true,
+                });
+            }
+        }
+        let limit = self.regions.unwrap_or(8);
+        if regions.len() > limit as usize {
+            bail!("{} MPU regions are required, but only {limit} are available", regions.len());
+        }
+        for region in &regions {
+            self.validate_region(region)?;
+        }
+        Ok(regions)
+    }
+
+    /// Returns pairs of MPU register values for each region. For ARMv7-M it
+    /// is `(MPU_RBAR, MPU_RASR)`, and for ARMv8-M it is `(MPU_RBAR, MPU_RLAR)`
+    /// with attribute indexes into [`ARMV8M_MAIR0`].
+    ///
+    /// # Panics
+    ///
+    /// If an ARMv8-M region has [`Access::None`], which [`Mpu::regions`]
+    /// never returns.
+    pub fn registers(&self, regions: &[Region]) -> Vec<(u32, u32)> {
+        regions
+            .iter()
+            .zip(0..)
+            .map(|(region, number)| match self.arch {
+                Arch::Armv7m => armv7m_registers(region, number),
+                Arch::Armv8m => armv8m_registers(region),
+            })
+            .collect()
+    }
+
+    fn validate_region(&self, region: &Region) -> Result<()> {
+        let Region { path, base, size, .. } = region;
+        if *size < MIN_REGION_SIZE {
+            bail!("{path} MPU region size {size} is less than {MIN_REGION_SIZE}");
+        }
+        match self.arch {
+            Arch::Armv7m => {
+                if !size.is_power_of_two() {
+                    bail!(
+                        "{path} MPU region size {} is not a power of two",
+                        size::to_string((*size).into())
+                    );
+                }
+                if base % size != 0 {
+                    bail!(
+                        "{path} MPU region base {} is not aligned to its size {}; consider \
+                         setting {path}.align",
+                        addr::to_string((*base).into()),
+                        size::to_string((*size).into())
+                    );
+                }
+            }
+            Arch::Armv8m => {
+                if region.access == Access::None {
+                    bail!(
+                        "{path} MPU region requires no access, which ARMv8-M MPU doesn't \
+                         support; use the stack limit registers instead of mpu.stack-guard"
+                    );
+                }
+                if base % MIN_REGION_SIZE != 0 || size % MIN_REGION_SIZE != 0 {
+                    bail!(
+                        "{path} MPU region {}..{} is not aligned to {MIN_REGION_SIZE} bytes",
+                        addr::to_string((*base).into()),
+                        addr::to_string(u64::from(*base) + u64::from(*size))
+                    );
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+fn memory_region(
+    path: String,
+    memory: &Memory,
+    access: Access,
+    executable: bool,
+) -> Result<Region> {
+    let (base, size) = narrow(&path, memory.origin, memory.size)?;
+    Ok(Region { path, base, size, access, executable, cacheable: memory.cacheable.unwrap_or(true) })
+}
+
+fn narrow(path: &str, base: u64, size: u64) -> Result<(u32, u32)> {
+    match (u32::try_from(base), u32::try_from(size)) {
+        (Ok(base), Ok(size)) => Ok((base, size)),
+        _ => bail!(
+            "{path} MPU region {} + {} doesn't fit into 32-bit registers",
+            addr::to_string(base),
+            size::to_string(size)
+        ),
+    }
+}
+
+fn armv7m_registers(region: &Region, number: u32) -> (u32, u32) {
+    const VALID: u32 = 1 << 4;
+    const XN: u32 = 1 << 28;
+    const NORMAL: u32 = 0b001 << 19;
+    const CACHEABLE: u32 = 0b11 << 16;
+    const ENABLE: u32 = 1;
+    let rbar = region.base | VALID | number;
+    let ap: u32 = match region.access {
+        Access::None => 0b000,
+        Access::ReadOnly => 0b110,
+        Access::ReadWrite => 0b011,
+    };
+    let mut rasr = ap << 24 | NORMAL | (region.size.trailing_zeros() - 1) << 1 | ENABLE;
+    if !region.executable {
+        rasr |= XN;
+    }
+    if region.cacheable {
+        rasr |= CACHEABLE;
+    }
+    (rbar, rasr)
+}
+
+fn armv8m_registers(region: &Region) -> (u32, u32) {
+    const XN: u32 = 1;
+    const ENABLE: u32 = 1;
+    let ap: u32 = match region.access {
+        Access::None => unreachable!("rejected by Mpu::validate_region"),
+        Access::ReadOnly => 0b11,
+        Access::ReadWrite => 0b01,
+    };
+    let mut rbar = region.base | ap << 1;
+    if !region.executable {
+        rbar |= XN;
+    }
+    let attr_index = u32::from(!region.cacheable);
+    let limit = (region.base + region.size - MIN_REGION_SIZE) | attr_index << 1 | ENABLE;
+    (rbar, limit)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_stack_guards() {
+        let layout = r#"
+[flash]
+program = { origin = 0x08000000, size = "64K" }
+[ram]
+main = { origin = 0x20000000, size = "20K" }
+[data]
+ram = "main"
+[stack]
+core0 = { ram = "main", size = "4K", align = "32" }
+[mpu]
+arch = "armv7m"
+stack-guard = "32"
+"#;
+        let mut layout = Layout::parse(layout).unwrap();
+        layout.calculate(Some(1024)).unwrap();
+        let mpu = layout.mpu.as_ref().unwrap();
+        let regions = mpu.regions(&layout).unwrap();
+        let stack = &layout.stack["core0"];
+        assert_eq!(stack.origin, 0x2000_0000);
+        assert_eq!(stack.prefix_size, 32);
+        assert_eq!(stack.fixed_size, 4 * 1024);
+        assert_eq!(layout.data.origin, 0x2000_1020);
+        assert_eq!(regions.len(), 1);
+        assert_eq!(regions[0].base, 0x2000_0000);
+        assert_eq!(regions[0].size, 32);
+        assert_eq!(mpu.registers(&regions), [(0x2000_0010, 0x100B_0009)]);
+    }
+
+    #[test]
+    fn test_armv8m_no_access() {
+        let layout = r#"
+[flash]
+program = { origin = 0x08000000, size = "64K" }
+[ram]
+main = { origin = 0x20000000, size = "20K" }
+[data]
+ram = "main"
+[stack]
+core0 = { ram = "main", size = "4K", align = "32" }
+[mpu]
+arch = "armv8m"
+stack-guard = "32"
+"#;
+        let err = Layout::parse(layout).unwrap_err();
+        assert_eq!(
+            format!("{err:#}"),
+            "layout config calculation error: MPU configuration error: stack.core0 MPU region \
+             requires no access, which ARMv8-M MPU doesn't support; use the stack limit \
+             registers instead of mpu.stack-guard"
+        );
+    }
+}
@@ -1,17 +1,24 @@
 //! Memory size values.
 
-use eyre::{bail, Error};
+use crate::expr;
+use eyre::{bail, eyre, Error};
 use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
-use std::num::ParseIntError;
 use std::str::FromStr;
 
+/// Denominator of flexible memory sizes: they are stored in millionths,
+/// i.e. percents with up to four fractional digits.
+pub const FLEXIBLE_SCALE: u32 = 1_000_000;
+
+const PERCENT_FRACTION_DIGITS: usize = 4;
+
 /// Possibly flexible memory size.
 #[derive(Clone, Debug)]
 pub enum Flexible {
     /// Fixed memory size.
-    Fixed(u32),
-    /// Flexible memory size.
-    Flexible(f32),
+    Fixed(u64),
+    /// Flexible memory size in millionths of the free space, see
+    /// [`FLEXIBLE_SCALE`].
+    Flexible(u32),
 }
 
 impl Serialize for Flexible {
@@ -30,12 +37,31 @@ impl FromStr for Flexible {
     type Err = Error;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        if s.ends_with('%') {
-            let value = s[0..s.len() - 1].parse::<f32>()?;
-            if !value.is_normal() || !value.is_sign_positive() {
-                bail!("invalid relative memory size: {value}");
+        if let Some(percent) = s.strip_suffix('%') {
+            let (integer, fraction) = percent.split_once('.').unwrap_or((percent, ""));
+            let is_digits = |s: &str| s.chars().all(|c| c.is_ascii_digit());
+            if integer.is_empty() || !is_digits(integer) || !is_digits(fraction) {
+                bail!("invalid relative memory size `{s}`: must be a positive number");
+            }
+            if fraction.len() > PERCENT_FRACTION_DIGITS {
+                bail!(
+                    "invalid relative memory size `{s}`: at most {PERCENT_FRACTION_DIGITS} \
+                     fractional digits are supported"
+                );
             }
-            Ok(Self::Flexible(value / 100.0))
+            let value = integer
+                .parse::<u32>()
+                .ok()
+                .and_then(|integer| integer.checked_mul(FLEXIBLE_SCALE / 100))
+                .and_then(|integer| {
+                    let fraction = format!("{fraction:0<PERCENT_FRACTION_DIGITS$}");
+                    integer.checked_add(fraction.parse().unwrap())
+                })
+                .ok_or_else(|| eyre!("invalid relative memory size `{s}`: too large"))?;
+            if value == 0 {
+                bail!("invalid relative memory size `{s}`: must be a positive number");
+            }
+            Ok(Self::Flexible(value))
         } else {
             Ok(Self::Fixed(from_str(s)?))
         }
@@ -46,7 +72,16 @@ impl ToString for Flexible {
     fn to_string(&self) -> String {
         match *self {
             Flexible::Fixed(value) => to_string(value),
-            Flexible::Flexible(value) => format!("{:.2}%", value * 100.0),
+            Flexible::Flexible(value) => {
+                let unit = FLEXIBLE_SCALE / 100;
+                let fraction = format!("{:04}", value % unit);
+                let fraction = fraction.trim_end_matches('0');
+                if fraction.is_empty() {
+                    format!("{}%", value / unit)
+                } else {
+                    format!("{}.{fraction}%", value / unit)
+                }
+            }
         }
     }
 }
@@ -69,7 +104,7 @@ impl Flexible {
     }
 
     /// Returns `Some(fixed)` if the size is fixed, and `None` otherwise.
-    pub fn fixed(&self) -> Option<u32> {
+    pub fn fixed(&self) -> Option<u64> {
         match *self {
             Flexible::Fixed(fixed) => Some(fixed),
             Flexible::Flexible(_) => None,
@@ -77,7 +112,7 @@ impl Flexible {
     }
 
     /// Returns `Some(flexible)` if the size is flexible, and `None` otherwise.
-    pub fn flexible(&self) -> Option<f32> {
+    pub fn flexible(&self) -> Option<u32> {
         match *self {
             Flexible::Flexible(fixed) => Some(fixed),
             Flexible::Fixed(_) => None,
@@ -85,13 +120,13 @@ impl Flexible {
     }
 }
 
-/// Serializes `u32` as a memory size string.
-pub fn serialize<S: Serializer>(size: &u32, serializer: S) -> Result<S::Ok, S::Error> {
+/// Serializes `u64` as a memory size string.
+pub fn serialize<S: Serializer>(size: &u64, serializer: S) -> Result<S::Ok, S::Error> {
     to_string(*size).serialize(serializer)
 }
 
-/// Deserializes `u32` from a memory size string.
-pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u32, D::Error> {
+/// Deserializes `u64` from a memory size string.
+pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u64, D::Error> {
     from_str(&String::deserialize(deserializer)?).map_err(de::Error::custom)
 }
 
@@ -100,52 +135,32 @@ pub mod opt {
     #[allow(clippy::wildcard_imports)]
     use super::*;
 
-    /// Serializes `Option<u32>` as a memory size string.
-    pub fn serialize<S: Serializer>(size: &Option<u32>, serializer: S) -> Result<S::Ok, S::Error> {
+    /// Serializes `Option<u64>` as a memory size string.
+    pub fn serialize<S: Serializer>(size: &Option<u64>, serializer: S) -> Result<S::Ok, S::Error> {
         size.map(to_string).serialize(serializer)
     }
 
-    /// Deserializes `Option<u32>` from a memory size string.
+    /// Deserializes `Option<u64>` from a memory size string.
     pub fn deserialize<'de, D: Deserializer<'de>>(
         deserializer: D,
-    ) -> Result<Option<u32>, D::Error> {
+    ) -> Result<Option<u64>, D::Error> {
         let str = Option::<String>::deserialize(deserializer).map_err(de::Error::custom)?;
         str.map(|str| from_str(&str)).transpose().map_err(de::Error::custom)
     }
 }
 
-/// Parses a fixed size value from the given string.
-pub fn from_str(s: &str) -> Result<u32, ParseIntError> {
-    let mut range = 0..s.len();
-    let mult = if s.ends_with('M') {
-        range.end -= 1;
-        1024 * 1024
-    } else if s.ends_with('K') {
-        range.end -= 1;
-        1024
-    } else {
-        1
-    };
-    let radix = if s.starts_with("0x") || s.starts_with("0X") {
-        range.start += 2;
-        16
-    } else if s.starts_with('0') && s.len() > 1 {
-        range.start += 1;
-        8
-    } else {
-        10
-    };
-    let value = u32::from_str_radix(&s[range], radix)?;
-    Ok(value * mult)
+/// Parses a fixed size value from the given string. See [`crate::expr`] for
+/// the supported syntax.
+pub fn from_str(s: &str) -> Result<u64, Error> {
+    expr::evaluate(s)
 }
 
 /// Returns a canonical string representation of the given fixed size.
-pub fn to_string(size: u32) -> String {
-    if size > 0 && size % (1024 * 1024) == 0 {
-        format!("{}M", size / (1024 * 1024))
-    } else if size > 0 && size % 1024 == 0 {
-        format!("{}K", size / 1024)
-    } else {
-        format!("{}", size)
+pub fn to_string(size: u64) -> String {
+    for (unit, suffix) in [(1 << 30, "G"), (1 << 20, "M"), (1 << 10, "K")] {
+        if size > 0 && size.is_multiple_of(unit) {
+            return format!("{}{suffix}", size / unit);
+        }
     }
+    format!("{size}")
 }
@@ -1,24 +1,32 @@
 #![warn(clippy::pedantic)]
 
-use drone::templates;
+use drone::report::{Report, PREVIOUS_REPORT_FILE, REPORT_FILE};
 use drone::templates::layout_ld::DATA_SECTIONS;
-use drone_config::{locate_project_root, locate_target_root, Layout};
+use drone::toolchain::{check_status, read_sections, search_rust_tool};
+use drone::{header, templates};
+use drone_config::layout::built_file_name;
+use drone_config::{locate_project_root, locate_target_root, Layout, LAYOUT_IMAGE_ENV};
 use eyre::{bail, Result, WrapErr};
-use std::collections::HashMap;
-use std::ffi::{OsStr, OsString};
-use std::path::{Path, PathBuf};
-use std::process::{Command, ExitStatus};
+use std::ffi::OsString;
+use std::path::Path;
+use std::process::Command;
 use std::{env, fs};
-use walkdir::WalkDir;
 
 fn main() -> Result<()> {
     let args = env::args_os().skip(1).collect::<Vec<_>>();
     if let Some(output_position) = args.iter().position(|arg| arg == "-o") {
         let project_root = locate_project_root()?;
         let mut layout = Layout::read_from_project_root(&project_root)?;
+        if !layout.image.is_empty() && layout.selected_image.is_none() {
+            let images = layout.image.keys().map(String::as_str).collect::<Vec<_>>();
+            bail!(
+                "the layout config defines images; set {LAYOUT_IMAGE_ENV} to one of: {}",
+                images.join(", ")
+            );
+        }
         let target = locate_target_root(&project_root)?;
-        let script = target.join("layout.ld");
-        let toml = target.join("layout.toml");
+        let script = target.join(built_file_name("ld"));
+        let toml = target.join(built_file_name("toml"));
         fs::create_dir_all(&target)?;
 
         templates::layout_ld::render(&script, &layout)
@@ -26,15 +34,49 @@ fn main() -> Result<()> {
         layout.write(&toml).wrap_err("serializing calculated layout")?;
         run_linker(&script, &args).wrap_err("running stage one linker")?;
 
-        let sections = run_size(&args[output_position + 1]).wrap_err("checking section sizes")?;
+        let output = Path::new(&args[output_position + 1]);
+        let sections = read_sections(output).wrap_err("checking section sizes")?;
         let mut data_size = layout.data.padding.unwrap_or(0);
-        data_size += DATA_SECTIONS.iter().filter_map(|section| sections.get(*section)).sum::<u32>();
-        layout.calculate(Some(data_size)).wrap_err("recalculating layout")?;
+        data_size += sections
+            .iter()
+            .filter(|section| DATA_SECTIONS.contains(&section.name.as_str()))
+            .map(|section| section.size)
+            .sum::<u64>();
+        let data_sizes = layout
+            .data
+            .sections
+            .iter()
+            .map(|(name, data)| {
+                let size = sections
+                    .iter()
+                    .filter(|s| s.name == format!("data_{name}") || s.name == format!("bss_{name}"))
+                    .map(|s| s.size)
+                    .sum::<u64>();
+                (name.clone(), data.padding.unwrap_or(0) + size)
+            })
+            .collect();
+        layout
+            .calculate_with_data_sizes(Some(data_size), &data_sizes)
+            .wrap_err("recalculating layout")?;
 
         templates::layout_ld::render(&script, &layout)
             .wrap_err("rendering stage two linker script")?;
         layout.write(&toml).wrap_err("serializing calculated layout")?;
         run_linker(&script, &args).wrap_err("running stage two linker")?;
+        if layout.header.is_some() {
+            header::patch(output, &layout).wrap_err("filling in the image header")?;
+        }
+
+        let sections = read_sections(output).wrap_err("checking section sizes")?;
+        let report_path = target.join(REPORT_FILE);
+        let report = Report::new(&layout, &sections);
+        eprint!("{}", report.render(Report::read(&report_path).as_ref()));
+        if report_path.exists() {
+            fs::rename(&report_path, target.join(PREVIOUS_REPORT_FILE))
+                .wrap_err("preserving previous memory usage report")?;
+        }
+        report.write(&report_path).wrap_err("writing memory usage report")?;
+        report.check(&layout)?;
     }
 
     Ok(())
@@ -50,47 +92,3 @@ fn run_linker(script: &Path, args: &[OsString]) -> Result<()> {
     check_status(program, status)?;
     Ok(())
 }
-
-fn run_size(output: &OsStr) -> Result<HashMap<String, u32>> {
-    let program = "llvm-size";
-    let mut command = Command::new(search_rust_tool(program)?);
-    command.arg("-A").arg(output);
-    let output = command.output()?;
-    check_status(program, output.status)?;
-    let stdout = String::from_utf8(output.stdout)?;
-    let mut map = HashMap::new();
-    for line in stdout.lines() {
-        if line.starts_with('.') {
-            if let [name, size, ..] = line.split_whitespace().collect::<Vec<_>>().as_slice() {
-                map.insert(name[1..].to_string(), size.parse()?);
-            }
-        }
-    }
-    Ok(map)
-}
-
-fn search_rust_tool(tool: &str) -> Result<PathBuf> {
-    let program = "rustc";
-    let mut rustc = Command::new(program);
-    rustc.arg("--print").arg("sysroot");
-    let output = rustc.output()?;
-    check_status(program, output.status)?;
-    let sysroot = String::from_utf8(output.stdout)?;
-    for entry in WalkDir::new(sysroot.trim()) {
-        let entry = entry?;
-        if entry.file_name() == tool {
-            return Ok(entry.into_path());
-        }
-    }
-    bail!("couldn't find `{}`", tool);
-}
-
-fn check_status(program: &str, status: ExitStatus) -> Result<()> {
-    if !status.success() {
-        if let Some(code) = status.code() {
-            bail!("{program} exited with status code: {code}")
-        }
-        bail!("{program} terminated by signal")
-    }
-    Ok(())
-}
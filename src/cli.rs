@@ -29,10 +29,14 @@ pub struct Cli {
 
 #[derive(Debug, Parser)]
 pub enum Cmd {
+    /// Read the crash log left by the firmware at the connected target
+    Crashlog(CrashlogCmd),
     /// Run a GDB server attached to target
     Debug(DebugCmd),
     /// Analyze or modify the heap layout
     Heap(HeapCmd),
+    /// Inspect the memory layout
+    Layout(LayoutCmd),
     /// Load image to target memory
     Load(LoadCmd),
     /// Run unmodified OpenOCD process
@@ -41,10 +45,22 @@ pub enum Cmd {
     Probe(ProbeCmd),
     /// Perform a reset on target
     Reset(ResetCmd),
+    /// Measure the maximum stack depth at the connected target
+    Stack(StackCmd),
     /// Listen to Drone Stream at the connected target
     Stream(StreamCmd),
 }
 
+#[derive(Debug, Parser)]
+pub struct CrashlogCmd {
+    /// Application image to symbolize the crash log against
+    #[clap(long, name = "binary", parse(from_os_str))]
+    pub elf: Option<PathBuf>,
+    /// Invalidate the crash log after reading
+    #[clap(short, long)]
+    pub clear: bool,
+}
+
 #[derive(Debug, Parser)]
 pub struct DebugCmd {
     /// TCP/IP port for the GDB server
@@ -68,6 +84,10 @@ pub struct LoadCmd {
     /// Only verify image against target memory, not load
     #[clap(short = 'C', long)]
     pub verify_only: bool,
+    /// Load the binary of the specified layout image, e.g. an application
+    /// slot
+    #[clap(long, name = "IMAGE-NAME")]
+    pub image: Option<String>,
 }
 
 #[derive(Debug, Parser)]
@@ -80,7 +100,7 @@ pub struct HeapCmd {
     pub config: String,
     /// Maximum size of the heap
     #[clap(short, long, parse(try_from_str = size::from_str))]
-    pub size: Option<u32>,
+    pub size: Option<u64>,
     #[clap(subcommand)]
     pub heap_sub_cmd: Option<HeapSubCmd>,
 }
@@ -98,6 +118,57 @@ pub struct HeapGenerateCmd {
     pub pools: u32,
 }
 
+#[derive(Debug, Parser)]
+pub struct LayoutCmd {
+    #[clap(subcommand)]
+    pub layout_sub_cmd: LayoutSubCmd,
+}
+
+#[derive(Debug, Parser)]
+pub enum LayoutSubCmd {
+    /// Print the resolved memory map
+    Show(LayoutShowCmd),
+    /// Print the JSON Schema of the layout config for editor integration
+    Schema(LayoutSchemaCmd),
+    /// Compare section origins and sizes of two memory layouts
+    Diff(LayoutDiffCmd),
+    /// Print the memory usage report of the last build
+    Report(LayoutReportCmd),
+}
+
+#[derive(Debug, Parser)]
+pub struct LayoutShowCmd {
+    /// Draw an ASCII bar map for each memory region
+    #[clap(short, long)]
+    pub bars: bool,
+    /// Print the memory map in JSON format
+    #[clap(long)]
+    pub json: bool,
+}
+
+#[derive(Debug, Parser)]
+pub struct LayoutSchemaCmd {}
+
+#[derive(Debug, Parser)]
+pub struct LayoutReportCmd {}
+
+#[derive(Debug, Parser)]
+pub struct LayoutDiffCmd {
+    /// Old layout config file or calculated layout from the target directory
+    #[clap(parse(from_os_str))]
+    pub old: PathBuf,
+    /// New layout config file or calculated layout from the target directory
+    #[clap(parse(from_os_str))]
+    pub new: PathBuf,
+    /// Size of the BSS and DATA sections to calculate layout config files
+    /// with. Defaults to the size from the last build
+    #[clap(long)]
+    pub data_size: Option<String>,
+    /// Print the changes in JSON format
+    #[clap(long)]
+    pub json: bool,
+}
+
 #[derive(Debug, Parser)]
 pub struct OpenocdCmd {
     /// Arguments for OpenOCD
@@ -118,14 +189,40 @@ pub struct ProbeCmd {
 #[derive(Debug, Parser)]
 pub struct ResetCmd {}
 
+#[derive(Debug, Parser)]
+pub struct StackCmd {
+    #[clap(subcommand)]
+    pub stack_sub_cmd: StackSubCmd,
+}
+
+#[derive(Debug, Parser)]
+pub enum StackSubCmd {
+    /// Reset target and fill the stacks with the known pattern
+    Paint(StackPaintCmd),
+    /// Read the stacks back and report the maximum depth used
+    Report(StackReportCmd),
+}
+
+#[derive(Debug, Parser)]
+pub struct StackPaintCmd {}
+
+#[derive(Debug, Parser)]
+pub struct StackReportCmd {}
+
 #[derive(Debug, Parser)]
 pub struct StreamCmd {
-    /// Stream routes specification. Leave `path` empty to route to STDOUT
-    #[clap(name = "path[:stream]...", default_value = ":0:1")]
+    /// Stream routes specification. Leave `path` empty to route to STDOUT.
+    /// `format` is either `raw` (default) or `jsonl` for one JSON object per
+    /// transaction
+    #[clap(name = "path[:format][:stream]...", default_value = ":0:1")]
     pub streams: Vec<String>,
     /// Reset target before streaming
     #[clap(short, long)]
     pub reset: bool,
+    /// Application image to read Drone Stream buffer locations from.
+    /// Defaults to the binary last built by cargo
+    #[clap(long, name = "binary", parse(from_os_str))]
+    pub elf: Option<PathBuf>,
 }
 
 fn de_from_str<T: de::DeserializeOwned>(s: &str) -> Result<T> {
@@ -0,0 +1,31 @@
+//! `drone crashlog` command.
+
+use crate::cli::CrashlogCmd;
+use crate::color::Color;
+use crate::openocd::{echo_colored, exit_with_openocd, openocd_main, Commands};
+use eyre::Result;
+use termcolor::Color::Green;
+
+/// Runs `drone crashlog` command.
+pub fn run(cmd: CrashlogCmd, color: Color) -> Result<()> {
+    let CrashlogCmd { elf, clear } = cmd;
+    let mut commands = Commands::new()?;
+    // Causes crashes for picoprobe
+    // commands.push("gdb_port disabled");
+    commands.push("tcl_port disabled");
+    commands.push("telnet_port disabled");
+    commands.push("init");
+    commands.push("halt");
+    match elf {
+        Some(elf) => commands.push(format!("drone_crashlog report -elf {{{}}}", elf.display())),
+        None => commands.push("drone_crashlog report"),
+    }
+    if clear {
+        commands.push("drone_crashlog clear");
+        commands.push(echo_colored("*** Crash log has been cleared", Green, color));
+    }
+    commands.push("resume");
+    commands.push("shutdown");
+    exit_with_openocd(openocd_main, commands.into())?;
+    Ok(())
+}
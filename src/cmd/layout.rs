@@ -0,0 +1,243 @@
+//! `drone layout` command.
+
+use crate::cli::{
+    LayoutCmd, LayoutDiffCmd, LayoutReportCmd, LayoutSchemaCmd, LayoutShowCmd, LayoutSubCmd,
+};
+use crate::color::Color;
+use crate::report::{percent, Report, PREVIOUS_REPORT_FILE, REPORT_FILE};
+use drone_config::layout::built_file_name;
+use drone_config::map::{self, MemoryMap, Placement, Region, RegionDiff, SectionKind, Span};
+use drone_config::{addr, locate_project_root, locate_target_root, size, Layout, LAYOUT_SCHEMA};
+use eyre::{eyre, Result, WrapErr};
+use indexmap::IndexMap;
+use std::path::Path;
+use termcolor::Color::{Green, Red};
+
+const BAR_WIDTH: u64 = 64;
+
+const BAR_SYMBOLS: &[u8] = b"abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ";
+
+const FREE_SYMBOL: char = '.';
+
+/// Runs `drone layout` command.
+pub fn run(cmd: LayoutCmd, color: Color) -> Result<()> {
+    let LayoutCmd { layout_sub_cmd } = cmd;
+    match layout_sub_cmd {
+        LayoutSubCmd::Show(cmd) => show(cmd, color),
+        LayoutSubCmd::Schema(LayoutSchemaCmd {}) => {
+            print!("{LAYOUT_SCHEMA}");
+            Ok(())
+        }
+        LayoutSubCmd::Diff(cmd) => diff(cmd, color),
+        LayoutSubCmd::Report(LayoutReportCmd {}) => report(),
+    }
+}
+
+fn show(cmd: LayoutShowCmd, color: Color) -> Result<()> {
+    let LayoutShowCmd { bars, json } = cmd;
+    let project_root = locate_project_root()?;
+    let layout = Layout::read_last_built(&project_root)?;
+    let mut map = MemoryMap::new(&layout);
+    if let Ok(target) = locate_target_root(&project_root) {
+        if let Some(report) = Report::read(&target.join(REPORT_FILE)) {
+            map.place_flash(&report.flash);
+        }
+    }
+    if json {
+        println!("{}", serde_json::to_string_pretty(&map)?);
+        return Ok(());
+    }
+    for (i, region) in map.regions.iter().enumerate() {
+        if i > 0 {
+            println!();
+        }
+        print_region(region, bars, color);
+    }
+    Ok(())
+}
+
+fn report() -> Result<()> {
+    let project_root = locate_project_root()?;
+    let target = locate_target_root(&project_root)?;
+    let report = Report::read(&target.join(REPORT_FILE))
+        .ok_or_else(|| eyre!("no memory usage report found; build the project first"))?;
+    let previous = Report::read(&target.join(PREVIOUS_REPORT_FILE));
+    print!("{}", report.render(previous.as_ref()));
+    let layout = Layout::read_last_built(&project_root)?;
+    report.check(&layout)
+}
+
+fn diff(cmd: LayoutDiffCmd, color: Color) -> Result<()> {
+    let LayoutDiffCmd { old, new, data_size, json } = cmd;
+    let (mut main_size, data_sizes) = last_built_data_sizes();
+    if let Some(data_size) = data_size {
+        main_size = size::from_str(&data_size)?;
+    }
+    let read = |path: &Path| {
+        Layout::read_any(path, main_size, &data_sizes)
+            .wrap_err_with(|| format!("reading {}", path.display()))
+    };
+    let regions = map::diff(&MemoryMap::new(&read(&old)?), &MemoryMap::new(&read(&new)?));
+    if json {
+        println!("{}", serde_json::to_string_pretty(&regions)?);
+        return Ok(());
+    }
+    if regions.is_empty() {
+        println!("No changes in the memory layout");
+    }
+    for (i, region) in regions.iter().enumerate() {
+        if i > 0 {
+            println!();
+        }
+        print_region_diff(region, color);
+    }
+    Ok(())
+}
+
+fn last_built_data_sizes() -> (u64, IndexMap<String, u64>) {
+    let layout = locate_project_root().ok().and_then(|root| {
+        let path = locate_target_root(&root).ok()?.join(built_file_name("toml"));
+        Layout::read_calculated(&path).ok()
+    });
+    layout.map_or_else(Default::default, |layout| {
+        let sections = layout.data.sections.iter().map(|(name, s)| (name.clone(), s.size));
+        (layout.data.size, sections.collect())
+    })
+}
+
+fn print_region_diff(region: &RegionDiff, color: Color) {
+    let span = |span: Option<Span>| {
+        span.map_or_else(
+            || "-".into(),
+            |span| {
+                format!(
+                    "{}..{}",
+                    addr::to_string(span.origin),
+                    addr::to_string(span.origin + span.size)
+                )
+            },
+        )
+    };
+    let status = match (region.old, region.new) {
+        (None, _) => "added".into(),
+        (_, None) => "removed".into(),
+        (old, new) if old != new => format!("was {}", span(old)),
+        _ => format!("{} sections unchanged", region.unchanged),
+    };
+    println!("{} {} ({status})", color.bold(&region.path()), span(region.new.or(region.old)));
+    if region.sections.is_empty() {
+        return;
+    }
+    let rows = region
+        .sections
+        .iter()
+        .map(|section| {
+            let field = |f: fn(Span) -> String| match (section.old, section.new) {
+                (Some(old), Some(new)) if f(old) != f(new) => format!("{} -> {}", f(old), f(new)),
+                (old, new) => new.or(old).map(f).unwrap_or_default(),
+            };
+            let origin = field(|span| addr::to_string(span.origin));
+            let size = field(|span| size::to_string(span.size));
+            let change = match (section.old, section.new, &section.moved_by) {
+                (None, _, _) => color.bold_fg("added", Green),
+                (_, None, _) => color.bold_fg("removed", Red),
+                (Some(old), Some(new), _) if old.size != new.size => "resized".into(),
+                (_, _, Some(moved_by)) => format!("moved by {moved_by}"),
+                (_, _, None) => "moved".into(),
+            };
+            [section.name.clone(), section.kind.as_str().into(), origin, size, change]
+        })
+        .collect::<Vec<_>>();
+    let width = |column: usize| rows.iter().map(|row| row[column].len()).max().unwrap_or(0);
+    let (name_width, kind_width, origin_width, size_width) =
+        (width(0), width(1), width(2), width(3));
+    println!(
+        "  {}",
+        color.bold(&format!(
+            "{:name_width$} {:kind_width$} {:origin_width$} {:size_width$} {}",
+            "NAME", "KIND", "ORIGIN", "SIZE", "CHANGE"
+        ))
+    );
+    for [name, kind, origin, size, change] in rows {
+        println!(
+            "  {name:name_width$} {kind:kind_width$} {origin:origin_width$} {size:size_width$} \
+             {change}"
+        );
+    }
+}
+
+fn print_region(region: &Region, bars: bool, color: Color) {
+    let used = region.used();
+    println!(
+        "{} {}..{} ({} total, {} used, {})",
+        color.bold(&format!("{}.{}", region.kind.as_str(), region.name)),
+        addr::to_string(region.origin),
+        addr::to_string(region.origin + region.size),
+        size::to_string(region.size),
+        size::to_string(used),
+        percent(used, region.size),
+    );
+    if region.sections.is_empty() {
+        println!("  no sections are placed by the layout config or the last build");
+        return;
+    }
+    let name_width = region.sections.iter().map(|s| section_name(s).len()).max().unwrap_or(0);
+    let kind_width = region.sections.iter().map(|s| s.kind.as_str().len()).max().unwrap_or(0);
+    println!(
+        "  {}",
+        color.bold(&format!(
+            "{:3} {:name_width$} {:kind_width$} {:10} {:10} {:>8} {:>7}",
+            "MAP", "NAME", "KIND", "ORIGIN", "END", "SIZE", "REGION"
+        ))
+    );
+    let mut symbols = Vec::with_capacity(region.sections.len());
+    for (i, section) in region.sections.iter().enumerate() {
+        let symbol = symbol(section, i);
+        symbols.push(symbol);
+        println!(
+            "  {:3} {:name_width$} {:kind_width$} {:10} {:10} {:>8} {:>7}",
+            symbol,
+            section_name(section),
+            section.kind.as_str(),
+            addr::to_string(section.origin),
+            addr::to_string(section.end()),
+            size::to_string(section.size),
+            percent(section.size, region.size),
+        );
+    }
+    if bars {
+        println!("  [{}]", render_bar(region, &symbols));
+    }
+}
+
+fn render_bar(region: &Region, symbols: &[char]) -> String {
+    (0..BAR_WIDTH)
+        .map(|column| {
+            let offset =
+                u128::from(column * 2 + 1) * u128::from(region.size) / u128::from(BAR_WIDTH * 2);
+            let address = region.origin + offset as u64;
+            region
+                .sections
+                .iter()
+                .zip(symbols)
+                .find(|(section, _)| (section.origin..section.end()).contains(&address))
+                .map_or(FREE_SYMBOL, |(_, &symbol)| symbol)
+        })
+        .collect()
+}
+
+fn symbol(section: &Placement, index: usize) -> char {
+    if section.kind == SectionKind::Free {
+        FREE_SYMBOL
+    } else {
+        BAR_SYMBOLS.get(index).map_or('#', |&symbol| symbol.into())
+    }
+}
+
+fn section_name(section: &Placement) -> &str {
+    if section.kind == SectionKind::Free {
+        "<free>"
+    } else {
+        &section.name
+    }
+}
@@ -3,21 +3,25 @@
 use crate::cli::LoadCmd;
 use crate::color::Color;
 use crate::openocd::{echo_colored, exit_with_openocd, openocd_main, Commands};
-use drone_config::locate_project_root;
-use eyre::{eyre, Result};
-use std::env;
-use std::os::unix::prelude::*;
+use crate::toolchain::{built_binaries, cargo_target_dir, read_symbols};
+use drone_config::{addr, locate_project_root, Layout, LAYOUT_IMAGE_ENV};
+use eyre::{bail, eyre, Result};
 use std::path::Path;
 use termcolor::Color::Blue;
 use tracing::error;
 
 /// Runs `drone load` command.
 pub fn run(cmd: LoadCmd, color: Color) -> Result<()> {
-    let LoadCmd { binary, release, profile, verify, verify_only } = cmd;
+    let LoadCmd { binary, release, profile, verify, verify_only, image } = cmd;
+    let slot = image.map(locate_slot).transpose()?;
+    let binary = binary.or_else(|| slot.as_ref().map(|slot| slot.binary.clone()));
     let binary = match locate_binary(binary, release, profile)? {
         Some(binary) => binary,
         None => return Ok(()),
     };
+    if let Some(slot) = &slot {
+        check_slot(&binary, slot)?;
+    }
     let mut commands = Commands::new()?;
     // Causes crashes for picoprobe
     // commands.push("gdb_port disabled");
@@ -32,6 +36,9 @@ pub fn run(cmd: LoadCmd, color: Color) -> Result<()> {
     if verify || verify_only {
         commands.push(echo_colored(format!("*** Verifying {binary}"), Blue, color));
         commands.push(format!("verify_image {binary} 0"));
+        if has_header()? {
+            commands.push("drone_header verify");
+        }
     }
     commands.push("reset halt");
     commands.push("resume");
@@ -40,6 +47,43 @@ pub fn run(cmd: LoadCmd, color: Color) -> Result<()> {
     Ok(())
 }
 
+struct Slot {
+    image: String,
+    binary: String,
+    origin: u64,
+}
+
+fn locate_slot(image: String) -> Result<Slot> {
+    let layout = Layout::read_from_project_root(&locate_project_root()?)?;
+    let Some(config) = layout.image.get(&image) else {
+        bail!("image.{image} is not defined in the layout config");
+    };
+    let binary = config.binary.clone().unwrap_or_else(|| image.clone());
+    Ok(Slot { origin: layout.flash[&config.flash].origin, binary, image })
+}
+
+fn check_slot(binary: &str, slot: &Slot) -> Result<()> {
+    let Slot { image, origin, .. } = slot;
+    let symbols = read_symbols(&locate_project_root()?.join(binary))?;
+    match symbols.get("IMAGE_BASE") {
+        Some(base) if base == origin => Ok(()),
+        Some(&base) => bail!(
+            "{binary} is linked for {} instead of image.{image} at {}; rebuild it with \
+             {LAYOUT_IMAGE_ENV}={image}",
+            addr::to_string(base),
+            addr::to_string(*origin)
+        ),
+        None => bail!(
+            "{binary} is not linked for any image; rebuild it with {LAYOUT_IMAGE_ENV}={image}"
+        ),
+    }
+}
+
+fn has_header() -> Result<bool> {
+    let layout = Layout::read_from_project_root(&locate_project_root()?)?;
+    Ok(layout.header.is_some())
+}
+
 fn locate_binary(
     binary: Option<String>,
     release: bool,
@@ -51,47 +95,18 @@ fn locate_binary(
         }
     }
     let root = locate_project_root()?;
-    let target_dir = env::var("CARGO_BUILD_TARGET_DIR")
-        .or_else(|_| env::var("CARGO_TARGET_DIR"))
-        .unwrap_or_else(|_| "target".into());
     let select_profile = profile.or_else(|| release.then(|| "release".into()));
-    let target = root.join(target_dir).join(env::var("CARGO_BUILD_TARGET")?);
-    let mut binaries = Vec::new();
-    for entry in target.read_dir()? {
-        let entry = entry?;
-        if !entry.file_type()?.is_dir() {
-            continue;
-        }
-        let profile_path = entry.path();
-        let filter_profile = select_profile.as_deref().map_or(false, |select_profile| {
-            profile_path.file_name().expect("bad target dir").to_string_lossy() != select_profile
-        });
-        if filter_profile {
-            continue;
-        }
-        for entry in profile_path.read_dir()? {
-            let path = entry?.path();
-            let metadata = path.metadata()?;
-            if !metadata.is_file() || metadata.permissions().mode() & 0o111 == 0 {
-                continue;
-            }
-            let file_name = path.file_name().expect("bad target dir").to_string_lossy();
-            if binary.as_deref().map_or(false, |binary| file_name != binary) {
-                continue;
-            }
-            if file_name.starts_with('.') {
-                continue;
-            }
-            let path = path
-                .strip_prefix(&root)
+    let target = cargo_target_dir(&root)?;
+    let mut binaries = built_binaries(&target, binary.as_deref(), select_profile.as_deref())?
+        .into_iter()
+        .map(|path| {
+            path.strip_prefix(&root)
                 .unwrap_or(&path)
-                .to_path_buf()
                 .to_str()
-                .ok_or_else(|| eyre!("non-unicode path to binary"))?
-                .to_string();
-            binaries.push(path);
-        }
-    }
+                .map(ToString::to_string)
+                .ok_or_else(|| eyre!("non-unicode path to binary"))
+        })
+        .collect::<Result<Vec<_>>>()?;
     if binaries.len() > 1 {
         error!("Found multiple matching binaries: {}", binaries.join(", "));
         error!("Please disambiguate specifying binary name, profile name, or release mode");
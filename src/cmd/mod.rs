@@ -1,9 +1,12 @@
 //! CLI commands.
 
+pub mod crashlog;
 pub mod debug;
 pub mod load;
 // pub mod heap;
+pub mod layout;
 pub mod openocd;
 pub mod probe;
 pub mod reset;
+pub mod stack;
 pub mod stream;
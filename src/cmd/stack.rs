@@ -0,0 +1,39 @@
+//! `drone stack` command.
+
+use crate::cli::{StackCmd, StackPaintCmd, StackReportCmd, StackSubCmd};
+use crate::color::Color;
+use crate::openocd::{echo_colored, exit_with_openocd, openocd_main, Commands};
+use eyre::Result;
+use termcolor::Color::{Cyan, Green};
+
+/// Runs `drone stack` command.
+pub fn run(cmd: StackCmd, color: Color) -> Result<()> {
+    let StackCmd { stack_sub_cmd } = cmd;
+    let mut commands = Commands::new()?;
+    // Causes crashes for picoprobe
+    // commands.push("gdb_port disabled");
+    commands.push("tcl_port disabled");
+    commands.push("telnet_port disabled");
+    commands.push("init");
+    match stack_sub_cmd {
+        StackSubCmd::Paint(StackPaintCmd {}) => {
+            commands.push("reset halt");
+            commands.push("drone_stack paint");
+            commands.push("resume");
+            commands.push(echo_colored("*** Stacks have been painted", Green, color));
+            commands.push(echo_colored(
+                "*** Hint: exercise the firmware, then run `drone stack report`",
+                Cyan,
+                color,
+            ));
+        }
+        StackSubCmd::Report(StackReportCmd {}) => {
+            commands.push("halt");
+            commands.push("drone_stack report");
+            commands.push("resume");
+        }
+    }
+    commands.push("shutdown");
+    exit_with_openocd(openocd_main, commands.into())?;
+    Ok(())
+}
@@ -8,8 +8,11 @@ use termcolor::Color::Green;
 
 /// Runs `drone stream` command.
 pub fn run(cmd: StreamCmd, color: Color) -> Result<()> {
-    let StreamCmd { streams, reset } = cmd;
-    let streams = streams.join(" ");
+    let StreamCmd { streams, reset, elf } = cmd;
+    let mut streams = streams.join(" ");
+    if let Some(elf) = elf {
+        streams = format!("-elf {{{}}} {streams}", elf.display());
+    }
     let mut commands = Commands::new()?;
     // Causes crashes for picoprobe
     // commands.push("gdb_port disabled");
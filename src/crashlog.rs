@@ -0,0 +1,326 @@
+//! Persistent crash log reading.
+//!
+//! The firmware fault handler writes a crash record into the `.crashlog`
+//! section defined by the `[crashlog]` entry of the memory layout. The record
+//! consists of little-endian 32-bit words:
+//!
+//! | Offset    | Field                                               |
+//! |-----------|-----------------------------------------------------|
+//! | 0         | [`MAGIC`]                                           |
+//! | 4..36     | Exception frame: R0, R1, R2, R3, R12, LR, PC, xPSR  |
+//! | 36        | SP before the exception entry                       |
+//! | 40        | EXC_RETURN                                          |
+//! | 44        | CFSR                                                |
+//! | 48        | HFSR                                                |
+//! | 52        | MMFAR                                               |
+//! | 56        | BFAR                                                |
+//! | 60        | Number of words in the stack dump                   |
+//! | 64..128   | Reason as a zero-padded UTF-8 string                |
+//! | 128..     | Stack dump starting from SP                         |
+
+use crate::stream::runtime::{self, result_from, result_into};
+use crate::toolchain::symbolize;
+use drone_config::{addr, locate_project_root, size, Layout, CRASHLOG_HEADER_SIZE};
+use drone_openocd::{
+    command_context, command_invocation, command_mode_COMMAND_EXEC, command_registration,
+    get_current_target, register_commands, target, target_read_buffer, target_write_u32,
+    COMMAND_REGISTRATION_DONE, ERROR_FAIL,
+};
+use eyre::{bail, Result};
+use std::ffi::{CStr, CString, OsStr};
+use std::os::raw::c_int;
+use std::os::unix::prelude::*;
+use std::path::Path;
+use std::{ptr, slice};
+use tracing::{error, info, warn};
+
+/// Marker of a valid crash record, `CLOG` in ASCII.
+pub const MAGIC: u32 = 0x474F_4C43;
+
+const FRAME_REGISTERS: [&str; 8] = ["R0", "R1", "R2", "R3", "R12", "LR", "PC", "xPSR"];
+
+const REASON_OFFSET: usize = 64;
+
+const CFSR_BITS: &[(u32, &str)] = &[
+    (0, "IACCVIOL: instruction access violation"),
+    (1, "DACCVIOL: data access violation"),
+    (3, "MUNSTKERR: memory fault on exception return unstacking"),
+    (4, "MSTKERR: memory fault on exception entry stacking"),
+    (5, "MLSPERR: memory fault during floating-point lazy state preservation"),
+    (8, "IBUSERR: instruction bus error"),
+    (9, "PRECISERR: precise data bus error"),
+    (10, "IMPRECISERR: imprecise data bus error"),
+    (11, "UNSTKERR: bus fault on exception return unstacking"),
+    (12, "STKERR: bus fault on exception entry stacking"),
+    (13, "LSPERR: bus fault during floating-point lazy state preservation"),
+    (16, "UNDEFINSTR: undefined instruction"),
+    (17, "INVSTATE: invalid EPSR state"),
+    (18, "INVPC: invalid EXC_RETURN value"),
+    (19, "NOCP: coprocessor access"),
+    (20, "STKOF: stack overflow"),
+    (24, "UNALIGNED: unaligned access"),
+    (25, "DIVBYZERO: division by zero"),
+];
+
+const HFSR_BITS: &[(u32, &str)] = &[
+    (1, "VECTTBL: vector table read fault"),
+    (30, "FORCED: escalated configurable fault"),
+    (31, "DEBUGEVT: debug event"),
+];
+
+/// Crash record decoded from the target memory.
+#[derive(Debug)]
+pub struct Crashlog {
+    /// Exception frame: R0, R1, R2, R3, R12, LR, PC, xPSR.
+    pub frame: [u32; 8],
+    /// Stack pointer before the exception entry.
+    pub sp: u32,
+    /// EXC_RETURN value of the fault handler.
+    pub exc_return: u32,
+    /// Configurable Fault Status Register.
+    pub cfsr: u32,
+    /// HardFault Status Register.
+    pub hfsr: u32,
+    /// MemManage Fault Address Register.
+    pub mmfar: u32,
+    /// BusFault Address Register.
+    pub bfar: u32,
+    /// Crash reason provided by the firmware.
+    pub reason: String,
+    /// Stack dump starting from `sp`.
+    pub stack: Vec<u32>,
+}
+
+impl Crashlog {
+    /// Decodes a crash record from the `buffer` read from the target memory.
+    /// Returns `None` if the buffer doesn't contain a valid record.
+    pub fn parse(buffer: &[u8]) -> Result<Option<Self>> {
+        if buffer.len() < CRASHLOG_HEADER_SIZE as usize {
+            bail!("crash log buffer is smaller than the header ({} bytes)", buffer.len());
+        }
+        let words = buffer
+            .chunks_exact(4)
+            .map(|chunk| u32::from_le_bytes(chunk.try_into().unwrap()))
+            .collect::<Vec<_>>();
+        if words[0] != MAGIC {
+            return Ok(None);
+        }
+        let mut frame = [0; 8];
+        frame.copy_from_slice(&words[1..9]);
+        let header_words = CRASHLOG_HEADER_SIZE as usize / 4;
+        let mut stack_len = words[15] as usize;
+        if header_words + stack_len > words.len() {
+            warn!("Crash log stack dump is truncated ({stack_len} words recorded)");
+            stack_len = words.len() - header_words;
+        }
+        let reason = &buffer[REASON_OFFSET..CRASHLOG_HEADER_SIZE as usize];
+        let reason = reason.split(|&byte| byte == 0).next().unwrap_or_default();
+        Ok(Some(Self {
+            frame,
+            sp: words[9],
+            exc_return: words[10],
+            cfsr: words[11],
+            hfsr: words[12],
+            mmfar: words[13],
+            bfar: words[14],
+            reason: String::from_utf8_lossy(reason).into_owned(),
+            stack: words[header_words..header_words + stack_len].to_vec(),
+        }))
+    }
+
+    /// Returns the program counter at the moment of the fault.
+    pub fn pc(&self) -> u32 {
+        self.frame[6]
+    }
+
+    /// Returns the link register at the moment of the fault.
+    pub fn lr(&self) -> u32 {
+        self.frame[5]
+    }
+
+    /// Returns descriptions of the fault status bits set in CFSR and HFSR.
+    pub fn faults(&self) -> Vec<&'static str> {
+        let cfsr = CFSR_BITS.iter().filter(|&&(bit, _)| self.cfsr & 1 << bit != 0);
+        let hfsr = HFSR_BITS.iter().filter(|&&(bit, _)| self.hfsr & 1 << bit != 0);
+        cfsr.chain(hfsr).map(|&(_, description)| description).collect()
+    }
+
+    /// Returns the address of the access that caused the fault, if it is
+    /// known.
+    pub fn fault_address(&self) -> Option<u32> {
+        const MMARVALID: u32 = 1 << 7;
+        const BFARVALID: u32 = 1 << 15;
+        if self.cfsr & MMARVALID != 0 {
+            Some(self.mmfar)
+        } else if self.cfsr & BFARVALID != 0 {
+            Some(self.bfar)
+        } else {
+            None
+        }
+    }
+}
+
+/// Initializes Drone Crashlog commands.
+///
+/// # Safety
+///
+/// `ctx` must be a valid pointer to the OpenOCD command context.
+pub unsafe fn init(ctx: *mut command_context) -> c_int {
+    let drone_crashlog_subcommand_handlers = Box::leak(Box::new([
+        command_registration {
+            name: CString::new("report").unwrap().into_raw(),
+            handler: Some(handle_drone_crashlog_report_command),
+            mode: command_mode_COMMAND_EXEC,
+            help: CString::new("decode and print the crash log").unwrap().into_raw(),
+            usage: CString::new("[-elf path]").unwrap().into_raw(),
+            chain: ptr::null_mut(),
+            jim_handler: None,
+        },
+        command_registration {
+            name: CString::new("clear").unwrap().into_raw(),
+            handler: Some(handle_drone_crashlog_clear_command),
+            mode: command_mode_COMMAND_EXEC,
+            help: CString::new("invalidate the crash log").unwrap().into_raw(),
+            usage: CString::new("").unwrap().into_raw(),
+            chain: ptr::null_mut(),
+            jim_handler: None,
+        },
+        unsafe { COMMAND_REGISTRATION_DONE },
+    ]));
+    let drone_crashlog_command_handlers = Box::leak(Box::new([
+        command_registration {
+            name: CString::new("drone_crashlog").unwrap().into_raw(),
+            handler: None,
+            mode: command_mode_COMMAND_EXEC,
+            help: CString::new("Drone Crashlog commands").unwrap().into_raw(),
+            usage: CString::new("").unwrap().into_raw(),
+            chain: drone_crashlog_subcommand_handlers.as_ptr(),
+            jim_handler: None,
+        },
+        unsafe { COMMAND_REGISTRATION_DONE },
+    ]));
+    unsafe { register_commands(ctx, ptr::null_mut(), drone_crashlog_command_handlers.as_ptr()) }
+}
+
+unsafe extern "C" fn handle_drone_crashlog_report_command(cmd: *mut command_invocation) -> c_int {
+    let args = unsafe { slice::from_raw_parts((*cmd).argv, (*cmd).argc as _) }
+        .iter()
+        .map(|arg| unsafe { CStr::from_ptr(*arg).to_bytes() })
+        .collect::<Vec<_>>();
+    let elf = match args.as_slice() {
+        [] => None,
+        [b"-elf", path] => Some(Path::new(OsStr::from_bytes(path))),
+        _ => {
+            error!("`drone_crashlog report` takes only an optional `-elf path` argument");
+            return ERROR_FAIL;
+        }
+    };
+    let Some((target, layout)) = (unsafe { prepare(cmd) }) else { return ERROR_FAIL };
+    result_into(unsafe { report(target, &layout, elf) })
+}
+
+unsafe extern "C" fn handle_drone_crashlog_clear_command(cmd: *mut command_invocation) -> c_int {
+    if unsafe { (*cmd).argc } > 0 {
+        error!("`drone_crashlog clear` takes no arguments");
+        return ERROR_FAIL;
+    }
+    let Some((target, layout)) = (unsafe { prepare(cmd) }) else { return ERROR_FAIL };
+    let origin = layout.crashlog.as_ref().unwrap().origin;
+    let result = unsafe { result_from(target_write_u32(target, origin, 0)) };
+    if result.is_ok() {
+        info!("Cleared the crash log at {}", addr::to_string(origin));
+    }
+    result_into(result)
+}
+
+unsafe fn prepare(cmd: *mut command_invocation) -> Option<(*mut target, Layout)> {
+    let layout = match locate_project_root().and_then(|root| Layout::read_last_built(&root)) {
+        Ok(layout) => layout,
+        Err(err) => {
+            error!("Couldn't read the memory layout: {err:#?}");
+            return None;
+        }
+    };
+    if layout.crashlog.is_none() {
+        error!("No crashlog section is defined in the memory layout");
+        return None;
+    }
+    Some((unsafe { get_current_target((*cmd).ctx) }, layout))
+}
+
+unsafe fn report(target: *mut target, layout: &Layout, elf: Option<&Path>) -> runtime::Result<()> {
+    let section = layout.crashlog.as_ref().unwrap();
+    let Ok(size) = u32::try_from(section.size) else {
+        error!("crashlog.size is too large to read ({})", size::to_string(section.size));
+        return Err(runtime::Error::Fail);
+    };
+    let mut buffer = vec![0; size as usize];
+    unsafe {
+        result_from(target_read_buffer(target, section.origin, size, buffer.as_mut_ptr()))?;
+    }
+    let crashlog = match Crashlog::parse(&buffer) {
+        Ok(Some(crashlog)) => crashlog,
+        Ok(None) => {
+            info!("No crash log found at {}", addr::to_string(section.origin));
+            return Ok(());
+        }
+        Err(err) => {
+            error!("Couldn't decode the crash log: {err:#?}");
+            return Err(runtime::Error::Fail);
+        }
+    };
+    print_report(&crashlog, layout, elf);
+    Ok(())
+}
+
+fn print_report(crashlog: &Crashlog, layout: &Layout, elf: Option<&Path>) {
+    let is_code = |word: u32| {
+        word & 1 != 0
+            && layout.flash.values().any(|flash| {
+                let range = flash.origin..flash.origin.saturating_add(flash.size);
+                range.contains(&u64::from(word & !1))
+            })
+    };
+    let mut addresses = vec![crashlog.lr() & !1, crashlog.pc()];
+    addresses.extend(crashlog.stack.iter().filter(|&&word| is_code(word)).map(|word| word & !1));
+    let mut symbols = match elf.map(|elf| symbolize(elf, &addresses)) {
+        Some(Ok(symbols)) => symbols.into_iter(),
+        Some(Err(err)) => {
+            warn!("Couldn't symbolize the crash log: {err:#}");
+            Vec::new().into_iter()
+        }
+        None => Vec::new().into_iter(),
+    };
+    let mut symbol = || symbols.next().map(|symbol| format!("  {symbol}")).unwrap_or_default();
+    if crashlog.reason.is_empty() {
+        println!("Crash reason: unknown");
+    } else {
+        println!("Crash reason: {}", crashlog.reason);
+    }
+    for fault in crashlog.faults() {
+        println!("Fault: {fault}");
+    }
+    if let Some(address) = crashlog.fault_address() {
+        println!("Fault address: {}", addr::to_string(address.into()));
+    }
+    println!();
+    for (name, value) in FRAME_REGISTERS.iter().zip(crashlog.frame) {
+        match *name {
+            "PC" | "LR" => println!("{name:>10} = {value:#010x}{}", symbol()),
+            _ => println!("{name:>10} = {value:#010x}"),
+        }
+    }
+    println!("{:>10} = {:#010x}", "SP", crashlog.sp);
+    println!("{:>10} = {:#010x}", "EXC_RETURN", crashlog.exc_return);
+    println!("{:>10} = {:#010x}", "CFSR", crashlog.cfsr);
+    println!("{:>10} = {:#010x}", "HFSR", crashlog.hfsr);
+    if !crashlog.stack.is_empty() {
+        println!();
+        println!("Stack dump ({} words):", crashlog.stack.len());
+        for (offset, &word) in (0..).step_by(4).zip(&crashlog.stack) {
+            let address = crashlog.sp.wrapping_add(offset);
+            let symbol = if is_code(word) { symbol() } else { String::new() };
+            println!("  {address:#010x}: {word:#010x}{symbol}");
+        }
+    }
+}
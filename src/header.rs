@@ -0,0 +1,316 @@
+//! Firmware image header.
+//!
+//! When the `[header]` entry of the memory layout is present, the generated
+//! linker script reserves the `.image_header` section at `header.offset` from
+//! the beginning of the program flash region, and `drone-ld` fills it in after
+//! linking. The header consists of little-endian fields:
+//!
+//! | Offset | Field                                                    |
+//! |--------|----------------------------------------------------------|
+//! | 0      | [`MAGIC`]                                                |
+//! | 4      | Checksum algorithm: 1 for CRC-32, 2 for SHA-256          |
+//! | 8      | Image length in bytes, including the header              |
+//! | 12     | Reserved, zero                                           |
+//! | 16     | Version: major, minor, and patch as `u16`, then zero     |
+//! | 24     | Build timestamp in seconds since the Unix epoch as `u64` |
+//! | 32..64 | Checksum, zero-padded                                    |
+//!
+//! The image starts at the beginning of the program flash region. The checksum
+//! covers the whole image with the checksum field zeroed. Gaps between the
+//! sections are filled with `0xFF`, as in erased flash memory.
+
+use crate::stream::runtime::{self, result_from, result_into};
+use crate::toolchain::{read_binary, update_section};
+use drone_config::layout::Checksum;
+use drone_config::{addr, locate_project_root, Layout, IMAGE_HEADER_SIZE};
+use drone_openocd::{
+    command_context, command_invocation, command_mode_COMMAND_EXEC, command_registration,
+    get_current_target, register_commands, target, target_read_buffer, COMMAND_REGISTRATION_DONE,
+    ERROR_FAIL,
+};
+use eyre::{bail, eyre, Result, WrapErr};
+use sha2::{Digest, Sha256};
+use std::ffi::CString;
+use std::os::raw::c_int;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+use std::{env, ptr};
+use tracing::{error, info};
+
+/// Marker of a valid image header, `DRIH` in ASCII.
+pub const MAGIC: u32 = 0x4849_5244;
+
+/// Name of the image header section.
+pub const SECTION: &str = ".image_header";
+
+/// The environment variable overriding `header.version` of the memory layout.
+pub const VERSION_ENV: &str = "DRONE_IMAGE_VERSION";
+
+const CHECKSUM_OFFSET: usize = 32;
+
+/// Decoded firmware image header.
+#[derive(Debug)]
+pub struct ImageHeader {
+    /// Checksum algorithm.
+    pub checksum: Checksum,
+    /// Image length in bytes, including the header.
+    pub length: u32,
+    /// Firmware version: major, minor, and patch.
+    pub version: [u16; 3],
+    /// Build timestamp in seconds since the Unix epoch.
+    pub timestamp: u64,
+    /// Image checksum.
+    pub digest: Vec<u8>,
+}
+
+impl ImageHeader {
+    /// Decodes a header from the beginning of the `header` bytes. Returns
+    /// `None` if there is no valid header.
+    pub fn parse(header: &[u8]) -> Result<Option<Self>> {
+        let Some(header) = header.get(..IMAGE_HEADER_SIZE as usize) else {
+            bail!("header is shorter than {IMAGE_HEADER_SIZE} bytes ({})", header.len());
+        };
+        let u16_at = |offset: usize| u16::from_le_bytes([header[offset], header[offset + 1]]);
+        let u32_at =
+            |offset: usize| u32::from_le_bytes(header[offset..offset + 4].try_into().unwrap());
+        if u32_at(0) != MAGIC {
+            return Ok(None);
+        }
+        let checksum = match u32_at(4) {
+            1 => Checksum::Crc32,
+            2 => Checksum::Sha256,
+            algorithm => bail!("unknown image checksum algorithm {algorithm}"),
+        };
+        let digest = &header[CHECKSUM_OFFSET..CHECKSUM_OFFSET + digest_size(checksum)];
+        Ok(Some(Self {
+            checksum,
+            length: u32_at(8),
+            version: [u16_at(16), u16_at(18), u16_at(20)],
+            timestamp: u64::from_le_bytes(header[24..32].try_into().unwrap()),
+            digest: digest.to_vec(),
+        }))
+    }
+
+    /// Encodes the header fields into the beginning of the `header` bytes.
+    pub fn write(&self, header: &mut [u8]) {
+        let header = &mut header[..IMAGE_HEADER_SIZE as usize];
+        header.fill(0);
+        let algorithm: u32 = match self.checksum {
+            Checksum::Crc32 => 1,
+            Checksum::Sha256 => 2,
+        };
+        header[0..4].copy_from_slice(&MAGIC.to_le_bytes());
+        header[4..8].copy_from_slice(&algorithm.to_le_bytes());
+        header[8..12].copy_from_slice(&self.length.to_le_bytes());
+        for (i, part) in self.version.iter().enumerate() {
+            header[16 + i * 2..18 + i * 2].copy_from_slice(&part.to_le_bytes());
+        }
+        header[24..32].copy_from_slice(&self.timestamp.to_le_bytes());
+        header[CHECKSUM_OFFSET..CHECKSUM_OFFSET + self.digest.len()].copy_from_slice(&self.digest);
+    }
+
+    /// Returns `Err` if the checksum doesn't match the `image`, which contains
+    /// the header at `offset`.
+    pub fn verify(&self, image: &[u8], offset: usize) -> Result<()> {
+        let Some(image) = image.get(..self.length as usize) else {
+            bail!("image is shorter than its header states ({} < {})", image.len(), self.length);
+        };
+        if image.len() < offset + IMAGE_HEADER_SIZE as usize {
+            bail!("image length {} doesn't cover the header at offset {offset}", self.length);
+        }
+        if compute_digest(self.checksum, image, offset) != self.digest {
+            bail!("image checksum mismatch");
+        }
+        Ok(())
+    }
+}
+
+/// Fills in the image header of the linked `elf` file according to the
+/// `layout`.
+pub fn patch(elf: &Path, layout: &Layout) -> Result<ImageHeader> {
+    let checksum = layout.header.as_ref().and_then(|header| header.checksum);
+    let checksum = checksum.unwrap_or(Checksum::Crc32);
+    let version = image_version(layout)?;
+    let mut image = read_binary(elf)?;
+    let size = layout.header_size().unwrap_or(IMAGE_HEADER_SIZE) as usize;
+    let offset = layout.header_offset().unwrap_or(0) as usize;
+    if image.len() < offset + size {
+        bail!("{} has no {SECTION} section at offset {offset} of the image", elf.display());
+    }
+    if let Some(flash) = layout.flash.get(layout.program_flash()) {
+        if image.len() > flash.size as usize {
+            bail!(
+                "{} doesn't fit into flash.{} ({} > {}); the image header requires all loadable \
+                 sections to be in one flash region",
+                elf.display(),
+                layout.program_flash(),
+                image.len(),
+                flash.size
+            );
+        }
+    }
+    let mut header = ImageHeader {
+        checksum,
+        length: u32::try_from(image.len())?,
+        version,
+        timestamp: build_timestamp()?,
+        digest: vec![0; digest_size(checksum)],
+    };
+    header.write(&mut image[offset..]);
+    header.digest = compute_digest(checksum, &image, offset);
+    header.write(&mut image[offset..]);
+    update_section(elf, SECTION, &image[offset..offset + size])?;
+    Ok(header)
+}
+
+fn image_version(layout: &Layout) -> Result<[u16; 3]> {
+    let version = match env::var(VERSION_ENV) {
+        Ok(version) => version,
+        Err(_) => layout.header.as_ref().and_then(|header| header.version.clone()).ok_or_else(
+            || eyre!("the image version is set by neither header.version nor {VERSION_ENV}"),
+        )?,
+    };
+    parse_version(&version).wrap_err_with(|| format!("invalid image version `{version}`"))
+}
+
+fn parse_version(version: &str) -> Result<[u16; 3]> {
+    let parts = version.split('.').map(str::parse::<u16>).collect::<Result<Vec<_>, _>>()?;
+    match parts.as_slice() {
+        &[major, minor, patch] => Ok([major, minor, patch]),
+        _ => bail!("expected MAJOR.MINOR.PATCH"),
+    }
+}
+
+/// Initializes Drone Header commands.
+///
+/// # Safety
+///
+/// `ctx` must be a valid pointer to the OpenOCD command context.
+pub unsafe fn init(ctx: *mut command_context) -> c_int {
+    let drone_header_subcommand_handlers = Box::leak(Box::new([
+        command_registration {
+            name: CString::new("verify").unwrap().into_raw(),
+            handler: Some(handle_drone_header_verify_command),
+            mode: command_mode_COMMAND_EXEC,
+            help: CString::new("validate the firmware image in the target memory")
+                .unwrap()
+                .into_raw(),
+            usage: CString::new("").unwrap().into_raw(),
+            chain: ptr::null_mut(),
+            jim_handler: None,
+        },
+        unsafe { COMMAND_REGISTRATION_DONE },
+    ]));
+    let drone_header_command_handlers = Box::leak(Box::new([
+        command_registration {
+            name: CString::new("drone_header").unwrap().into_raw(),
+            handler: None,
+            mode: command_mode_COMMAND_EXEC,
+            help: CString::new("Drone Header commands").unwrap().into_raw(),
+            usage: CString::new("").unwrap().into_raw(),
+            chain: drone_header_subcommand_handlers.as_ptr(),
+            jim_handler: None,
+        },
+        unsafe { COMMAND_REGISTRATION_DONE },
+    ]));
+    unsafe { register_commands(ctx, ptr::null_mut(), drone_header_command_handlers.as_ptr()) }
+}
+
+unsafe extern "C" fn handle_drone_header_verify_command(cmd: *mut command_invocation) -> c_int {
+    if unsafe { (*cmd).argc } > 0 {
+        error!("`drone_header verify` takes no arguments");
+        return ERROR_FAIL;
+    }
+    let layout = match locate_project_root().and_then(|root| Layout::read_last_built(&root)) {
+        Ok(layout) => layout,
+        Err(err) => {
+            error!("Couldn't read the memory layout: {err:#?}");
+            return ERROR_FAIL;
+        }
+    };
+    let Some(flash) = layout.flash.get(layout.program_flash()) else {
+        error!("No flash.{} region is defined in the memory layout", layout.program_flash());
+        return ERROR_FAIL;
+    };
+    let offset = layout.header_offset().unwrap_or(0);
+    let target = unsafe { get_current_target((*cmd).ctx) };
+    result_into(unsafe { verify(target, flash.origin, flash.size, offset) })
+}
+
+unsafe fn verify(target: *mut target, origin: u64, size: u64, offset: u64) -> runtime::Result<()> {
+    let mut header = [0; IMAGE_HEADER_SIZE as usize];
+    unsafe {
+        result_from(target_read_buffer(
+            target,
+            origin + offset,
+            IMAGE_HEADER_SIZE as u32,
+            header.as_mut_ptr(),
+        ))?;
+    }
+    let header = match ImageHeader::parse(&header) {
+        Ok(Some(header)) if u64::from(header.length) <= size => header,
+        Ok(Some(header)) => {
+            error!("Image length {} exceeds the flash region size {size}", header.length);
+            return Err(runtime::Error::Fail);
+        }
+        Ok(None) => {
+            error!("No image header found at {}", addr::to_string(origin + offset));
+            return Err(runtime::Error::Fail);
+        }
+        Err(err) => {
+            error!("Couldn't decode the image header: {err:#}");
+            return Err(runtime::Error::Fail);
+        }
+    };
+    let mut image = vec![0; header.length as usize];
+    unsafe {
+        result_from(target_read_buffer(target, origin, header.length, image.as_mut_ptr()))?;
+    }
+    let [major, minor, patch] = header.version;
+    match header.verify(&image, offset as usize) {
+        Ok(()) => {
+            info!("Image v{major}.{minor}.{patch} ({} bytes) checksum is valid", header.length);
+            Ok(())
+        }
+        Err(err) => {
+            error!("Image v{major}.{minor}.{patch} is corrupted: {err:#}");
+            Err(runtime::Error::Fail)
+        }
+    }
+}
+
+fn compute_digest(checksum: Checksum, image: &[u8], offset: usize) -> Vec<u8> {
+    let field = offset + CHECKSUM_OFFSET..offset + CHECKSUM_OFFSET + digest_size(checksum);
+    let (head, tail) = (&image[..field.start], &image[field.end..]);
+    let zeros = vec![0; field.len()];
+    match checksum {
+        Checksum::Crc32 => {
+            let mut hasher = crc32fast::Hasher::new();
+            for chunk in [head, &zeros, tail] {
+                hasher.update(chunk);
+            }
+            hasher.finalize().to_le_bytes().to_vec()
+        }
+        Checksum::Sha256 => {
+            let mut hasher = Sha256::new();
+            for chunk in [head, &zeros, tail] {
+                hasher.update(chunk);
+            }
+            hasher.finalize().to_vec()
+        }
+    }
+}
+
+fn digest_size(checksum: Checksum) -> usize {
+    match checksum {
+        Checksum::Crc32 => 4,
+        Checksum::Sha256 => 32,
+    }
+}
+
+fn build_timestamp() -> Result<u64> {
+    if let Ok(epoch) = env::var("SOURCE_DATE_EPOCH") {
+        return Ok(epoch.parse()?);
+    }
+    Ok(SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs())
+}
@@ -61,7 +61,7 @@ pub fn optimize(trace: &TraceMap, size: u32, mut pools: u32) -> Result<(Vec<(u32
 pub fn render(w: &mut impl Write, key: &str, layout: &[(u32, u32)]) -> Result<()> {
     let size = layout.iter().map(|(size, count)| size * count).sum::<u32>();
     writeln!(w, "[heap.{}]", key)?;
-    writeln!(w, "size = \"{}\"", size::to_string(size))?;
+    writeln!(w, "size = \"{}\"", size::to_string(size.into()))?;
     writeln!(w, "pools = [")?;
     for (block, capacity) in layout {
         if *capacity == 0 {
@@ -45,10 +45,15 @@
 pub mod cli;
 pub mod cmd;
 pub mod color;
+pub mod crashlog;
+pub mod header;
 pub mod heap;
 pub mod openocd;
+pub mod report;
+pub mod stack;
 pub mod stream;
 pub mod templates;
+pub mod toolchain;
 
 use self::cli::{Cli, Cmd};
 use eyre::Result;
@@ -66,13 +71,16 @@ impl Cli {
         color_eyre::install()?;
         log_init(verbose, quiet)?;
         match cmd {
+            Cmd::Crashlog(cmd) => cmd::crashlog::run(cmd, color),
             Cmd::Debug(cmd) => cmd::debug::run(cmd, color),
             Cmd::Heap(_) => todo!(),
+            Cmd::Layout(cmd) => cmd::layout::run(cmd, color),
             Cmd::Load(cmd) => cmd::load::run(cmd, color),
             // Cmd::Heap(cmd) => cmd::heap::run(cmd, color),
             Cmd::Openocd(cmd) => cmd::openocd::run(cmd),
             Cmd::Probe(cmd) => cmd::probe::run(cmd),
             Cmd::Reset(cmd) => cmd::reset::run(cmd, color),
+            Cmd::Stack(cmd) => cmd::stack::run(cmd, color),
             Cmd::Stream(cmd) => cmd::stream::run(cmd, color),
         }
     }
@@ -1,7 +1,7 @@
 //! OpenOCD integration.
 
 use crate::color::Color;
-use crate::stream;
+use crate::{crashlog, header, stack, stream};
 use drone_config::locate_project_root;
 use drone_openocd::{
     adapter_quit, arm_cti_cleanup_all, command_context_mode, command_exit,
@@ -69,6 +69,18 @@ pub unsafe extern "C" fn openocd_main(argc: i32, argv: *mut *mut i8) -> i32 {
             return EXIT_FAILURE as i32;
         }
 
+        if stack::init(cmd_ctx) != ERROR_OK as i32 {
+            return EXIT_FAILURE as i32;
+        }
+
+        if crashlog::init(cmd_ctx) != ERROR_OK as i32 {
+            return EXIT_FAILURE as i32;
+        }
+
+        if header::init(cmd_ctx) != ERROR_OK as i32 {
+            return EXIT_FAILURE as i32;
+        }
+
         command_context_mode(cmd_ctx, command_mode_COMMAND_CONFIG);
         command_set_output_handler(cmd_ctx, Some(configuration_output_handler), ptr::null_mut());
 
@@ -93,7 +105,7 @@ pub unsafe extern "C" fn openocd_main(argc: i32, argv: *mut *mut i8) -> i32 {
 
         free_config();
 
-        if ret == ERROR_FAIL {
+        if ret == ERROR_FAIL || stream::has_failed() {
             return EXIT_FAILURE as i32;
         } else if ret != ERROR_OK as i32 {
             exit_on_signal(ret);
@@ -0,0 +1,371 @@
+//! Post-link memory usage report.
+
+use crate::toolchain::ElfSection;
+use drone_config::map::{MemoryMap, Placement, RegionKind, SectionKind};
+use drone_config::{size, Layout};
+use eyre::{bail, Result};
+use serde::{Deserialize, Serialize};
+use std::fmt::Write;
+use std::fs;
+use std::path::Path;
+use tracing::warn;
+
+/// The name of the memory usage report file inside the target directory.
+pub const REPORT_FILE: &str = "memory-report.json";
+
+/// The name of the memory usage report file of the build preceding the last
+/// one inside the target directory.
+pub const PREVIOUS_REPORT_FILE: &str = "memory-report.previous.json";
+
+/// Sections loaded into flash memory, which are shown in the report.
+pub const FLASH_SECTIONS: &[&str] = &["text", "rodata", "data"];
+
+const NON_ALLOC_SECTIONS: &[&str] = &["comment", "symtab", "strtab", "shstrtab"];
+
+/// Memory usage report of a linked image.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Report {
+    /// Usage of memory regions.
+    pub regions: Vec<Usage>,
+    /// Sizes of sections loaded into flash memory.
+    pub sections: Vec<Usage>,
+    /// Calculated sizes of stacks and heaps.
+    #[serde(default)]
+    pub allocations: Vec<Allocation>,
+    /// Sections of the linked image placed into flash regions.
+    #[serde(default)]
+    pub flash: Vec<Placement>,
+}
+
+/// Memory usage entry.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Usage {
+    /// Entry name.
+    pub name: String,
+    /// Used size.
+    pub used: u64,
+    /// Available size, if applicable.
+    pub size: Option<u64>,
+}
+
+/// Calculated size of a stack or heap.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Allocation {
+    /// Section name.
+    pub name: String,
+    /// Calculated size.
+    pub size: u64,
+    /// Minimum allowed size, if configured with `report.min-size`.
+    pub min_size: Option<u64>,
+}
+
+impl Allocation {
+    /// Returns the size left above the minimum allowed size, which is
+    /// negative if the size is below the minimum.
+    pub fn headroom(&self) -> Option<i128> {
+        self.min_size.map(|min_size| i128::from(self.size) - i128::from(min_size))
+    }
+}
+
+impl Report {
+    /// Creates a new report from the calculated `layout` and the `sections` of
+    /// the linked image.
+    pub fn new(layout: &Layout, sections: &[ElfSection]) -> Self {
+        let section_size =
+            |name: &str| sections.iter().find(|section| section.name == name).map_or(0, |s| s.size);
+        let mut loads = vec![(
+            layout.data.load_from.as_deref().unwrap_or(layout.program_flash()),
+            section_size("data"),
+        )];
+        for (name, data) in &layout.data.sections {
+            let load_from = data.load_from.as_deref().unwrap_or(layout.program_flash());
+            loads.push((load_from, section_size(&format!("data_{name}"))));
+        }
+        for (name, code) in &layout.code {
+            if code.ram.is_some() {
+                let load_from = code.load_from.as_deref().unwrap_or(layout.program_flash());
+                loads.push((load_from, section_size(&format!("code_{name}"))));
+            }
+        }
+        let map = MemoryMap::new(layout);
+        let regions = map
+            .regions
+            .iter()
+            .map(|region| {
+                let range = region.origin..region.origin + region.size;
+                let mut used = sections
+                    .iter()
+                    .filter(|section| section.size > 0 && is_alloc(section))
+                    .filter(|section| range.contains(&section.address))
+                    .map(|section| section.size)
+                    .sum::<u64>();
+                if region.kind == RegionKind::Flash {
+                    used += loads
+                        .iter()
+                        .filter(|(load_from, _)| *load_from == region.name)
+                        .map(|(_, size)| size)
+                        .sum::<u64>();
+                }
+                Usage {
+                    name: format!("{}.{}", region.kind.as_str(), region.name),
+                    used,
+                    size: Some(region.size),
+                }
+            })
+            .collect();
+        let flash_sections = FLASH_SECTIONS
+            .iter()
+            .map(|&name| Usage { name: format!(".{name}"), used: section_size(name), size: None })
+            .collect();
+        let min_size = layout.report.min_size;
+        let stacks = layout.stack.iter().map(|(name, stack)| Allocation {
+            name: format!("stack.{name}"),
+            size: stack.fixed_size,
+            min_size,
+        });
+        let heaps = layout.heap.iter().map(|(name, heap)| Allocation {
+            name: format!("heap.{name}"),
+            size: heap.section.fixed_size,
+            min_size,
+        });
+        let allocations = stacks.chain(heaps).collect();
+        let flash = sections
+            .iter()
+            .filter(|section| section.size > 0 && is_alloc(section))
+            .filter(|section| {
+                map.regions.iter().any(|region| {
+                    region.kind == RegionKind::Flash
+                        && (region.origin..region.origin + region.size).contains(&section.address)
+                })
+            })
+            .map(|section| Placement {
+                name: format!(".{}", section.name),
+                kind: SectionKind::Program,
+                origin: section.address,
+                size: section.size,
+            })
+            .collect();
+        Self { regions, sections: flash_sections, allocations, flash }
+    }
+
+    /// Reads a previously written report from `path`, if any.
+    pub fn read(path: &Path) -> Option<Self> {
+        let string = fs::read_to_string(path).ok()?;
+        match serde_json::from_str(&string) {
+            Ok(report) => Some(report),
+            Err(err) => {
+                warn!("Ignoring malformed {}: {err}", path.display());
+                None
+            }
+        }
+    }
+
+    /// Writes the report to `path`.
+    pub fn write(&self, path: &Path) -> Result<()> {
+        fs::write(path, serde_json::to_string_pretty(self)?)?;
+        Ok(())
+    }
+
+    /// Renders a human-readable report with deltas against the `previous`
+    /// report.
+    pub fn render(&self, previous: Option<&Self>) -> String {
+        let mut output = String::new();
+        let groups = [
+            ("Memory regions", &self.regions, previous.map(|p| &p.regions)),
+            ("Flash sections", &self.sections, previous.map(|p| &p.sections)),
+        ];
+        let width = groups
+            .iter()
+            .flat_map(|(_, usages, _)| usages.iter().map(|u| u.name.len()))
+            .chain(self.allocations.iter().map(|a| a.name.len()))
+            .max()
+            .unwrap_or(0);
+        for (title, usages, previous) in groups {
+            if usages.is_empty() {
+                continue;
+            }
+            writeln!(output, "{title}:").unwrap();
+            for usage in usages {
+                write!(output, "  {:width$} {:>8}", usage.name, size::to_string(usage.used))
+                    .unwrap();
+                if let Some(size) = usage.size {
+                    write!(
+                        output,
+                        " / {:>6} {:>7}",
+                        size::to_string(size),
+                        percent(usage.used, size)
+                    )
+                    .unwrap();
+                }
+                let previous = previous
+                    .and_then(|previous| previous.iter().find(|p| p.name == usage.name))
+                    .map(|previous| previous.used);
+                if let Some(previous) = previous {
+                    let delta = i128::from(usage.used) - i128::from(previous);
+                    if delta != 0 {
+                        write!(output, " ({delta:+})").unwrap();
+                    }
+                }
+                writeln!(output).unwrap();
+            }
+        }
+        if !self.allocations.is_empty() {
+            writeln!(output, "Stack and heap sizes:").unwrap();
+        }
+        for allocation in &self.allocations {
+            write!(output, "  {:width$} {:>8}", allocation.name, size::to_string(allocation.size))
+                .unwrap();
+            if let Some((min_size, headroom)) = allocation.min_size.zip(allocation.headroom()) {
+                let headroom = u64::try_from(headroom.unsigned_abs()).unwrap_or(u64::MAX);
+                let sign = if allocation.size < min_size { "-" } else { "" };
+                write!(
+                    output,
+                    " / min {:>6}, headroom {sign}{}",
+                    size::to_string(min_size),
+                    size::to_string(headroom)
+                )
+                .unwrap();
+            }
+            let previous = previous
+                .and_then(|previous| previous.allocations.iter().find(|p| p.name == allocation.name))
+                .map(|previous| previous.size);
+            if let Some(previous) = previous {
+                let delta = i128::from(allocation.size) - i128::from(previous);
+                if delta != 0 {
+                    write!(output, " ({delta:+})").unwrap();
+                }
+            }
+            writeln!(output).unwrap();
+        }
+        output
+    }
+
+    /// Returns `Err` if any of the `layout` report thresholds is exceeded.
+    pub fn check(&self, layout: &Layout) -> Result<()> {
+        let mut errors = Vec::new();
+        for usage in &self.regions {
+            let threshold = if usage.name.starts_with("flash.") {
+                layout.report.flash_threshold
+            } else {
+                layout.report.ram_threshold
+            };
+            if let Some((threshold, size)) = threshold.zip(usage.size) {
+                if usage.used as f64 * 100.0 > f64::from(threshold) * size as f64 {
+                    errors.push(format!(
+                        "{} usage {} exceeds the threshold of {threshold}%",
+                        usage.name,
+                        percent(usage.used, size)
+                    ));
+                }
+            }
+        }
+        if let Some(min_size) = layout.report.min_size {
+            for allocation in &self.allocations {
+                if allocation.size < min_size {
+                    errors.push(format!(
+                        "{} size {} is less than the minimum size of {}",
+                        allocation.name,
+                        size::to_string(allocation.size),
+                        size::to_string(min_size)
+                    ));
+                }
+            }
+        }
+        if !errors.is_empty() {
+            bail!("memory usage report thresholds exceeded:\n  {}", errors.join("\n  "));
+        }
+        Ok(())
+    }
+}
+
+fn is_alloc(section: &ElfSection) -> bool {
+    !section.name.starts_with("debug")
+        && !section.name.starts_with("ARM.")
+        && !NON_ALLOC_SECTIONS.contains(&section.name.as_str())
+}
+
+/// Formats `value` as a percentage of `total`, or `-` if `total` is zero.
+pub fn percent(value: u64, total: u64) -> String {
+    if total == 0 {
+        return "-".into();
+    }
+    format!("{:.2}%", value as f64 * 100.0 / total as f64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn usage(name: &str, used: u64, size: Option<u64>) -> Usage {
+        Usage { name: name.into(), used, size }
+    }
+
+    fn allocation(name: &str, size: u64, min_size: Option<u64>) -> Allocation {
+        Allocation { name: name.into(), size, min_size }
+    }
+
+    fn report(ram_used: u64, stack_size: u64) -> Report {
+        Report {
+            regions: vec![
+                usage("flash.program", 1024, Some(4096)),
+                usage("ram.main", ram_used, Some(4096)),
+            ],
+            sections: vec![usage(".text", 512, None)],
+            allocations: vec![
+                allocation("stack.core0", stack_size, Some(2048)),
+                allocation("heap.main", 1024, Some(2048)),
+            ],
+            flash: Vec::new(),
+        }
+    }
+
+    fn layout(report: &str) -> Layout {
+        Layout::parse(&format!(
+            r#"
+[ram]
+main = {{ origin = 0x20000000, size = "4K" }}
+[data]
+ram = "main"
+[report]
+{report}
+"#
+        ))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_render() {
+        let previous = report(2048, 3072);
+        assert_eq!(
+            report(3072, 4096).render(Some(&previous)),
+            concat!(
+                "Memory regions:\n",
+                "  flash.program       1K /     4K  25.00%\n",
+                "  ram.main            3K /     4K  75.00% (+1024)\n",
+                "Flash sections:\n",
+                "  .text              512\n",
+                "Stack and heap sizes:\n",
+                "  stack.core0         4K / min     2K, headroom 2K (+1024)\n",
+                "  heap.main           1K / min     2K, headroom -1K\n",
+            )
+        );
+    }
+
+    #[test]
+    fn test_check() {
+        let report = report(3072, 4096);
+        report.check(&layout("ram-threshold = 75.0")).unwrap();
+        let err = report.check(&layout("ram-threshold = 70.0\nflash-threshold = 25.0"));
+        assert_eq!(
+            err.unwrap_err().to_string(),
+            "memory usage report thresholds exceeded:\n  ram.main usage 75.00% exceeds the \
+             threshold of 70%"
+        );
+        let err = report.check(&layout("min-size = \"2K\""));
+        assert_eq!(
+            err.unwrap_err().to_string(),
+            "memory usage report thresholds exceeded:\n  heap.main size 1K is less than the \
+             minimum size of 2K"
+        );
+    }
+}
@@ -0,0 +1,225 @@
+//! Stack painting and high-water mark measurement.
+
+use crate::stream::runtime::{self, result_from, result_into};
+use drone_config::layout::Section;
+use drone_config::{addr, locate_project_root, size, Layout};
+use drone_openocd::{
+    command_context, command_invocation, command_mode_COMMAND_EXEC, command_registration,
+    get_current_target, register_commands, target, target_read_buffer, target_write_buffer,
+    COMMAND_REGISTRATION_DONE, ERROR_FAIL,
+};
+use std::ffi::CString;
+use std::os::raw::c_int;
+use std::ptr;
+use tracing::{error, info, warn};
+
+/// Byte pattern the stacks are painted with. Firmware painting its own stacks
+/// must use the same pattern.
+pub const PAINT_PATTERN: u8 = 0xCD;
+
+/// Granularity of the suggested stack sizes.
+pub const SIZE_GRANULARITY: u64 = 256;
+
+/// Stack usage measured from the target memory.
+#[derive(Debug)]
+pub struct StackUsage {
+    /// Stack name.
+    pub name: String,
+    /// Maximum depth reached by the stack.
+    pub used: u64,
+    /// Current stack size.
+    pub size: u64,
+}
+
+impl StackUsage {
+    /// Measures the stack usage from the `buffer` read from the target memory.
+    /// The stack grows downwards, so the first overwritten byte from the
+    /// beginning of the buffer marks the high-water mark.
+    pub fn measure(name: String, buffer: &[u8]) -> Self {
+        let untouched = buffer.iter().take_while(|&&byte| byte == PAINT_PATTERN).count();
+        Self { name, used: (buffer.len() - untouched) as u64, size: buffer.len() as u64 }
+    }
+
+    /// Returns the suggested stack size, which keeps a quarter of the
+    /// measured depth as a safety margin.
+    pub fn suggested_size(&self) -> u64 {
+        (self.used + self.used / 4).div_ceil(SIZE_GRANULARITY).max(1) * SIZE_GRANULARITY
+    }
+}
+
+/// Initializes Drone Stack commands.
+///
+/// # Safety
+///
+/// `ctx` must be a valid pointer to the OpenOCD command context.
+pub unsafe fn init(ctx: *mut command_context) -> c_int {
+    let drone_stack_subcommand_handlers = Box::leak(Box::new([
+        command_registration {
+            name: CString::new("paint").unwrap().into_raw(),
+            handler: Some(handle_drone_stack_paint_command),
+            mode: command_mode_COMMAND_EXEC,
+            help: CString::new("fill the stacks with the known pattern").unwrap().into_raw(),
+            usage: CString::new("").unwrap().into_raw(),
+            chain: ptr::null_mut(),
+            jim_handler: None,
+        },
+        command_registration {
+            name: CString::new("report").unwrap().into_raw(),
+            handler: Some(handle_drone_stack_report_command),
+            mode: command_mode_COMMAND_EXEC,
+            help: CString::new("report the maximum depth of the painted stacks")
+                .unwrap()
+                .into_raw(),
+            usage: CString::new("").unwrap().into_raw(),
+            chain: ptr::null_mut(),
+            jim_handler: None,
+        },
+        unsafe { COMMAND_REGISTRATION_DONE },
+    ]));
+    let drone_stack_command_handlers = Box::leak(Box::new([
+        command_registration {
+            name: CString::new("drone_stack").unwrap().into_raw(),
+            handler: None,
+            mode: command_mode_COMMAND_EXEC,
+            help: CString::new("Drone Stack commands").unwrap().into_raw(),
+            usage: CString::new("").unwrap().into_raw(),
+            chain: drone_stack_subcommand_handlers.as_ptr(),
+            jim_handler: None,
+        },
+        unsafe { COMMAND_REGISTRATION_DONE },
+    ]));
+    unsafe { register_commands(ctx, ptr::null_mut(), drone_stack_command_handlers.as_ptr()) }
+}
+
+unsafe extern "C" fn handle_drone_stack_paint_command(cmd: *mut command_invocation) -> c_int {
+    unsafe { run_command(cmd, paint) }
+}
+
+unsafe extern "C" fn handle_drone_stack_report_command(cmd: *mut command_invocation) -> c_int {
+    unsafe { run_command(cmd, report) }
+}
+
+unsafe fn run_command(
+    cmd: *mut command_invocation,
+    f: unsafe fn(*mut target, &Layout) -> runtime::Result<()>,
+) -> c_int {
+    if unsafe { (*cmd).argc } > 0 {
+        error!("`drone_stack` subcommands take no arguments");
+        return ERROR_FAIL;
+    }
+    let layout = match locate_project_root().and_then(|root| Layout::read_last_built(&root)) {
+        Ok(layout) => layout,
+        Err(err) => {
+            error!("Couldn't read the memory layout: {err:#?}");
+            return ERROR_FAIL;
+        }
+    };
+    if layout.stack.is_empty() {
+        error!("No stacks are defined in the memory layout");
+        return ERROR_FAIL;
+    }
+    result_into(unsafe { f(get_current_target((*cmd).ctx), &layout) })
+}
+
+unsafe fn paint(target: *mut target, layout: &Layout) -> runtime::Result<()> {
+    for (name, stack) in &layout.stack {
+        let size = stack_size(name, stack)?;
+        let origin = stack.origin + stack.prefix_size;
+        let buffer = vec![PAINT_PATTERN; size as usize];
+        unsafe {
+            result_from(target_write_buffer(target, origin, size, buffer.as_ptr()))?;
+        }
+        info!(
+            "Painted stack.{name} at {}..{}",
+            addr::to_string(origin),
+            addr::to_string(origin + stack.fixed_size)
+        );
+    }
+    Ok(())
+}
+
+unsafe fn report(target: *mut target, layout: &Layout) -> runtime::Result<()> {
+    let mut usages = Vec::new();
+    for (name, stack) in &layout.stack {
+        let size = stack_size(name, stack)?;
+        let origin = stack.origin + stack.prefix_size;
+        let mut buffer = vec![0; size as usize];
+        unsafe {
+            result_from(target_read_buffer(target, origin, size, buffer.as_mut_ptr()))?;
+        }
+        usages.push(StackUsage::measure(format!("stack.{name}"), &buffer));
+    }
+    let width = usages.iter().map(|usage| usage.name.len()).max().unwrap_or(0);
+    for usage in &usages {
+        let percent = usage.used as f64 * 100.0 / usage.size as f64;
+        println!(
+            "{:width$} {:>8} / {:>6} {:>6.2}%  suggested size: {}",
+            usage.name,
+            size::to_string(usage.used),
+            size::to_string(usage.size),
+            percent,
+            size::to_string(usage.suggested_size()),
+        );
+        if usage.used == usage.size {
+            warn!(
+                "{} is fully used: either it has overflowed or it wasn't painted; run `drone \
+                 stack paint` before running the firmware",
+                usage.name
+            );
+        }
+    }
+    Ok(())
+}
+
+fn stack_size(name: &str, stack: &Section) -> runtime::Result<u32> {
+    u32::try_from(stack.fixed_size).map_err(|_| {
+        error!("stack.{name} is too large to access ({})", size::to_string(stack.fixed_size));
+        runtime::Error::Fail
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn measure(size: usize, used: usize) -> StackUsage {
+        let mut buffer = vec![PAINT_PATTERN; size];
+        buffer[size - used..].fill(0);
+        StackUsage::measure("main".into(), &buffer)
+    }
+
+    #[test]
+    fn test_fully_painted() {
+        let usage = measure(1024, 0);
+        assert_eq!(usage.used, 0);
+        assert_eq!(usage.size, 1024);
+        assert_eq!(usage.suggested_size(), 256);
+    }
+
+    #[test]
+    fn test_fully_used() {
+        let usage = measure(1024, 1024);
+        assert_eq!(usage.used, 1024);
+        assert_eq!(usage.size, 1024);
+        assert_eq!(usage.suggested_size(), 1280);
+    }
+
+    #[test]
+    fn test_partial_watermark() {
+        let mut buffer = vec![PAINT_PATTERN; 1024];
+        buffer[600] = 0;
+        buffer[900] = PAINT_PATTERN;
+        let usage = StackUsage::measure("main".into(), &buffer);
+        assert_eq!(usage.used, 424);
+        assert_eq!(usage.size, 1024);
+        assert_eq!(usage.suggested_size(), 768);
+    }
+
+    #[test]
+    fn test_suggested_size_rounding() {
+        assert_eq!(measure(2048, 1024).suggested_size(), 1280);
+        assert_eq!(measure(2048, 1025).suggested_size(), 1536);
+        assert_eq!(measure(2048, 205).suggested_size(), 256);
+        assert_eq!(measure(2048, 206).suggested_size(), 512);
+    }
+}
@@ -0,0 +1,139 @@
+//! Drone Stream memory map.
+//!
+//! Locates Drone Stream data structures in the target memory either from the
+//! symbols of the application image, or from the memory layout.
+
+use crate::toolchain::{built_binaries, cargo_target_dir, read_symbols};
+use drone_config::{locate_project_root, Layout, LAYOUT_CONFIG};
+use eyre::{bail, eyre, Result};
+use heck::ToShoutySnakeCase;
+use std::path::{Path, PathBuf};
+use tracing::{debug, warn};
+
+/// Location of Drone Stream data structures in the target memory.
+#[derive(Debug)]
+pub struct StreamMap {
+    /// Address of the global runtime.
+    pub global_address: u64,
+    /// Stream buffers.
+    pub streams: Vec<StreamBuffer>,
+}
+
+/// Location of a single stream buffer.
+#[derive(Debug)]
+pub struct StreamBuffer {
+    /// Stream name.
+    pub name: String,
+    /// Whether this stream is the primary initializer.
+    pub init_primary: bool,
+    /// Address of the buffer. The stream runtime immediately precedes it.
+    pub address: u64,
+    /// Size of the buffer, limited to 32 bits by the stream runtime.
+    pub size: u32,
+}
+
+impl StreamMap {
+    /// Locates Drone Stream data structures. The addresses are read from the
+    /// symbols of `elf`, or if not given, of the application binary last built
+    /// by cargo. Only if there is no such binary, the layout calculated during
+    /// the last build is used, falling back to calculating the layout from the
+    /// project's `layout.toml`.
+    pub fn locate(elf: Option<&Path>) -> Result<Self> {
+        let layout = locate_project_root().and_then(|root| Layout::read_last_built(&root));
+        if let Some(elf) = elf {
+            return Self::from_elf(elf, layout.as_ref().ok());
+        }
+        if let Some(elf) = last_built_binary(layout.as_ref().ok()) {
+            match Self::from_elf(&elf, layout.as_ref().ok()) {
+                Ok(map) => {
+                    debug!("Drone Stream buffers are located from {}", elf.display());
+                    return Ok(map);
+                }
+                Err(err) => warn!("{err}; locating Drone Stream buffers from the layout"),
+            }
+        }
+        Self::from_layout(&layout?)
+    }
+
+    /// Creates a new map from the calculated `layout`.
+    pub fn from_layout(layout: &Layout) -> Result<Self> {
+        let stream = match &layout.stream {
+            Some(stream) if !stream.sections.is_empty() => stream,
+            _ => bail!("no streams are defined in the layout config"),
+        };
+        let streams = stream
+            .sections
+            .iter()
+            .map(|(name, stream)| {
+                Ok(StreamBuffer {
+                    name: name.clone(),
+                    init_primary: stream.init_primary.unwrap_or(false),
+                    address: stream.origin + stream.prefix_size,
+                    size: buffer_size(name, stream.size)?,
+                })
+            })
+            .collect::<Result<_>>()?;
+        Ok(Self { global_address: stream.origin, streams })
+    }
+
+    /// Creates a new map from the symbols of the `elf` file. The optional
+    /// `layout` is used to determine the primary initializer.
+    pub fn from_elf(elf: &Path, layout: Option<&Layout>) -> Result<Self> {
+        let symbols = read_symbols(elf)?;
+        let global_address = *symbols
+            .get("STREAM_RT_BASE")
+            .ok_or_else(|| eyre!("{} has no Drone Stream global runtime", elf.display()))?;
+        let mut streams = Vec::new();
+        for (symbol, &address) in &symbols {
+            let Some(name) =
+                symbol.strip_prefix("STREAM_").and_then(|symbol| symbol.strip_suffix("_BUF_BASE"))
+            else {
+                continue;
+            };
+            let end = symbols
+                .get(&format!("STREAM_{name}_BUF_END"))
+                .ok_or_else(|| eyre!("STREAM_{name}_BUF_END symbol is missing"))?;
+            let size = end.checked_sub(address).ok_or_else(|| {
+                eyre!("STREAM_{name}_BUF_END is less than STREAM_{name}_BUF_BASE")
+            })?;
+            let section = layout.and_then(|layout| layout.stream.as_ref()).and_then(|stream| {
+                stream.sections.iter().find(|(key, _)| key.to_shouty_snake_case() == name)
+            });
+            let (name, init_primary) = match section {
+                Some((key, section)) => (key.clone(), section.init_primary.unwrap_or(false)),
+                None => (name.to_lowercase(), false),
+            };
+            let size = buffer_size(&name, size)?;
+            streams.push(StreamBuffer { name, init_primary, address, size });
+        }
+        if streams.is_empty() {
+            bail!("{} has no Drone Stream buffers", elf.display());
+        }
+        streams.sort_by_key(|stream| stream.address);
+        if let [stream] = streams.as_mut_slice() {
+            stream.init_primary = true;
+        } else if !streams.iter().any(|stream| stream.init_primary) {
+            warn!(
+                "Couldn't determine the primary Drone Stream initializer without {LAYOUT_CONFIG}; \
+                 the global runtime won't be bootstrapped"
+            );
+        }
+        Ok(Self { global_address, streams })
+    }
+}
+
+fn last_built_binary(layout: Option<&Layout>) -> Option<PathBuf> {
+    let binary = layout.and_then(|layout| {
+        let name = layout.selected_image.as_ref()?;
+        Some(layout.image[name].binary.clone().unwrap_or_else(|| name.clone()))
+    });
+    let target = cargo_target_dir(&locate_project_root().ok()?).ok()?;
+    built_binaries(&target, binary.as_deref(), None)
+        .ok()?
+        .into_iter()
+        .max_by_key(|path| path.metadata().and_then(|metadata| metadata.modified()).ok())
+}
+
+fn buffer_size(name: &str, size: u64) -> Result<u32> {
+    u32::try_from(size).map_err(|_| eyre!("stream.{name} buffer is larger than 4G ({size} bytes)"))
+}
@@ -1,46 +1,86 @@
 //! Drone Stream.
 
+pub mod map;
 pub mod route;
 pub mod runtime;
 
+use self::map::StreamMap;
 use self::route::{RouteDesc, Routes};
 use self::runtime::{RemoteGlobalRuntime, RemoteRuntime};
-use drone_config::{locate_project_root, Layout};
+use drone_config::addr;
 use drone_openocd::{
     command_context, command_invocation, command_mode_COMMAND_EXEC, command_registration,
-    command_run_line, get_current_target, register_commands, target,
-    target_register_timer_callback, target_timer_type_TARGET_TIMER_TYPE_PERIODIC,
+    command_run_line, get_current_target, register_commands, target, target_event,
+    target_event_TARGET_EVENT_RESET_END, target_register_event_callback,
+    target_register_timer_callback, target_state_TARGET_HALTED,
+    target_timer_type_TARGET_TIMER_TYPE_PERIODIC, target_unregister_event_callback,
     target_unregister_timer_callback, COMMAND_REGISTRATION_DONE, ERROR_FAIL, ERROR_OK,
 };
-use drone_stream::{GlobalRuntime, Runtime, HEADER_LENGTH, STREAM_COUNT};
+use drone_stream::compress::decompress;
+use drone_stream::{
+    GlobalRuntime, Runtime, CAPABILITY_COMPRESSION, COMPRESSED_FLAG, HEADER_LENGTH, MAGIC,
+    MAX_TRANSACTION_LENGTH, PROTOCOL_VERSION, STREAM_COUNT,
+};
+use eyre::eyre;
 use libc::c_void;
-use std::ffi::{CStr, CString};
+use std::ffi::{CStr, CString, OsStr};
 use std::iter::FusedIterator;
 use std::os::raw::c_int;
-use std::sync::atomic::{AtomicPtr, Ordering};
-use std::time::Duration;
+use std::os::unix::prelude::*;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, AtomicPtr, Ordering};
+use std::time::{Duration, Instant};
 use std::{ptr, slice};
-use tracing::{error, trace, warn};
+use tracing::{debug, error, info, trace, warn};
 
 const POLLING_INTERVAL: Duration = Duration::from_millis(50);
 
+const MAX_RETRY_INTERVAL: Duration = Duration::from_secs(5);
+
+const INIT_TIMEOUT: Duration = Duration::from_secs(5);
+
 const OVERRIDABLE_PROCS: &[&str] =
     &["before_drone_stream", "before_drone_stream_reset", "before_drone_stream_run"];
 
 static CONTEXT_PTR: AtomicPtr<Context> = AtomicPtr::new(ptr::null_mut());
 
+static FAILED: AtomicBool = AtomicBool::new(false);
+
 struct Context {
     target: *mut target,
-    global_address: u32,
+    ctx: *mut command_context,
+    global_address: u64,
     global_runtime: GlobalRuntime,
     streams: Vec<Stream>,
     routes: Routes,
+    link: Link,
+    legacy: bool,
+    failed: bool,
+    init_deadline: Instant,
+    retry: Option<Retry>,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Link {
+    /// The bootstrap sequence is written, and the application picks it up on
+    /// start. The output is captured from the beginning.
+    Bootstrapped,
+    /// The application starts or runs without the bootstrap sequence. The
+    /// output is captured from the moment of attaching.
+    Detached,
+    /// The probe is attached to the application runtime.
+    Attached,
+}
+
+struct Retry {
+    interval: Duration,
+    deadline: Instant,
 }
 
 struct Stream {
     name: String,
     init_primary: bool,
-    address: u32,
+    address: u64,
     runtime: Runtime,
     buffer: Vec<u8>,
 }
@@ -48,64 +88,81 @@ struct Stream {
 unsafe impl Send for Context {}
 
 impl Context {
-    fn new(target: *mut target, route_descs: Vec<RouteDesc>) -> Option<Self> {
-        let project_root = match locate_project_root() {
-            Ok(project_root) => project_root,
+    fn new(
+        target: *mut target,
+        ctx: *mut command_context,
+        route_descs: Vec<RouteDesc>,
+        elf: Option<PathBuf>,
+    ) -> Option<Self> {
+        let map = match StreamMap::locate(elf.as_deref()) {
+            Ok(map) => map,
             Err(err) => {
-                error!("Couldn't locate project root: {err:#?}");
+                error!("Couldn't locate Drone Stream in the target memory: {err:#?}");
                 return None;
             }
         };
-        match Layout::read_from_project_root(&project_root) {
-            Ok(Layout { ref stream, .. })
-                if !stream.as_ref().map_or(true, |stream| stream.sections.is_empty()) =>
-            {
-                match Routes::open_all(&route_descs) {
-                    Ok(routes) => {
-                        let global_address = stream.as_ref().unwrap().origin;
-                        let global_runtime =
-                            GlobalRuntime::from_enable_mask(make_enable_mask(&route_descs));
-                        let streams = stream
-                            .as_ref()
-                            .unwrap()
-                            .sections
-                            .iter()
-                            .map(|(name, stream)| Stream {
-                                name: name.clone(),
-                                init_primary: stream.init_primary.unwrap_or(false),
-                                address: stream.origin + stream.prefix_size,
-                                runtime: Runtime::from_buffer_size(stream.size),
-                                buffer: vec![0; stream.size as usize],
-                            })
-                            .collect();
-                        return Some(Self {
-                            target,
-                            global_address,
-                            global_runtime,
-                            streams,
-                            routes,
-                        });
-                    }
-                    Err(err) => {
-                        error!("Couldn't open Drone Stream output: {err:#?}");
-                    }
-                }
-            }
-            Ok(_) => {
-                error!("no streams are defined in the layout config");
-            }
+        let routes = match Routes::open_all(&route_descs) {
+            Ok(routes) => routes,
             Err(err) => {
-                error!("{err:#?}");
+                error!("Couldn't open Drone Stream output: {err:#?}");
+                return None;
             }
-        }
-        None
+        };
+        let mut global_runtime = GlobalRuntime::from_enable_mask(make_enable_mask(&route_descs));
+        global_runtime.capabilities = CAPABILITY_COMPRESSION;
+        let streams = map
+            .streams
+            .into_iter()
+            .map(|stream| Stream {
+                name: stream.name,
+                init_primary: stream.init_primary,
+                address: stream.address,
+                runtime: Runtime::from_buffer_size(stream.size),
+                buffer: vec![0; stream.size as usize],
+            })
+            .collect();
+        Some(Self {
+            target,
+            ctx,
+            global_address: map.global_address,
+            global_runtime,
+            streams,
+            routes,
+            link: Link::Detached,
+            legacy: false,
+            failed: false,
+            init_deadline: Instant::now(),
+            retry: None,
+        })
     }
 
     fn start_reset(&mut self, ctx: *mut command_context) -> runtime::Result<()> {
         unsafe {
             let line = CString::new("before_drone_stream_reset").unwrap().into_raw();
             runtime::result_from(command_run_line(ctx, line))?;
-            for stream in &self.streams {
+        }
+        self.bootstrap()
+    }
+
+    fn start_run(&mut self, ctx: *mut command_context) -> runtime::Result<()> {
+        unsafe {
+            let line = CString::new("before_drone_stream_run").unwrap().into_raw();
+            runtime::result_from(command_run_line(ctx, line))?;
+        }
+        self.detach(Link::Detached);
+        // The application is expected to be running already.
+        self.init_deadline = Instant::now();
+        self.attach()?;
+        if self.failed {
+            return Err(runtime::Error::Fail);
+        }
+        Ok(())
+    }
+
+    fn bootstrap(&mut self) -> runtime::Result<()> {
+        self.detach(Link::Bootstrapped);
+        for stream in &self.streams {
+            unsafe {
                 stream.runtime.target_write_bootstrap(
                     self.target,
                     stream.address,
@@ -116,20 +173,116 @@ impl Context {
         Ok(())
     }
 
-    fn start_run(&mut self, ctx: *mut command_context) -> runtime::Result<()> {
-        unsafe {
-            let line = CString::new("before_drone_stream_run").unwrap().into_raw();
-            runtime::result_from(command_run_line(ctx, line))?;
+    fn detach(&mut self, link: Link) {
+        self.link = link;
+        self.init_deadline = Instant::now() + INIT_TIMEOUT;
+        if link == Link::Bootstrapped {
             for stream in &mut self.streams {
-                stream.runtime.target_read_write_cursor(self.target, stream.address)?;
-                stream.runtime.read_cursor = stream.runtime.write_cursor;
-                stream.runtime.target_write_read_cursor(self.target, stream.address)?;
+                stream.runtime = Runtime::from_buffer_size(stream.runtime.buffer_size);
+            }
+        }
+    }
+
+    fn attach(&mut self) -> runtime::Result<bool> {
+        if self.link == Link::Bootstrapped {
+            for stream in &self.streams {
+                if unsafe { Runtime::target_bootstrap_pending(self.target, stream.address)? } {
+                    // The application hasn't picked up the bootstrap sequence yet.
+                    return Ok(false);
+                }
+            }
+        }
+        let remote = unsafe { GlobalRuntime::target_read(self.target, self.global_address)? };
+        if remote.magic != MAGIC {
+            if is_halted(self.target) {
+                self.init_deadline = Instant::now() + INIT_TIMEOUT;
+            }
+            if Instant::now() < self.init_deadline {
+                // The application hasn't initialized the runtime yet.
+                return Ok(false);
+            }
+        }
+        if !self.verify(&remote) {
+            self.failed = true;
+            return Ok(false);
+        }
+        unsafe {
+            if self.link == Link::Detached {
+                for stream in &mut self.streams {
+                    stream.runtime.target_read_write_cursor(self.target, stream.address)?;
+                    stream.runtime.read_cursor = stream.runtime.write_cursor;
+                    stream.runtime.target_write_read_cursor(self.target, stream.address)?;
+                }
             }
+            self.negotiate(&remote)?;
             self.global_runtime.target_write_enable_mask(self.target, self.global_address)?;
         }
+        debug!("Drone Stream attached to the application runtime");
+        self.link = Link::Attached;
+        Ok(true)
+    }
+
+    fn handle_reset(&mut self) -> runtime::Result<()> {
+        if is_halted(self.target) {
+            debug!("Target reset into halted state, writing Drone Stream bootstrap sequence");
+            self.bootstrap()
+        } else {
+            warn!(
+                "Target reset into running state, Drone Stream output is captured only after the \
+                 application initializes the runtime"
+            );
+            self.detach(Link::Detached);
+            Ok(())
+        }
+    }
+
+    fn verify(&mut self, remote: &GlobalRuntime) -> bool {
+        self.legacy = remote.magic != MAGIC;
+        if self.legacy {
+            warn!(
+                "Drone Stream protocol header is not found at {}: the application is built \
+                 against an older version of `drone-stream`; falling back to the legacy protocol \
+                 without compression",
+                addr::to_string(self.global_address)
+            );
+            return true;
+        }
+        if remote.version != PROTOCOL_VERSION {
+            error!(
+                "Drone Stream protocol version mismatch: the application uses version {}, while \
+                 this utility supports version {PROTOCOL_VERSION}; rebuild the application \
+                 against `drone-stream` {}",
+                remote.version,
+                env!("CARGO_PKG_VERSION")
+            );
+            return false;
+        }
+        debug!(
+            "Drone Stream protocol version {}, application features {:#x}, probe capabilities \
+             {:#x}",
+            remote.version, remote.features, self.global_runtime.capabilities
+        );
+        true
+    }
+
+    fn negotiate(&self, remote: &GlobalRuntime) -> runtime::Result<()> {
+        if !self.legacy && remote.features & self.global_runtime.capabilities != 0 {
+            unsafe {
+                self.global_runtime.target_write_capabilities(self.target, self.global_address)?;
+            }
+        }
         Ok(())
     }
 
+    fn shutdown(&self) {
+        error!("Drone Stream has stopped capturing");
+        FAILED.store(true, Ordering::SeqCst);
+        unsafe {
+            let line = CString::new("shutdown error").unwrap().into_raw();
+            command_run_line(self.ctx, line);
+        }
+    }
+
     fn stop(&mut self) -> runtime::Result<()> {
         unsafe {
             self.global_runtime.enable_mask = 0;
@@ -139,55 +292,130 @@ impl Context {
     }
 
     fn poll(&mut self) -> runtime::Result<()> {
+        if self.failed {
+            return Ok(());
+        }
+        if self.retry.as_ref().is_some_and(|retry| Instant::now() < retry.deadline) {
+            return Ok(());
+        }
+        match self.try_poll() {
+            Ok(()) => {
+                if self.retry.take().is_some() {
+                    info!("Drone Stream connection to the target restored");
+                }
+                if self.failed {
+                    self.shutdown();
+                }
+            }
+            Err(err) => {
+                let interval = if let Some(retry) = &self.retry {
+                    debug!("Drone Stream is still disconnected from the target: {err:?}");
+                    (retry.interval * 2).min(MAX_RETRY_INTERVAL)
+                } else {
+                    warn!("Drone Stream lost connection to the target: {err:?}; retrying");
+                    POLLING_INTERVAL
+                };
+                self.retry = Some(Retry { interval, deadline: Instant::now() + interval });
+            }
+        }
+        Ok(())
+    }
+
+    fn try_poll(&mut self) -> runtime::Result<()> {
+        if self.link != Link::Attached && !self.attach()? {
+            return Ok(());
+        }
+        let mut reset = None;
         for stream_context in &mut self.streams {
-            let (mut buffer, mut wrap_point) = unsafe {
+            let remote = unsafe { Runtime::target_read(self.target, stream_context.address)? };
+            if remote.buffer_size != stream_context.runtime.buffer_size
+                || remote.read_cursor != stream_context.runtime.read_cursor
+                || remote.write_cursor > remote.buffer_size
+            {
+                let address = stream_context.address;
+                if unsafe { Runtime::target_bootstrap_pending(self.target, address)? } {
+                    debug!("Bootstrap sequence found in `{}` stream buffer", stream_context.name);
+                    reset = Some(Link::Bootstrapped);
+                } else {
+                    warn!(
+                        "Target reset detected: `{}` stream runtime is out of sync",
+                        stream_context.name
+                    );
+                    reset = Some(Link::Detached);
+                }
+                break;
+            }
+            stream_context.runtime.write_cursor = remote.write_cursor;
+            let (buffer, wrap_point) = unsafe {
                 stream_context.runtime.target_consume_buffer(
                     self.target,
                     stream_context.address,
                     &mut stream_context.buffer,
                 )?
             };
-            while !buffer.is_empty() {
-                let stream = buffer[0];
-                if stream == 0xFF {
-                    if let Some(wrap_point) = wrap_point.take() {
-                        buffer = &mut buffer[wrap_point..];
-                        continue;
-                    }
-                    warn!("Drone Stream encoding error: invalid header format");
-                    break;
-                }
-                if buffer.len() < HEADER_LENGTH as usize {
-                    warn!("Drone Stream encoding error: chunk is too short");
-                    break;
-                }
-                if stream >= STREAM_COUNT {
-                    warn!("Drone Stream encoding error: invalid stream number");
-                    break;
-                }
-                let length = buffer[1];
-                let range = HEADER_LENGTH as usize..usize::from(length) + HEADER_LENGTH as usize;
-                let data = if let Some(data) = buffer.get(range) {
-                    data
-                } else {
-                    warn!("Drone Stream encoding error: invalid length");
-                    break;
-                };
-                trace!("Transaction {}:{} -> {:?}", stream_context.name, stream, data);
-                if let Err(err) = self.routes.write(stream, data) {
-                    error!("Couldn't write to Drone Stream output: {err:#?}");
-                }
-                let shift = usize::from(length) + HEADER_LENGTH as usize;
-                buffer = &mut buffer[shift..];
-                if let Some(wrap_point) = &mut wrap_point {
-                    *wrap_point -= shift;
-                }
-            }
+            decode(&self.routes, &stream_context.name, buffer, wrap_point);
+        }
+        if let Some(link) = reset {
+            self.detach(link);
         }
         Ok(())
     }
 }
 
+fn decode(routes: &Routes, name: &str, mut buffer: &mut [u8], mut wrap_point: Option<usize>) {
+    let mut decompressed = [0; MAX_TRANSACTION_LENGTH as usize];
+    while !buffer.is_empty() {
+        let mut stream = buffer[0];
+        if stream == 0xFF {
+            if let Some(wrap_point) = wrap_point.take() {
+                buffer = &mut buffer[wrap_point..];
+                continue;
+            }
+            warn!("Drone Stream encoding error: invalid header format");
+            break;
+        }
+        if buffer.len() < HEADER_LENGTH as usize {
+            warn!("Drone Stream encoding error: chunk is too short");
+            break;
+        }
+        let compressed = stream & COMPRESSED_FLAG != 0;
+        stream &= !COMPRESSED_FLAG;
+        if stream >= STREAM_COUNT {
+            warn!("Drone Stream encoding error: invalid stream number");
+            break;
+        }
+        let length = buffer[1];
+        let range = HEADER_LENGTH as usize..usize::from(length) + HEADER_LENGTH as usize;
+        let Some(mut data) = buffer.get(range) else {
+            warn!("Drone Stream encoding error: invalid length");
+            break;
+        };
+        if compressed {
+            if let Some(length) = decompress(data, &mut decompressed) {
+                data = &decompressed[..length];
+            } else {
+                warn!("Drone Stream encoding error: invalid compressed payload");
+                break;
+            }
+        }
+        trace!("Transaction {}:{} -> {:?}", name, stream, data);
+        if let Err(err) = routes.write(name, stream, data) {
+            error!("Couldn't write to Drone Stream output: {err:#?}");
+        }
+        let shift = usize::from(length) + HEADER_LENGTH as usize;
+        buffer = &mut buffer[shift..];
+        if let Some(wrap_point) = &mut wrap_point {
+            *wrap_point -= shift;
+        }
+    }
+}
+
+/// Returns `true` if Drone Stream has stopped capturing because of an error.
+/// OpenOCD should exit with a failure status in this case.
+pub fn has_failed() -> bool {
+    FAILED.load(Ordering::SeqCst)
+}
+
 /// Initializes Drone Stream commands.
 ///
 /// # Safety
@@ -200,7 +428,7 @@ pub unsafe fn init(ctx: *mut command_context) -> c_int {
             handler: Some(handle_drone_stream_reset_command),
             mode: command_mode_COMMAND_EXEC,
             help: CString::new("start capture immediately after reset").unwrap().into_raw(),
-            usage: CString::new("[path[:stream]...]...").unwrap().into_raw(),
+            usage: CString::new("[-elf path] [path[:format][:stream]...]...").unwrap().into_raw(),
             chain: ptr::null_mut(),
             jim_handler: None,
         },
@@ -209,7 +437,7 @@ pub unsafe fn init(ctx: *mut command_context) -> c_int {
             handler: Some(handle_drone_stream_run_command),
             mode: command_mode_COMMAND_EXEC,
             help: CString::new("start capture on the running target").unwrap().into_raw(),
-            usage: CString::new("[path[:stream]...]...").unwrap().into_raw(),
+            usage: CString::new("[-elf path] [path[:format][:stream]...]...").unwrap().into_raw(),
             chain: ptr::null_mut(),
             jim_handler: None,
         },
@@ -288,6 +516,12 @@ unsafe extern "C" fn handle_drone_stream_stop_command(cmd: *mut command_invocati
             Some(drone_stream_timer_callback),
             context_ptr.cast(),
         ))
+        .and_then(|()| {
+            runtime::result_from(target_unregister_event_callback(
+                Some(drone_stream_event_callback),
+                context_ptr.cast(),
+            ))
+        })
         .and_then(|()| (*context_ptr).stop())
     })
 }
@@ -297,15 +531,30 @@ unsafe extern "C" fn drone_stream_timer_callback(context: *mut c_void) -> c_int
     runtime::result_into(context.poll())
 }
 
+#[allow(clippy::cast_possible_wrap)]
+unsafe extern "C" fn drone_stream_event_callback(
+    _target: *mut target,
+    event: target_event,
+    context: *mut c_void,
+) -> c_int {
+    let context = unsafe { &mut *context.cast::<Context>() };
+    if event == target_event_TARGET_EVENT_RESET_END {
+        if let Err(err) = context.handle_reset() {
+            warn!("Couldn't bootstrap Drone Stream after reset: {err:?}");
+            context.detach(Link::Detached);
+        }
+    }
+    ERROR_OK as i32
+}
+
 unsafe fn start_streaming<F: FnOnce(&mut Context, *mut command_context) -> runtime::Result<()>>(
     cmd: *mut command_invocation,
     f: F,
 ) -> c_int {
-    let route_descs = unsafe { args_iter(&mut *cmd) }.map(TryInto::try_into).collect();
-    match route_descs {
-        Ok(route_descs) => {
+    match parse_args(unsafe { args_iter(&mut *cmd) }) {
+        Ok((route_descs, elf)) => {
             let target = unsafe { get_current_target((*cmd).ctx) };
-            if let Some(context) = Context::new(target, route_descs) {
+            if let Some(context) = Context::new(target, unsafe { (*cmd).ctx }, route_descs, elf) {
                 let context_ptr = Box::into_raw(Box::new(context));
                 let atomic_result = CONTEXT_PTR.compare_exchange(
                     ptr::null_mut(),
@@ -327,6 +576,10 @@ unsafe fn start_streaming<F: FnOnce(&mut Context, *mut command_context) -> runti
                             target_timer_type_TARGET_TIMER_TYPE_PERIODIC,
                             context_ptr.cast(),
                         ))?;
+                        runtime::result_from(target_register_event_callback(
+                            Some(drone_stream_event_callback),
+                            context_ptr.cast(),
+                        ))?;
                         Ok(())
                     })());
                 }
@@ -345,6 +598,26 @@ unsafe fn args_iter(cmd: &mut command_invocation) -> impl FusedIterator<Item = &
         .map(|arg| unsafe { CStr::from_ptr(*arg).to_bytes() })
 }
 
+fn parse_args<'a>(
+    mut args: impl Iterator<Item = &'a [u8]>,
+) -> eyre::Result<(Vec<RouteDesc>, Option<PathBuf>)> {
+    let mut route_descs = Vec::new();
+    let mut elf = None;
+    while let Some(arg) = args.next() {
+        if arg == b"-elf" {
+            let path = args.next().ok_or_else(|| eyre!("`-elf` option requires a path"))?;
+            elf = Some(OsStr::from_bytes(path).into());
+        } else {
+            route_descs.push(arg.try_into()?);
+        }
+    }
+    Ok((route_descs, elf))
+}
+
+fn is_halted(target: *mut target) -> bool {
+    unsafe { (*target).state == target_state_TARGET_HALTED }
+}
+
 fn make_enable_mask(routes: &[RouteDesc]) -> u32 {
     let mut enable_mask = 0;
     for route in routes {
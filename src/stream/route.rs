@@ -2,6 +2,7 @@
 
 use drone_stream::STREAM_COUNT;
 use eyre::{bail, Error, Result};
+use serde_json::json;
 use std::cell::RefCell;
 use std::ffi::{OsStr, OsString};
 use std::fs::{File, OpenOptions};
@@ -10,6 +11,9 @@ use std::io::prelude::*;
 use std::io::{stdout, Stdout};
 use std::os::unix::prelude::*;
 use std::rc::Rc;
+use std::str::FromStr;
+use time::format_description::well_known::Rfc3339;
+use time::OffsetDateTime;
 use tracing::warn;
 
 /// Route description.
@@ -17,10 +21,22 @@ use tracing::warn;
 pub struct RouteDesc {
     /// Output path.
     pub path: OsString,
+    /// Output format.
+    pub format: Format,
     /// Selected streams.
     pub streams: Vec<u32>,
 }
 
+/// Output format.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Format {
+    /// Raw transaction payloads.
+    #[default]
+    Raw,
+    /// One JSON object per transaction.
+    JsonLines,
+}
+
 /// Stream output.
 #[derive(Debug)]
 pub enum Output {
@@ -30,36 +46,45 @@ pub enum Output {
     File(File),
 }
 
+/// Opened route.
+#[derive(Debug)]
+pub struct Route {
+    /// Output format.
+    pub format: Format,
+    /// Output destination.
+    pub output: Output,
+}
+
 /// Routes map.
 #[derive(Debug)]
 pub struct Routes(RoutesArray);
 
-type RoutesArray = [Vec<Rc<RefCell<Output>>>; STREAM_COUNT as usize];
+type RoutesArray = [Vec<Rc<RefCell<Route>>>; STREAM_COUNT as usize];
 
 impl Routes {
     /// Opens all outputs.
     pub fn open_all(route_descs: &[RouteDesc]) -> io::Result<Self> {
         let opened_routes = route_descs
             .iter()
-            .map(|RouteDesc { path, streams }| {
+            .map(|RouteDesc { path, format, streams }| {
                 if path.is_empty() {
                     Ok(Output::Stdout(stdout()))
                 } else {
                     OpenOptions::new().append(true).create(true).open(path).map(Output::File)
                 }
-                .map(|output| (streams, Rc::new(RefCell::new(output))))
+                .map(|output| (streams, Rc::new(RefCell::new(Route { format: *format, output }))))
             })
             .collect::<io::Result<Vec<_>>>()?;
         let mut routes: RoutesArray = Default::default();
-        for (streams, output) in opened_routes {
+        for (streams, route) in opened_routes {
             if streams.is_empty() {
-                for outputs in &mut routes {
-                    outputs.push(Rc::clone(&output));
+                for routes in &mut routes {
+                    routes.push(Rc::clone(&route));
                 }
             } else {
                 for stream in streams {
                     if let Some(map) = routes.get_mut(*stream as usize) {
-                        map.push(Rc::clone(&output));
+                        map.push(Rc::clone(&route));
                     } else {
                         warn!("Ignoring stream {}", stream);
                     }
@@ -69,19 +94,33 @@ impl Routes {
         Ok(Self(routes))
     }
 
-    /// Write `data` to all `stream` outputs.
+    /// Write `data` received from `section` to all `stream` outputs.
     ///
     /// # Panics
     ///
     /// If `stream` exceeds the maximum number of stream.
-    pub fn write(&self, stream: u8, data: &[u8]) -> io::Result<()> {
-        for output in &self.0[stream as usize] {
-            output.borrow_mut().write(data)?;
+    pub fn write(&self, section: &str, stream: u8, data: &[u8]) -> io::Result<()> {
+        for route in &self.0[stream as usize] {
+            route.borrow_mut().write(section, stream, data)?;
         }
         Ok(())
     }
 }
 
+impl Route {
+    /// Write `data` to the output in the route format.
+    pub fn write(&mut self, section: &str, stream: u8, data: &[u8]) -> io::Result<()> {
+        match self.format {
+            Format::Raw => self.output.write(data),
+            Format::JsonLines => {
+                let timestamp =
+                    OffsetDateTime::now_utc().format(&Rfc3339).map_err(io::Error::other)?;
+                self.output.write(json_line(section, stream, &timestamp, data).as_bytes())
+            }
+        }
+    }
+}
+
 impl Output {
     /// Write `data` to the output.
     pub fn write(&mut self, data: &[u8]) -> io::Result<()> {
@@ -97,15 +136,69 @@ impl Output {
     }
 }
 
+/// Formats a transaction as a single JSON line. UTF-8 payloads are embedded as
+/// is, and others are encoded with base64.
+fn json_line(section: &str, stream: u8, timestamp: &str, data: &[u8]) -> String {
+    let (encoding, payload) = match std::str::from_utf8(data) {
+        Ok(text) => ("utf-8", text.to_string()),
+        Err(_) => ("base64", base64::encode(data)),
+    };
+    let mut line = json!({
+        "section": section,
+        "stream": stream,
+        "timestamp": timestamp,
+        "encoding": encoding,
+        "payload": payload,
+        "length": data.len(),
+    })
+    .to_string();
+    line.push('\n');
+    line
+}
+
+impl Format {
+    /// Returns the format with the given name, or `None` if the name is not a
+    /// known format.
+    pub fn from_name(name: &[u8]) -> Option<Self> {
+        match name {
+            b"raw" => Some(Self::Raw),
+            b"jsonl" => Some(Self::JsonLines),
+            _ => None,
+        }
+    }
+}
+
+impl FromStr for Format {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match Self::from_name(s.as_bytes()) {
+            Some(format) => Ok(format),
+            None => bail!("unknown route format `{s}`, expected `raw` or `jsonl`"),
+        }
+    }
+}
+
 impl TryFrom<&[u8]> for RouteDesc {
     type Error = Error;
 
     fn try_from(value: &[u8]) -> Result<Self> {
-        let mut chunks = value.split(|&b| b == b':');
+        let mut chunks = value.split(|&b| b == b':').peekable();
         let path = OsStr::from_bytes(chunks.next().unwrap()).into();
+        let format = chunks.peek().and_then(|chunk| Format::from_name(chunk));
+        if format.is_some() {
+            chunks.next();
+        }
+        let format = format.unwrap_or_default();
         let streams = chunks
             .map(|stream| {
-                let number = String::from_utf8(stream.to_vec())?.parse()?;
+                let stream = String::from_utf8(stream.to_vec())?;
+                let Ok(number) = stream.parse() else {
+                    bail!(
+                        "invalid route chunk `{stream}`, expected a stream number or a format \
+                         (`raw` or `jsonl`)"
+                    );
+                };
                 if number >= STREAM_COUNT.into() {
                     bail!(
                         "stream number {number} exceeds the maximum number of streams \
@@ -115,6 +208,56 @@ impl TryFrom<&[u8]> for RouteDesc {
                 Ok(number)
             })
             .collect::<Result<_>>()?;
-        Ok(Self { path, streams })
+        Ok(Self { path, format, streams })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(route: &str) -> RouteDesc {
+        RouteDesc::try_from(route.as_bytes()).unwrap()
+    }
+
+    #[test]
+    fn test_parse_route() {
+        let route = parse("");
+        assert!(route.path.is_empty());
+        assert_eq!(route.format, Format::Raw);
+        assert!(route.streams.is_empty());
+        let route = parse("out.log:0:1");
+        assert_eq!(route.path, "out.log");
+        assert_eq!(route.format, Format::Raw);
+        assert_eq!(route.streams, [0, 1]);
+        let route = parse("out.jsonl:jsonl");
+        assert_eq!(route.path, "out.jsonl");
+        assert_eq!(route.format, Format::JsonLines);
+        assert!(route.streams.is_empty());
+        let route = parse(":raw:2:31");
+        assert!(route.path.is_empty());
+        assert_eq!(route.format, Format::Raw);
+        assert_eq!(route.streams, [2, 31]);
+        assert!(RouteDesc::try_from(b":json:1".as_slice()).is_err());
+        assert!(RouteDesc::try_from(b":1:jsonl".as_slice()).is_err());
+        assert!(RouteDesc::try_from(b":32".as_slice()).is_err());
+    }
+
+    #[test]
+    fn test_json_line() {
+        let line = json_line(".stream", 3, "2022-01-01T00:00:00Z", b"hello");
+        assert!(line.ends_with('\n'));
+        let value: serde_json::Value = serde_json::from_str(&line).unwrap();
+        assert_eq!(value["section"], ".stream");
+        assert_eq!(value["stream"], 3);
+        assert_eq!(value["timestamp"], "2022-01-01T00:00:00Z");
+        assert_eq!(value["encoding"], "utf-8");
+        assert_eq!(value["payload"], "hello");
+        assert_eq!(value["length"], 5);
+        let line = json_line(".stream", 0, "2022-01-01T00:00:00Z", &[0xFF, 0x00, 0x80]);
+        let value: serde_json::Value = serde_json::from_str(&line).unwrap();
+        assert_eq!(value["encoding"], "base64");
+        assert_eq!(value["payload"], "/wCA");
+        assert_eq!(value["length"], 3);
     }
 }
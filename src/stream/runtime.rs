@@ -4,10 +4,12 @@
 //! for storing the runtime state of Drone Stream.
 
 use drone_openocd::{
-    target, target_read_buffer, target_read_u32, target_write_buffer, target_write_u32, ERROR_FAIL,
-    ERROR_OK,
+    target, target_buffer_set_u32, target_read_buffer, target_read_u32, target_write_buffer,
+    target_write_u32, ERROR_FAIL, ERROR_OK,
+};
+use drone_stream::{
+    GlobalRuntime, Runtime, BOOTSTRAP_PAYLOAD_LENGTH, BOOTSTRAP_SEQUENCE, BOOTSTRAP_SEQUENCE_LENGTH,
 };
-use drone_stream::{GlobalRuntime, Runtime, BOOTSTRAP_SEQUENCE, BOOTSTRAP_SEQUENCE_LENGTH};
 use std::cmp::Ordering;
 use std::mem::{size_of, transmute, MaybeUninit};
 use std::os::raw::c_int;
@@ -42,8 +44,32 @@ pub trait RemoteGlobalRuntime {
     unsafe fn target_write_enable_mask(
         &self,
         target: *mut target,
-        global_address: u32,
+        global_address: u64,
+    ) -> Result<()>;
+
+    /// Writes the `capabilities` field to the target.
+    ///
+    /// Applications built against older versions of `drone-stream` don't have
+    /// this field, so it should be written only after the application has
+    /// advertised a matching feature.
+    ///
+    /// # Safety
+    ///
+    /// `target` must be a valid pointer to the OpencOCD target.
+    unsafe fn target_write_capabilities(
+        &self,
+        target: *mut target,
+        global_address: u64,
     ) -> Result<()>;
+
+    /// Reads the whole global runtime from the target.
+    ///
+    /// # Safety
+    ///
+    /// `target` must be a valid pointer to the OpencOCD target.
+    unsafe fn target_read(target: *mut target, global_address: u64) -> Result<Self>
+    where
+        Self: Sized;
 }
 
 /// Methods for working with the runtime instance that resides in the
@@ -53,7 +79,8 @@ pub trait RemoteRuntime {
     /// all other fields zeroed.
     fn from_buffer_size(buffer_size: u32) -> Self;
 
-    /// Writes the runtime to the target as a bootstrap sequence.
+    /// Writes the writable runtime fields to the target as a bootstrap
+    /// sequence. The read-only fields are left for the application to fill.
     ///
     /// # Safety
     ///
@@ -61,32 +88,50 @@ pub trait RemoteRuntime {
     unsafe fn target_write_bootstrap(
         &self,
         target: *mut target,
-        address: u32,
+        address: u64,
         global_runtime: Option<&GlobalRuntime>,
     ) -> Result<()>;
 
+    /// Checks whether the buffer still starts with the bootstrap sequence, i.e.
+    /// the application hasn't picked it up yet.
+    ///
+    /// # Safety
+    ///
+    /// `target` must be a valid pointer to the OpencOCD target.
+    unsafe fn target_bootstrap_pending(target: *mut target, address: u64) -> Result<bool>;
+
     /// Writes the `read_cursor` field to the target.
     ///
     /// # Safety
     ///
     /// `target` must be a valid pointer to the OpencOCD target.
-    unsafe fn target_write_read_cursor(&self, target: *mut target, address: u32) -> Result<()>;
+    unsafe fn target_write_read_cursor(&self, target: *mut target, address: u64) -> Result<()>;
 
     /// Writes the `write_cursor` field to the target.
     ///
     /// # Safety
     ///
     /// `target` must be a valid pointer to the OpencOCD target.
-    unsafe fn target_write_write_cursor(&self, target: *mut target, address: u32) -> Result<()>;
+    unsafe fn target_write_write_cursor(&self, target: *mut target, address: u64) -> Result<()>;
 
     /// Reads the `write_cursor` field from the target.
     ///
     /// # Safety
     ///
     /// `target` must be a valid pointer to the OpencOCD target.
-    unsafe fn target_read_write_cursor(&mut self, target: *mut target, address: u32) -> Result<()>;
+    unsafe fn target_read_write_cursor(&mut self, target: *mut target, address: u64) -> Result<()>;
 
-    /// Consumes pending data available on the target.
+    /// Reads the whole runtime from the target.
+    ///
+    /// # Safety
+    ///
+    /// `target` must be a valid pointer to the OpencOCD target.
+    unsafe fn target_read(target: *mut target, address: u64) -> Result<Self>
+    where
+        Self: Sized;
+
+    /// Consumes pending data available on the target up to the last read
+    /// `write_cursor` value.
     ///
     /// # Safety
     ///
@@ -94,7 +139,7 @@ pub trait RemoteRuntime {
     unsafe fn target_consume_buffer<'r, 'b>(
         &'r mut self,
         target: *mut target,
-        address: u32,
+        address: u64,
         buffer: &'b mut [u8],
     ) -> Result<(&'b mut [u8], Option<usize>)>;
 }
@@ -113,7 +158,7 @@ macro_rules! read_field {
         result_from(unsafe {
             target_read_u32(
                 $target,
-                ($address - size_of::<Self>() as u32 + offset_of!($field) as u32).into(),
+                $address - size_of::<Self>() as u64 + offset_of!($field) as u64,
                 &mut $self.$field,
             )
         })
@@ -125,7 +170,7 @@ macro_rules! write_field {
         result_from(unsafe {
             target_write_u32(
                 $target,
-                ($address - size_of::<Self>() as u32 + offset_of!($field) as u32).into(),
+                $address - size_of::<Self>() as u64 + offset_of!($field) as u64,
                 $self.$field,
             )
         })
@@ -135,11 +180,7 @@ macro_rules! write_field {
 macro_rules! write_global_field {
     ($self:ident, $target:expr, $global_address:expr, $field:ident) => {{
         result_from(unsafe {
-            target_write_u32(
-                $target,
-                ($global_address + offset_of!($field) as u32).into(),
-                $self.$field,
-            )
+            target_write_u32($target, $global_address + offset_of!($field) as u64, $self.$field)
         })
     }};
 }
@@ -154,10 +195,31 @@ impl RemoteGlobalRuntime for GlobalRuntime {
     unsafe fn target_write_enable_mask(
         &self,
         target: *mut target,
-        global_address: u32,
+        global_address: u64,
     ) -> Result<()> {
         write_global_field!(self, target, global_address, enable_mask)
     }
+
+    unsafe fn target_write_capabilities(
+        &self,
+        target: *mut target,
+        global_address: u64,
+    ) -> Result<()> {
+        write_global_field!(self, target, global_address, capabilities)
+    }
+
+    unsafe fn target_read(target: *mut target, global_address: u64) -> Result<Self> {
+        let mut global_runtime = [0; size_of::<GlobalRuntime>()];
+        unsafe {
+            result_from(target_read_buffer(
+                target,
+                global_address,
+                size_of::<GlobalRuntime>() as u32,
+                global_runtime.as_mut_ptr(),
+            ))?;
+            Ok(transmute::<[u8; size_of::<GlobalRuntime>()], GlobalRuntime>(global_runtime))
+        }
+    }
 }
 
 impl RemoteRuntime for Runtime {
@@ -170,63 +232,77 @@ impl RemoteRuntime for Runtime {
     unsafe fn target_write_bootstrap(
         &self,
         target: *mut target,
-        address: u32,
+        address: u64,
         global_runtime: Option<&GlobalRuntime>,
     ) -> Result<()> {
+        let mut payload = Vec::with_capacity(BOOTSTRAP_PAYLOAD_LENGTH);
+        payload.extend_from_slice(&BOOTSTRAP_SEQUENCE);
+        let fields = [self.buffer_size, self.read_cursor, self.write_cursor];
+        let enable_mask = global_runtime.map(|global_runtime| global_runtime.enable_mask);
+        for field in fields.into_iter().chain(enable_mask) {
+            let mut bytes = [0; 4];
+            unsafe { target_buffer_set_u32(target, bytes.as_mut_ptr(), field) };
+            payload.extend_from_slice(&bytes);
+        }
         unsafe {
-            let mut bootstrap_address = address.into();
             result_from(target_write_buffer(
                 target,
-                bootstrap_address,
-                BOOTSTRAP_SEQUENCE_LENGTH as u32,
-                BOOTSTRAP_SEQUENCE.as_ptr(),
-            ))?;
-            bootstrap_address += BOOTSTRAP_SEQUENCE_LENGTH as u64;
-            let runtime: [u8; size_of::<Runtime>()] = transmute(self.clone());
-            result_from(target_write_buffer(
-                target,
-                bootstrap_address,
-                size_of::<Runtime>() as u32,
-                runtime.as_ptr(),
+                address,
+                payload.len() as u32,
+                payload.as_ptr(),
             ))?;
-            bootstrap_address += size_of::<Runtime>() as u64;
-            if let Some(global_runtime) = global_runtime {
-                let global_runtime: [u8; size_of::<GlobalRuntime>()] =
-                    transmute(global_runtime.clone());
-                result_from(target_write_buffer(
-                    target,
-                    bootstrap_address,
-                    size_of::<GlobalRuntime>() as u32,
-                    global_runtime.as_ptr(),
-                ))?;
-            }
             self.target_write_read_cursor(target, address)?;
             self.target_write_write_cursor(target, address)?;
         }
         Ok(())
     }
 
-    unsafe fn target_write_read_cursor(&self, target: *mut target, address: u32) -> Result<()> {
+    unsafe fn target_bootstrap_pending(target: *mut target, address: u64) -> Result<bool> {
+        let mut sequence = [0; BOOTSTRAP_SEQUENCE_LENGTH];
+        unsafe {
+            result_from(target_read_buffer(
+                target,
+                address,
+                BOOTSTRAP_SEQUENCE_LENGTH as u32,
+                sequence.as_mut_ptr(),
+            ))?;
+        }
+        Ok(sequence == BOOTSTRAP_SEQUENCE)
+    }
+
+    unsafe fn target_write_read_cursor(&self, target: *mut target, address: u64) -> Result<()> {
         write_field!(self, target, address, read_cursor)
     }
 
-    unsafe fn target_write_write_cursor(&self, target: *mut target, address: u32) -> Result<()> {
+    unsafe fn target_write_write_cursor(&self, target: *mut target, address: u64) -> Result<()> {
         write_field!(self, target, address, write_cursor)
     }
 
-    unsafe fn target_read_write_cursor(&mut self, target: *mut target, address: u32) -> Result<()> {
+    unsafe fn target_read_write_cursor(&mut self, target: *mut target, address: u64) -> Result<()> {
         read_field!(self, target, address, write_cursor)
     }
 
+    unsafe fn target_read(target: *mut target, address: u64) -> Result<Self> {
+        let mut runtime = [0; size_of::<Runtime>()];
+        unsafe {
+            result_from(target_read_buffer(
+                target,
+                address - size_of::<Runtime>() as u64,
+                size_of::<Runtime>() as u32,
+                runtime.as_mut_ptr(),
+            ))?;
+            Ok(transmute::<[u8; size_of::<Runtime>()], Runtime>(runtime))
+        }
+    }
+
     unsafe fn target_consume_buffer<'r, 'b>(
         &'r mut self,
         target: *mut target,
-        address: u32,
+        address: u64,
         buffer: &'b mut [u8],
     ) -> Result<(&'b mut [u8], Option<usize>)> {
         let mut count;
         let mut wrap_point = None;
-        unsafe { self.target_read_write_cursor(target, address)? };
         match self.write_cursor.cmp(&self.read_cursor) {
             Ordering::Equal => return Ok((&mut buffer[0..0], wrap_point)),
             Ordering::Greater => {
@@ -235,7 +311,7 @@ impl RemoteRuntime for Runtime {
                 unsafe {
                     result_from(target_read_buffer(
                         target,
-                        (address + self.read_cursor).into(),
+                        address + u64::from(self.read_cursor),
                         count,
                         buffer.as_mut_ptr(),
                     ))?;
@@ -247,7 +323,7 @@ impl RemoteRuntime for Runtime {
                 unsafe {
                     result_from(target_read_buffer(
                         target,
-                        (address + self.read_cursor).into(),
+                        address + u64::from(self.read_cursor),
                         count,
                         buffer.as_mut_ptr(),
                     ))?;
@@ -258,12 +334,7 @@ impl RemoteRuntime for Runtime {
                     count += self.write_cursor;
                     assert!(count as usize <= buffer.len());
                     unsafe {
-                        result_from(target_read_buffer(
-                            target,
-                            address.into(),
-                            self.write_cursor,
-                            ptr,
-                        ))?;
+                        result_from(target_read_buffer(target, address, self.write_cursor, ptr))?;
                     }
                 }
             }
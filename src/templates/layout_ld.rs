@@ -1,10 +1,10 @@
 //! Linker script.
 
+use drone_config::mpu::{Arch, ARMV8M_MAIR0};
 use drone_config::{addr, size, Layout};
 use eyre::Result;
 use heck::{AsShoutySnakeCase, ToShoutySnakeCase};
 use sailfish::TemplateOnce;
-use std::collections::BTreeMap;
 use std::fs;
 use std::path::Path;
 
@@ -15,16 +15,36 @@ pub const DATA_SECTIONS: &[&str] = &["data", "bss", "uninitialized"];
 #[template(path = "layout.ld/outer.stpl")]
 struct LayoutLd<'a> {
     memories: Vec<Memory>,
-    sections: BTreeMap<u32, String>,
+    program: String,
+    alias_program: bool,
+    header_size: Option<String>,
+    header_offset: Option<String>,
+    word: &'static str,
+    entry: &'a str,
+    images: Vec<Image>,
+    sections: Vec<(u64, String)>,
+    flash_sections: Vec<String>,
+    data_sections: Vec<String>,
+    copy_table: Vec<String>,
+    zero_table: Vec<String>,
+    mpu_table: Vec<(String, String)>,
+    mpu_mair0: Option<String>,
     include_before: &'a [String],
     include_after: &'a [String],
 }
 
 struct Memory {
     name: String,
-    mode: &'static str,
+    mode: String,
     origin: String,
     length: String,
+    no_dma: bool,
+}
+
+struct Image {
+    symbol: String,
+    origin: String,
+    end: String,
 }
 
 #[derive(TemplateOnce)]
@@ -42,6 +62,29 @@ struct Stack<'a> {
 struct Data {
     origin: String,
     ram: String,
+    load_from: String,
+}
+
+#[derive(TemplateOnce)]
+#[template(path = "layout.ld/data_section.stpl")]
+struct DataSection<'a> {
+    name: &'a str,
+    uppercase_name: String,
+    origin: String,
+    ram: String,
+    load_from: String,
+}
+
+#[derive(TemplateOnce)]
+#[template(path = "layout.ld/code.stpl")]
+struct Code<'a> {
+    name: &'a str,
+    uppercase_name: String,
+    origin: String,
+    size: String,
+    align: String,
+    ram: Option<String>,
+    flash: String,
 }
 
 #[derive(TemplateOnce)]
@@ -52,6 +95,8 @@ struct Heap<'a> {
     origin: String,
     size: String,
     ram: String,
+    program: String,
+    word: &'static str,
     pools: Vec<Pool>,
 }
 
@@ -78,21 +123,72 @@ struct Stream<'a> {
     ram: String,
 }
 
+#[derive(TemplateOnce)]
+#[template(path = "layout.ld/reserved.stpl")]
+struct Reserved {
+    section: String,
+    symbol: String,
+    origin: String,
+    size: String,
+    ram: String,
+}
+
 /// Creates a new linker script.
 pub fn render(path: &Path, layout: &Layout) -> Result<()> {
-    let mut sections = BTreeMap::new();
+    Ok(fs::write(path, render_script(layout)?)?)
+}
+
+fn render_script(layout: &Layout) -> Result<String> {
+    let mut sections = Vec::new();
+    let mut flash_sections = Vec::new();
+    let mut data_sections = Vec::new();
+    let mut copy_table = Vec::new();
+    let mut zero_table = Vec::new();
+    render_code_sections(&mut sections, &mut flash_sections, &mut copy_table, layout);
+    render_named_data_sections(&mut data_sections, &mut copy_table, &mut zero_table, layout);
     render_global_stream_sections(&mut sections, layout);
     render_stream_sections(&mut sections, layout);
     render_data_sections(&mut sections, layout);
     render_heap_sections(&mut sections, layout);
     render_stacks(&mut sections, layout);
+    render_reserved_sections(&mut sections, &mut zero_table, layout);
+    // Stable sort keeps empty sections sharing an origin in the script.
+    sections.sort_by_key(|&(origin, _)| origin);
+    let (mpu_table, mpu_mair0) = render_mpu_table(layout)?;
+    let entry = layout
+        .selected_image
+        .as_ref()
+        .and_then(|image| layout.image[image].entry.as_deref())
+        .unwrap_or("main");
     let ctx = LayoutLd {
         memories: render_memories(layout),
+        program: layout.program_flash().to_shouty_snake_case(),
+        alias_program: layout.selected_image.is_some() && !layout.flash.contains_key("program"),
+        header_size: layout.header_size().map(size::to_string),
+        header_offset: layout.header_offset().filter(|&offset| offset > 0).map(size::to_string),
+        word: word(layout),
+        entry,
+        images: render_images(layout),
         sections,
+        flash_sections,
+        data_sections,
+        copy_table,
+        zero_table,
+        mpu_table,
+        mpu_mair0,
         include_before: &layout.linker.include_before,
         include_after: &layout.linker.include_after,
     };
-    Ok(fs::write(path, ctx.render_once().unwrap())?)
+    Ok(ctx.render_once().unwrap())
+}
+
+/// Returns the linker data command for a pointer-sized word.
+fn word(layout: &Layout) -> &'static str {
+    if layout.address_width() == 64 {
+        "QUAD"
+    } else {
+        "LONG"
+    }
 }
 
 fn render_memories(layout: &Layout) -> Vec<Memory> {
@@ -100,44 +196,169 @@ fn render_memories(layout: &Layout) -> Vec<Memory> {
     for (name, flash) in &layout.flash {
         memories.push(Memory {
             name: format!("FLASH_{}", AsShoutySnakeCase(name)),
-            mode: "rx",
+            mode: flash.mode.clone().unwrap_or_else(|| "rx".into()),
             origin: addr::to_string(flash.origin),
             length: size::to_string(flash.size),
+            no_dma: flash.no_dma.unwrap_or(false),
         });
     }
     for (name, ram) in &layout.ram {
         memories.push(Memory {
             name: format!("RAM_{}", AsShoutySnakeCase(name)),
-            mode: "wx",
+            mode: ram.mode.clone().unwrap_or_else(|| "wx".into()),
             origin: addr::to_string(ram.origin),
             length: size::to_string(ram.size),
+            no_dma: ram.no_dma.unwrap_or(false),
         });
     }
     memories
 }
 
-fn render_stacks(sections: &mut BTreeMap<u32, String>, layout: &Layout) {
+fn render_images(layout: &Layout) -> Vec<Image> {
+    let mut images = Vec::new();
+    let mut push = |symbol: String, flash: &str| {
+        let flash = &layout.flash[flash];
+        images.push(Image {
+            symbol,
+            origin: addr::to_string(flash.origin),
+            end: addr::to_string(flash.origin + flash.size),
+        });
+    };
+    for (name, image) in &layout.image {
+        push(format!("IMAGE_{}", AsShoutySnakeCase(name)), &image.flash);
+    }
+    if layout.selected_image.is_some() {
+        push("IMAGE".into(), layout.program_flash());
+    }
+    images
+}
+
+type MpuTable = Vec<(String, String)>;
+
+fn render_mpu_table(layout: &Layout) -> Result<(MpuTable, Option<String>)> {
+    let Some(mpu) = &layout.mpu else { return Ok((Vec::new(), None)) };
+    let regions = mpu.regions(layout)?;
+    let table = mpu
+        .registers(&regions)
+        .into_iter()
+        .map(|(base, attrs)| (format!("0x{base:08X}"), format!("0x{attrs:08X}")))
+        .collect();
+    let mair0 = (mpu.arch == Arch::Armv8m).then(|| format!("0x{ARMV8M_MAIR0:08X}"));
+    Ok((table, mair0))
+}
+
+fn render_stacks(sections: &mut Vec<(u64, String)>, layout: &Layout) {
     for (name, stack) in &layout.stack {
+        let origin = stack.origin + stack.prefix_size;
         let ctx = Stack {
             name,
             uppercase_name: name.to_shouty_snake_case(),
-            origin: addr::to_string(stack.origin),
+            origin: addr::to_string(origin),
             size: size::to_string(stack.fixed_size),
             ram: stack.ram.to_shouty_snake_case(),
         };
-        sections.insert(stack.origin, ctx.render_once().unwrap());
+        sections.push((origin, ctx.render_once().unwrap()));
+    }
+}
+
+fn render_reserved_sections(
+    sections: &mut Vec<(u64, String)>,
+    zero_table: &mut Vec<String>,
+    layout: &Layout,
+) {
+    for (name, reserved) in &layout.reserved {
+        let ctx = Reserved {
+            section: format!("reserved_{name}"),
+            symbol: format!("RESERVED_{}", AsShoutySnakeCase(name)),
+            origin: addr::to_string(reserved.origin),
+            size: size::to_string(reserved.size),
+            ram: reserved.ram.to_shouty_snake_case(),
+        };
+        sections.push((reserved.origin, ctx.render_once().unwrap()));
+        if !reserved.noinit.unwrap_or(false) {
+            zero_table.push(format!(".reserved_{name}"));
+        }
+    }
+    if let Some(crashlog) = &layout.crashlog {
+        let ctx = Reserved {
+            section: "crashlog".into(),
+            symbol: "CRASHLOG".into(),
+            origin: addr::to_string(crashlog.origin),
+            size: size::to_string(crashlog.size),
+            ram: crashlog.ram.to_shouty_snake_case(),
+        };
+        sections.push((crashlog.origin, ctx.render_once().unwrap()));
     }
 }
 
-fn render_data_sections(sections: &mut BTreeMap<u32, String>, layout: &Layout) {
+fn render_data_sections(sections: &mut Vec<(u64, String)>, layout: &Layout) {
     let ctx = Data {
         origin: addr::to_string(layout.data.origin),
         ram: layout.data.ram.to_shouty_snake_case(),
+        load_from: layout
+            .data
+            .load_from
+            .as_deref()
+            .unwrap_or(layout.program_flash())
+            .to_shouty_snake_case(),
     };
-    sections.insert(layout.data.origin, ctx.render_once().unwrap());
+    sections.push((layout.data.origin, ctx.render_once().unwrap()));
+}
+
+fn render_named_data_sections(
+    data_sections: &mut Vec<String>,
+    copy_table: &mut Vec<String>,
+    zero_table: &mut Vec<String>,
+    layout: &Layout,
+) {
+    for (name, data) in &layout.data.sections {
+        let ctx = DataSection {
+            name,
+            uppercase_name: name.to_shouty_snake_case(),
+            origin: addr::to_string(data.origin),
+            ram: data.ram.to_shouty_snake_case(),
+            load_from: data
+                .load_from
+                .as_deref()
+                .unwrap_or(layout.program_flash())
+                .to_shouty_snake_case(),
+        };
+        data_sections.push(ctx.render_once().unwrap());
+        copy_table.push(format!(".data_{name}"));
+        zero_table.push(format!(".bss_{name}"));
+    }
+}
+
+fn render_code_sections(
+    sections: &mut Vec<(u64, String)>,
+    flash_sections: &mut Vec<String>,
+    copy_table: &mut Vec<String>,
+    layout: &Layout,
+) {
+    for (name, code) in &layout.code {
+        let flash = match &code.ram {
+            Some(_) => code.load_from.as_deref().unwrap_or(layout.program_flash()),
+            None => code.flash.as_deref().unwrap_or(layout.program_flash()),
+        };
+        let ctx = Code {
+            name,
+            uppercase_name: name.to_shouty_snake_case(),
+            origin: addr::to_string(code.origin),
+            size: size::to_string(code.size.unwrap_or(0)),
+            align: size::to_string(code.align.unwrap_or(4)),
+            ram: code.ram.as_deref().map(ToShoutySnakeCase::to_shouty_snake_case),
+            flash: flash.to_shouty_snake_case(),
+        };
+        if code.ram.is_some() {
+            sections.push((code.origin, ctx.render_once().unwrap()));
+            copy_table.push(format!(".code_{name}"));
+        } else {
+            flash_sections.push(ctx.render_once().unwrap());
+        }
+    }
 }
 
-fn render_heap_sections(sections: &mut BTreeMap<u32, String>, layout: &Layout) {
+fn render_heap_sections(sections: &mut Vec<(u64, String)>, layout: &Layout) {
     for (name, heap) in &layout.heap {
         let mut pointer = heap.section.origin + heap.section.prefix_size;
         let mut pools = Vec::new();
@@ -156,23 +377,25 @@ fn render_heap_sections(sections: &mut BTreeMap<u32, String>, layout: &Layout) {
             origin: addr::to_string(heap.section.origin),
             size: size::to_string(heap.section.fixed_size),
             ram: heap.section.ram.to_shouty_snake_case(),
+            program: layout.program_flash().to_shouty_snake_case(),
+            word: word(layout),
             pools,
         };
-        sections.insert(heap.section.origin, ctx.render_once().unwrap());
+        sections.push((heap.section.origin, ctx.render_once().unwrap()));
     }
 }
 
-fn render_global_stream_sections(sections: &mut BTreeMap<u32, String>, layout: &Layout) {
+fn render_global_stream_sections(sections: &mut Vec<(u64, String)>, layout: &Layout) {
     if let Some(stream) = &layout.stream {
         let ctx = GlobalStream {
             origin: addr::to_string(stream.origin),
             ram: stream.ram.to_shouty_snake_case(),
         };
-        sections.insert(stream.origin, ctx.render_once().unwrap());
+        sections.push((stream.origin, ctx.render_once().unwrap()));
     }
 }
 
-fn render_stream_sections(sections: &mut BTreeMap<u32, String>, layout: &Layout) {
+fn render_stream_sections(sections: &mut Vec<(u64, String)>, layout: &Layout) {
     if let Some(stream) = &layout.stream {
         for (name, stream) in &stream.sections {
             let ctx = Stream {
@@ -182,7 +405,65 @@ fn render_stream_sections(sections: &mut BTreeMap<u32, String>, layout: &Layout)
                 size: size::to_string(stream.size),
                 ram: stream.ram.to_shouty_snake_case(),
             };
-            sections.insert(stream.origin, ctx.render_once().unwrap());
+            sections.push((stream.origin, ctx.render_once().unwrap()));
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sections_sharing_origin() {
+        let layout = r#"
+[flash]
+program = { origin = 0x08000000, size = "128K" }
+[ram]
+main = { origin = 0x20000000, size = "20K" }
+[data]
+ram = "main"
+[stack]
+core0 = { ram = "main", size = "50%" }
+[heap.main]
+ram = "main"
+size = "50%"
+pools = []
+[reserved]
+crashlog = { ram = "main", origin = "ram.main.end - 256", size = "256", noinit = true }
+"#;
+        let mut layout = Layout::parse(layout).unwrap();
+        layout.calculate(Some(0)).unwrap();
+        assert_eq!(layout.data.origin, layout.reserved["crashlog"].origin);
+        let script = render_script(&layout).unwrap();
+        let data = script.find("    .data 0x20004f00 :").unwrap();
+        let reserved = script.find("    .reserved_crashlog 0x20004f00 (NOLOAD) :").unwrap();
+        assert!(data < reserved);
+    }
+
+    #[test]
+    fn test_header_offset() {
+        let layout = r#"
+[flash]
+program = { origin = 0x08000000, size = "128K" }
+[ram]
+main = { origin = 0x20000000, size = "20K" }
+[data]
+ram = "main"
+[header]
+version = "1.2.3"
+"#;
+        let mut layout = Layout::parse(layout).unwrap();
+        let script = render_script(&layout).unwrap();
+        assert_eq!(script.matches("*(.text.main);").count(), 1);
+        let text_main = script.find("    .text_main ORIGIN(FLASH_PROGRAM) :").unwrap();
+        let assert = script.find("ASSERT(ABSOLUTE(.) <= ORIGIN(FLASH_PROGRAM) + 1K,").unwrap();
+        let header = script.find("    .image_header ORIGIN(FLASH_PROGRAM) + 1K :").unwrap();
+        assert!(text_main < assert && assert < header);
+        layout.header.as_mut().unwrap().offset = Some(0);
+        let script = render_script(&layout).unwrap();
+        assert_eq!(script.matches("*(.text.main);").count(), 1);
+        assert!(!script.contains(".text_main"));
+        assert!(script.contains("    .image_header ORIGIN(FLASH_PROGRAM) :"));
+    }
+}
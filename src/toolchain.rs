@@ -0,0 +1,178 @@
+//! Rust toolchain utilities.
+
+use eyre::{bail, Result};
+use std::collections::HashMap;
+use std::os::unix::prelude::*;
+use std::path::{Path, PathBuf};
+use std::{env, fs};
+use std::process::{Command, ExitStatus};
+use walkdir::WalkDir;
+
+/// Searches for an executable `tool` inside the Rust sysroot, e.g. one of
+/// `llvm-tools-preview` component.
+pub fn search_rust_tool(tool: &str) -> Result<PathBuf> {
+    let program = "rustc";
+    let mut rustc = Command::new(program);
+    rustc.arg("--print").arg("sysroot");
+    let output = rustc.output()?;
+    check_status(program, output.status)?;
+    let sysroot = String::from_utf8(output.stdout)?;
+    for entry in WalkDir::new(sysroot.trim()) {
+        let entry = entry?;
+        if entry.file_name() == tool {
+            return Ok(entry.into_path());
+        }
+    }
+    bail!("couldn't find `{}`", tool);
+}
+
+/// Returns the cargo output directory for the current target inside the
+/// project `root`.
+pub fn cargo_target_dir(root: &Path) -> Result<PathBuf> {
+    let target_dir = env::var("CARGO_BUILD_TARGET_DIR")
+        .or_else(|_| env::var("CARGO_TARGET_DIR"))
+        .unwrap_or_else(|_| "target".into());
+    Ok(root.join(target_dir).join(env::var("CARGO_BUILD_TARGET")?))
+}
+
+/// Lists executables built by cargo inside the `target` directory returned by
+/// [`cargo_target_dir`]. The list is filtered by the `binary` name and the
+/// `profile` name, if given.
+pub fn built_binaries(
+    target: &Path,
+    binary: Option<&str>,
+    profile: Option<&str>,
+) -> Result<Vec<PathBuf>> {
+    let mut binaries = Vec::new();
+    for entry in target.read_dir()? {
+        let entry = entry?;
+        if !entry.file_type()?.is_dir() {
+            continue;
+        }
+        let profile_path = entry.path();
+        let filter_profile = profile.is_some_and(|profile| {
+            profile_path.file_name().expect("bad target dir").to_string_lossy() != profile
+        });
+        if filter_profile {
+            continue;
+        }
+        for entry in profile_path.read_dir()? {
+            let path = entry?.path();
+            let metadata = path.metadata()?;
+            if !metadata.is_file() || metadata.permissions().mode() & 0o111 == 0 {
+                continue;
+            }
+            let file_name = path.file_name().expect("bad target dir").to_string_lossy();
+            if binary.is_some_and(|binary| file_name != binary) {
+                continue;
+            }
+            if file_name.starts_with('.') {
+                continue;
+            }
+            binaries.push(path);
+        }
+    }
+    Ok(binaries)
+}
+
+/// Returns `Err` if `status` is not successful.
+pub fn check_status(program: &str, status: ExitStatus) -> Result<()> {
+    if !status.success() {
+        if let Some(code) = status.code() {
+            bail!("{program} exited with status code: {code}")
+        }
+        bail!("{program} terminated by signal")
+    }
+    Ok(())
+}
+
+/// Reads values of all defined symbols from the `elf` file.
+pub fn read_symbols(elf: &Path) -> Result<HashMap<String, u64>> {
+    let program = "llvm-nm";
+    let mut command = Command::new(search_rust_tool(program)?);
+    command.arg("--defined-only").arg(elf);
+    let output = command.output()?;
+    check_status(program, output.status)?;
+    let stdout = String::from_utf8(output.stdout)?;
+    let mut map = HashMap::new();
+    for line in stdout.lines() {
+        if let [value, _, name] = line.split_whitespace().collect::<Vec<_>>().as_slice() {
+            map.insert((*name).to_string(), u64::from_str_radix(value, 16)?);
+        }
+    }
+    Ok(map)
+}
+
+/// Resolves `addresses` to function names and source locations using debug
+/// information of the `elf` file.
+pub fn symbolize(elf: &Path, addresses: &[u32]) -> Result<Vec<String>> {
+    let program = "llvm-addr2line";
+    let mut command = Command::new(search_rust_tool(program)?);
+    command.arg("--functions").arg("--demangle").arg("--pretty-print").arg("-e").arg(elf);
+    command.args(addresses.iter().map(|address| format!("{address:#010x}")));
+    let output = command.output()?;
+    check_status(program, output.status)?;
+    let stdout = String::from_utf8(output.stdout)?;
+    Ok(stdout.lines().map(ToString::to_string).collect())
+}
+
+/// Converts the `elf` file into a flat binary image. Gaps between the
+/// sections are filled with `0xFF`.
+pub fn read_binary(elf: &Path) -> Result<Vec<u8>> {
+    let program = "llvm-objcopy";
+    let output = elf.with_extension("bin.tmp");
+    let mut command = Command::new(search_rust_tool(program)?);
+    command.arg("-O").arg("binary").arg("--gap-fill").arg("0xff").arg(elf).arg(&output);
+    check_status(program, command.status()?)?;
+    let binary = fs::read(&output)?;
+    fs::remove_file(&output)?;
+    Ok(binary)
+}
+
+/// Replaces the contents of the `section` of the `elf` file with `data`.
+pub fn update_section(elf: &Path, section: &str, data: &[u8]) -> Result<()> {
+    let program = "llvm-objcopy";
+    let contents = elf.with_extension("section.tmp");
+    fs::write(&contents, data)?;
+    let mut command = Command::new(search_rust_tool(program)?);
+    command.arg("--update-section").arg(format!("{section}={}", contents.display())).arg(elf);
+    let status = command.status();
+    fs::remove_file(&contents)?;
+    check_status(program, status?)
+}
+
+/// Output section of an ELF file.
+#[derive(Debug)]
+pub struct ElfSection {
+    /// Section name without the leading dot.
+    pub name: String,
+    /// Section size.
+    pub size: u64,
+    /// Section virtual address.
+    pub address: u64,
+}
+
+/// Reads sizes and addresses of all sections from the `elf` file.
+pub fn read_sections(elf: &Path) -> Result<Vec<ElfSection>> {
+    let program = "llvm-size";
+    let mut command = Command::new(search_rust_tool(program)?);
+    command.arg("-A").arg(elf);
+    let output = command.output()?;
+    check_status(program, output.status)?;
+    let stdout = String::from_utf8(output.stdout)?;
+    let mut sections = Vec::new();
+    for line in stdout.lines() {
+        if let Some(line) = line.strip_prefix('.') {
+            if let [name, size, address, ..] =
+                line.split_whitespace().collect::<Vec<_>>().as_slice()
+            {
+                sections.push(ElfSection {
+                    name: (*name).to_string(),
+                    size: size.parse()?,
+                    address: address.parse()?,
+                });
+            }
+        }
+    }
+    Ok(sections)
+}
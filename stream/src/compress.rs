@@ -0,0 +1,191 @@
+//! Transaction compression codec.
+//!
+//! A compressed transaction payload is a sequence of tokens. Each token starts
+//! with a control byte:
+//!
+//! * `0x00..=0x7F` - a literal run of `control + 1` bytes, which follow the
+//!   control byte.
+//! * `0x80..=0xFF` - a back-reference of `(control & 0x7F) + 3` bytes. The next
+//!   byte holds the distance minus one, so the reference may point up to 256
+//!   bytes back into the already decoded output. The distance may be less than
+//!   the length, in which case the referenced bytes are repeated.
+//!
+//! The compressor looks for back-references with a small hash table of recent
+//! positions, probing a single candidate per input byte. This keeps the work
+//! per byte constant at the cost of missing some matches.
+
+/// Minimal length of a back-reference.
+pub const MIN_MATCH: usize = 3;
+
+/// Maximal length of a back-reference.
+pub const MAX_MATCH: usize = 0x7F + MIN_MATCH;
+
+/// Maximal distance of a back-reference.
+pub const MAX_DISTANCE: usize = 0x100;
+
+const MATCH_FLAG: u8 = 0x80;
+
+const MAX_LITERALS: usize = 0x80;
+
+const HASH_BITS: u32 = 6;
+
+/// Marks an empty slot of the hash table.
+const NO_POSITION: usize = usize::MAX;
+
+/// Compresses `input` into `output`. Returns the length of the compressed data,
+/// or `None` if it doesn't fit into `output`.
+pub fn compress(input: &[u8], output: &mut [u8]) -> Option<usize> {
+    let mut written = 0;
+    let mut position = 0;
+    let mut literals = 0;
+    let mut table = [NO_POSITION; 1 << HASH_BITS];
+    while position < input.len() {
+        let (length, distance) = find_match(input, position, &mut table);
+        if length < MIN_MATCH {
+            position += 1;
+            continue;
+        }
+        for skipped in position + 1..position + length {
+            insert(input, skipped, &mut table);
+        }
+        written = write_literals(&input[literals..position], output, written)?;
+        #[allow(clippy::cast_possible_truncation)]
+        {
+            *output.get_mut(written)? = MATCH_FLAG | (length - MIN_MATCH) as u8;
+            *output.get_mut(written + 1)? = (distance - 1) as u8;
+        }
+        written += 2;
+        position += length;
+        literals = position;
+    }
+    write_literals(&input[literals..], output, written)
+}
+
+/// Decompresses `input` into `output`. Returns the length of the decompressed
+/// data, or `None` if `input` is malformed or doesn't fit into `output`.
+pub fn decompress(input: &[u8], output: &mut [u8]) -> Option<usize> {
+    let mut read = 0;
+    let mut written = 0;
+    while let Some(&control) = input.get(read) {
+        read += 1;
+        if control & MATCH_FLAG == 0 {
+            let length = usize::from(control) + 1;
+            let literals = input.get(read..read + length)?;
+            output.get_mut(written..written + length)?.copy_from_slice(literals);
+            read += length;
+            written += length;
+        } else {
+            let length = usize::from(control & !MATCH_FLAG) + MIN_MATCH;
+            let distance = usize::from(*input.get(read)?) + 1;
+            read += 1;
+            let start = written.checked_sub(distance)?;
+            if written + length > output.len() {
+                return None;
+            }
+            for i in 0..length {
+                output[written + i] = output[start + i];
+            }
+            written += length;
+        }
+    }
+    Some(written)
+}
+
+/// Probes the most recent position with the same hash as `position`, and
+/// records `position` in its place.
+fn find_match(input: &[u8], position: usize, table: &mut [usize]) -> (usize, usize) {
+    let Some(start) = insert(input, position, table) else { return (0, 0) };
+    if start == NO_POSITION || position - start > MAX_DISTANCE {
+        return (0, 0);
+    }
+    let length = input[start..]
+        .iter()
+        .zip(&input[position..])
+        .take(MAX_MATCH)
+        .take_while(|(a, b)| a == b)
+        .count();
+    (length, position - start)
+}
+
+/// Records `position` in the hash table. Returns the previously recorded
+/// position with the same hash, or `None` if there are not enough bytes left
+/// to form a back-reference.
+fn insert(input: &[u8], position: usize, table: &mut [usize]) -> Option<usize> {
+    let bytes = input.get(position..position + MIN_MATCH)?;
+    let key = u32::from(bytes[0]) << 16 | u32::from(bytes[1]) << 8 | u32::from(bytes[2]);
+    let slot = key.wrapping_mul(0x9E37_79B1) >> (32 - HASH_BITS);
+    Some(core::mem::replace(&mut table[slot as usize], position))
+}
+
+fn write_literals(literals: &[u8], output: &mut [u8], mut written: usize) -> Option<usize> {
+    for chunk in literals.chunks(MAX_LITERALS) {
+        #[allow(clippy::cast_possible_truncation)]
+        {
+            *output.get_mut(written)? = (chunk.len() - 1) as u8;
+        }
+        written += 1;
+        output.get_mut(written..written + chunk.len())?.copy_from_slice(chunk);
+        written += chunk.len();
+    }
+    Some(written)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(input: &[u8]) -> usize {
+        let mut compressed = [0; 1024];
+        let mut decompressed = [0; 1024];
+        let length = compress(input, &mut compressed).unwrap();
+        let decompressed_length = decompress(&compressed[..length], &mut decompressed).unwrap();
+        assert_eq!(&decompressed[..decompressed_length], input);
+        length
+    }
+
+    #[test]
+    fn test_empty() {
+        assert_eq!(round_trip(&[]), 0);
+    }
+
+    #[test]
+    fn test_literals() {
+        #[allow(clippy::cast_possible_truncation)]
+        let input: [u8; 256] = core::array::from_fn(|i| i as u8);
+        assert_eq!(round_trip(&input), 258);
+    }
+
+    #[test]
+    fn test_repetition() {
+        assert_eq!(round_trip(&[7; 256]), 6);
+    }
+
+    #[test]
+    fn test_text() {
+        let input = b"temperature=21.5 humidity=40 temperature=21.6 humidity=41";
+        assert!(round_trip(input) < input.len());
+    }
+
+    #[test]
+    fn test_distance_limit() {
+        #[allow(clippy::cast_possible_truncation)]
+        let mut input: [u8; 600] = core::array::from_fn(|i| (i * 7 + i / 13) as u8);
+        input[500..510].copy_from_slice(b"0123456789");
+        input[100..110].copy_from_slice(b"0123456789");
+        round_trip(&input);
+    }
+
+    #[test]
+    fn test_output_overflow() {
+        let mut output = [0; 4];
+        assert_eq!(compress(b"abcdef", &mut output), None);
+        assert_eq!(decompress(&[0, b'a', 0x80 | 2, 0], &mut output), None);
+    }
+
+    #[test]
+    fn test_invalid_distance() {
+        let mut output = [0; 16];
+        assert_eq!(decompress(&[0, b'a', 0x80, 1], &mut output), None);
+        assert_eq!(decompress(&[0, b'a', 0x80], &mut output), None);
+    }
+}
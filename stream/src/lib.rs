@@ -4,7 +4,14 @@
 #![warn(clippy::pedantic)]
 #![no_std]
 
-use core::mem::size_of;
+pub mod compress;
+
+/// Magic value of [`GlobalRuntime::magic`].
+pub const MAGIC: u32 = 0x4452_5354;
+
+/// Version of the Drone Stream protocol. Incremented on every incompatible
+/// change of the runtime data structures or the wire format.
+pub const PROTOCOL_VERSION: u32 = 1;
 
 /// Maximum number of streams.
 pub const STREAM_COUNT: u8 = 32;
@@ -13,6 +20,11 @@ pub const STREAM_COUNT: u8 = 32;
 pub const BOOTSTRAP_SEQUENCE_LENGTH: usize = 16;
 
 /// Sequence to bootstrap Drone Stream runtime immediately after reset.
+///
+/// The probe writes it at the beginning of each stream buffer, followed by the
+/// initial `buffer_size`, `read_cursor`, and `write_cursor` of [`Runtime`],
+/// and for the primary initializer, the initial `enable_mask` of
+/// [`GlobalRuntime`]. See [`BOOTSTRAP_PAYLOAD_LENGTH`].
 // Generated with the following command:
 //
 // rust-script --dep rand -e 'use rand::Rng; let mut a = [0_u8; 16]; \
@@ -20,17 +32,33 @@ pub const BOOTSTRAP_SEQUENCE_LENGTH: usize = 16;
 pub const BOOTSTRAP_SEQUENCE: [u8; BOOTSTRAP_SEQUENCE_LENGTH] =
     [41, 139, 234, 244, 56, 213, 238, 162, 226, 175, 62, 199, 229, 177, 168, 74];
 
+/// Length of the bootstrap payload, including [`BOOTSTRAP_SEQUENCE`]. The
+/// read-only runtime fields are never part of it.
+pub const BOOTSTRAP_PAYLOAD_LENGTH: usize = BOOTSTRAP_SEQUENCE_LENGTH + 4 * 4;
+
 /// Length of one frame header.
 pub const HEADER_LENGTH: u32 = 2;
 
+/// Bit in the stream number byte of the frame header, which marks the
+/// transaction payload as compressed. See [`compress`] module.
+pub const COMPRESSED_FLAG: u8 = 0x80;
+
+/// Capability bit of [`GlobalRuntime::capabilities`] and
+/// [`GlobalRuntime::features`]. If set in both, the application is allowed to
+/// send compressed transactions.
+pub const CAPABILITY_COMPRESSION: u32 = 1 << 0;
+
+/// Capabilities implemented by this version of the crate. Advertised in
+/// [`GlobalRuntime::features`] by [`GlobalRuntime::new`].
+pub const FEATURES: u32 = CAPABILITY_COMPRESSION;
+
 /// Maximal supported length of a single transaction.
 pub const MAX_TRANSACTION_LENGTH: u32 = 256;
 
 /// Minimal buffer size in bytes.
 #[allow(clippy::cast_possible_truncation)]
 pub const MIN_BUFFER_SIZE: u32 = {
-    let bootstrap_size =
-        (BOOTSTRAP_SEQUENCE_LENGTH + size_of::<Runtime>() + size_of::<GlobalRuntime>()) as u32;
+    let bootstrap_size = BOOTSTRAP_PAYLOAD_LENGTH as u32;
     let transaction_size = HEADER_LENGTH + MAX_TRANSACTION_LENGTH;
     let size = if bootstrap_size > transaction_size { bootstrap_size } else { transaction_size };
     (size / 4 + (size % 4 != 0) as u32) * 4
@@ -39,7 +67,12 @@ pub const MIN_BUFFER_SIZE: u32 = {
 /// Drone Stream global runtime data structure.
 ///
 /// This data structure risides in both the application memory and the `drone`
-/// utility memory.
+/// utility memory. The application initializes it with [`GlobalRuntime::new`].
+///
+/// The fields after `enable_mask` are absent in applications built against
+/// older versions of the crate, so the probe checks `magic` and `version`
+/// before touching them. Without `magic`, the probe falls back to the legacy
+/// protocol without capabilities.
 #[derive(Clone, Debug)]
 #[repr(C)]
 pub struct GlobalRuntime {
@@ -47,12 +80,34 @@ pub struct GlobalRuntime {
     ///
     /// Writable by the probe; readable by the application.
     pub enable_mask: u32,
+    /// Always equals to [`MAGIC`] when the runtime is initialized.
+    ///
+    /// Read-only field.
+    pub magic: u32,
+    /// Drone Stream protocol version the application is built with. See
+    /// [`PROTOCOL_VERSION`].
+    ///
+    /// Read-only field.
+    pub version: u32,
+    /// Capabilities supported by the application. See `CAPABILITY_*`
+    /// constants.
+    ///
+    /// Read-only field.
+    pub features: u32,
+    /// Capabilities supported by the probe. See `CAPABILITY_*` constants.
+    ///
+    /// Writable by the probe; readable by the application.
+    pub capabilities: u32,
 }
 
 /// Drone Stream runtime data structure.
 ///
 /// This data structure risides in both the application memory and the `drone`
-/// utility memory.
+/// utility memory, immediately before the associated buffer. The application
+/// initializes it with [`Runtime::new`].
+///
+/// The layout is the same in all protocol versions. The protocol version is
+/// published only in [`GlobalRuntime::version`].
 #[derive(Clone, Debug)]
 #[repr(C)]
 pub struct Runtime {
@@ -71,14 +126,34 @@ pub struct Runtime {
 }
 
 impl GlobalRuntime {
+    /// Creates a new Drone Stream global runtime for the application memory,
+    /// with the read-only fields describing this version of the crate.
+    #[must_use]
+    pub const fn new() -> Self {
+        Self { magic: MAGIC, version: PROTOCOL_VERSION, features: FEATURES, ..Self::zeroed() }
+    }
+
     /// Creates a new zeroed Drone Stream global runtime.
     #[must_use]
     pub const fn zeroed() -> Self {
-        Self { enable_mask: 0 }
+        Self { enable_mask: 0, magic: 0, version: 0, features: 0, capabilities: 0 }
+    }
+}
+
+impl Default for GlobalRuntime {
+    fn default() -> Self {
+        Self::new()
     }
 }
 
 impl Runtime {
+    /// Creates a new Drone Stream runtime for the application memory, with the
+    /// read-only fields describing the buffer.
+    #[must_use]
+    pub const fn new(buffer_size: u32) -> Self {
+        Self { buffer_size, ..Self::zeroed() }
+    }
+
     /// Creates a new zeroed Drone Stream runtime.
     #[must_use]
     pub const fn zeroed() -> Self {